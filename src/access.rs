@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Most lumens a central line can have
+pub const MAX_LUMENS: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Peripheral,
+    Central,
+}
+
+/// A line the patient has, such as a peripheral cannula or a central line
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Device {
+    pub kind: Access,
+    /// Each lumen can be used as a separate IV
+    #[serde(default = "one_lumen")]
+    pub lumens: u32,
+}
+
+fn one_lumen() -> u32 {
+    1
+}
+
+impl Device {
+    /// Check the device makes sense
+    pub fn check(&self) -> Result<(), String> {
+        match self.kind {
+            Access::Peripheral if self.lumens != 1 => Err("a peripheral line has a single lumen".to_string()),
+            Access::Central if self.lumens == 0 || self.lumens > MAX_LUMENS => {
+                Err(format!("a central line has between 1 and {} lumens", MAX_LUMENS))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One lumen of a device, which can be used as an IV
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Line {
+    pub access: Access,
+    /// Numbered from 1 among devices of the same kind
+    pub device: u32,
+    /// Numbered from 1. `None` for single lumen devices.
+    pub lumen: Option<u32>,
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Peripheral => write!(f, "Peripheral line {}", self.device)?,
+            Access::Central => write!(f, "Central line {}", self.device)?,
+        }
+        if let Some(lumen) = self.lumen {
+            write!(f, ", lumen {}", lumen)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Every lumen of `devices`, in order. IV number `i` goes through the `i`th of these.
+pub fn lines(devices: &[Device]) -> Vec<Line> {
+    let (mut peripheral, mut central) = (0, 0);
+
+    devices
+        .iter()
+        .flat_map(|device| {
+            let count = match device.kind {
+                Access::Peripheral => &mut peripheral,
+                Access::Central => &mut central,
+            };
+            *count += 1;
+            let number = *count;
+            let lumens = device.lumens;
+
+            (1..=lumens).map(move |lumen| {
+                Line { access: device.kind, device: number, lumen: (lumens > 1).then_some(lumen) }
+            })
+        })
+        .collect()
+}
+
+/// `devices` with a new single lumen device for each IV in `layout` past their lumens: a central line
+/// if one of the IV's infusions needs central access, otherwise a peripheral line.
+///
+/// Without any devices the lines weren't given, so none are added.
+pub fn with_new_lines(devices: &[Device], layout: &[Vec<u32>], central_only: impl Fn(u32) -> bool) -> Vec<Device> {
+    let mut devices = devices.to_vec();
+    if devices.is_empty() {
+        return devices;
+    }
+
+    for iv in layout.iter().skip(lines(&devices).len()) {
+        let kind = if iv.iter().any(|id| { central_only(*id) }) { Access::Central } else { Access::Peripheral };
+        devices.push(Device { kind, lumens: 1 });
+    }
+
+    devices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_lumen_is_a_line() {
+        let devices = [
+            Device { kind: Access::Peripheral, lumens: 1 },
+            Device { kind: Access::Central, lumens: 2 },
+            Device { kind: Access::Peripheral, lumens: 1 },
+        ];
+        let lines = lines(&devices).iter().map(|line| { line.to_string() }).collect::<Vec<_>>();
+        assert_eq!(lines, ["Peripheral line 1", "Central line 1, lumen 1", "Central line 1, lumen 2", "Peripheral line 2"]);
+    }
+
+    #[test]
+    fn checks_lumens() {
+        assert!(Device { kind: Access::Peripheral, lumens: 2 }.check().is_err());
+        assert!(Device { kind: Access::Central, lumens: 0 }.check().is_err());
+        assert!(Device { kind: Access::Central, lumens: MAX_LUMENS + 1 }.check().is_err());
+        assert!(Device { kind: Access::Central, lumens: MAX_LUMENS }.check().is_ok());
+    }
+
+    #[test]
+    fn new_lines_for_ivs_past_the_devices() {
+        let devices = [Device { kind: Access::Peripheral, lumens: 1 }];
+        let layout = [vec![1], vec![2], vec![3, 4]];
+        let devices = with_new_lines(&devices, &layout, |id| { id == 4 });
+        assert_eq!(devices.iter().map(|device| { device.kind }).collect::<Vec<_>>(),
+                   [Access::Peripheral, Access::Peripheral, Access::Central]);
+
+        assert!(with_new_lines(&[], &layout, |_| { true }).is_empty());
+    }
+}
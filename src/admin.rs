@@ -0,0 +1,467 @@
+//! Admin pages of the web server, for editing infusions and compatibility records
+
+use crate::login::{Admin, Pharmacist, User};
+use crate::{reload_dataset, AppState};
+use iv_compatibility::audit::{AuditQuery, AuditVerification, SolveRecord};
+use iv_compatibility::auth::{self, Account, Role};
+use iv_compatibility::db::StoreError;
+use iv_compatibility::dataset::CompatibilityCounts;
+use iv_compatibility::edit::{ChangeRecord, Edit};
+use iv_compatibility::infusion::InfusionType;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::Form;
+use chrono::{DateTime, NaiveDate};
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+/// How many recent changes the admin page lists
+const RECENT_CHANGES: u32 = 50;
+/// How many audit log entries a search shows
+const AUDIT_PAGE_SIZE: u32 = 200;
+
+pub fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|t| { t.format("%Y-%m-%d %H:%M UTC").to_string() })
+        .unwrap_or_else(|| { timestamp.to_string() })
+}
+
+pub async fn handler_admin(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist) -> Response {
+    #[derive(Serialize)]
+    struct Change {
+        changed_at: String,
+        #[serde(flatten)]
+        record: ChangeRecord,
+    }
+
+    /// An infusion with a dedicated line, and the infusions allowed to share it
+    #[derive(Serialize)]
+    struct DedicatedLine<'a> {
+        id: u32,
+        name: &'a str,
+        reason: &'a str,
+        partners: Vec<(u32, &'a str)>,
+    }
+
+    let store = state.store.clone();
+    let changes = match tokio::task::spawn_blocking(move || { store.list_changes(RECENT_CHANGES) }).await.expect("Change log task panicked") {
+        Ok(changes) => changes,
+        Err(error) => return error_response(&state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    };
+    let changes = changes
+        .into_iter()
+        .map(|record| { Change { changed_at: format_time(record.changed_at), record } })
+        .collect::<Vec<_>>();
+
+    let dataset = state.dataset.load();
+    let infusions = dataset.infusions().collect::<Vec<_>>();
+    let dedicated = infusions
+        .iter()
+        .filter_map(|infusion| {
+            let reason = infusion.dedicated_line.as_deref()?;
+            let partners = infusions
+                .iter()
+                .filter(|partner| { dataset.is_line_partner(infusion.id, partner.id) })
+                .map(|partner| { (partner.id, partner.name.as_str()) })
+                .collect();
+            Some(DedicatedLine { id: infusion.id, name: &infusion.name, reason, partners })
+        })
+        .collect::<Vec<_>>();
+    let version = dataset.version().map(|version| { version.to_string() });
+
+    let template = state.env.get_template("admin").expect("Template not found!");
+    let rendered = template
+        .render(context!(user => user, infusions => infusions, dedicated => dedicated, changes => changes, version => version))
+        .expect("Unable to render admin page");
+
+    Html(rendered).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddInfusionForm {
+    name: String,
+    infusion_type: u32,
+    reason: String,
+}
+
+pub async fn handler_add_infusion(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                  Form(form): Form<AddInfusionForm>) -> Response {
+    let Some(infusion_type) = InfusionType::from_id(form.infusion_type) else {
+        return error_response(&state, StatusCode::BAD_REQUEST, "Unknown infusion type");
+    };
+
+    let edit = Edit::AddInfusion { name: form.name, infusion_type };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RenameForm {
+    name: String,
+    reason: String,
+}
+
+pub async fn handler_rename_infusion(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                     Path(id): Path<u32>, Form(form): Form<RenameForm>) -> Response {
+    let edit = Edit::RenameInfusion { id, name: form.name };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RetireForm {
+    retired: bool,
+    reason: String,
+}
+
+pub async fn handler_retire_infusion(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                     Path(id): Path<u32>, Form(form): Form<RetireForm>) -> Response {
+    let edit = Edit::RetireInfusion { id, retired: form.retired };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AccessForm {
+    central_only: bool,
+    reason: String,
+}
+
+pub async fn handler_set_central_only(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                      Path(id): Path<u32>, Form(form): Form<AccessForm>) -> Response {
+    let edit = Edit::SetCentralOnly { id, central_only: form.central_only };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PairParams {
+    infusion_a: u32,
+    infusion_b: u32,
+}
+
+pub async fn handler_compatibility(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                   Query(pair): Query<PairParams>) -> Response {
+    let dataset = state.dataset.load();
+    let (Some(infusion_a), Some(infusion_b)) = (dataset.infusion(pair.infusion_a), dataset.infusion(pair.infusion_b)) else {
+        return error_response(&state, StatusCode::NOT_FOUND, "One of the infusions doesn't exist");
+    };
+    if infusion_a.id == infusion_b.id {
+        return error_response(&state, StatusCode::BAD_REQUEST, "An infusion can't be paired with itself");
+    }
+    let counts = dataset.compatibility(infusion_a.id, infusion_b.id);
+    let flushable = dataset.is_flushable(infusion_a.id, infusion_b.id);
+
+    let template = state.env.get_template("admin_compatibility").expect("Template not found!");
+    let rendered = template
+        .render(context!(user => user, infusion_a => infusion_a, infusion_b => infusion_b, counts => counts, flushable => flushable))
+        .expect("Unable to render compatibility page");
+
+    Html(rendered).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CompatibilityForm {
+    infusion_a: u32,
+    infusion_b: u32,
+    compatible: u32,
+    incompatible: u32,
+    mixed: u32,
+    reason: String,
+    /// Set by the remove button, to delete the record instead of saving it
+    #[serde(default)]
+    remove: bool,
+}
+
+pub async fn handler_edit_compatibility(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                        Form(form): Form<CompatibilityForm>) -> Response {
+    let (id1, id2) = (form.infusion_a, form.infusion_b);
+    let edit = if form.remove {
+        Edit::RemoveCompatibility { id1, id2 }
+    } else {
+        let counts = CompatibilityCounts { compatible: form.compatible, incompatible: form.incompatible, mixed: form.mixed };
+        Edit::SetCompatibility { id1, id2, counts }
+    };
+
+    let redirect = format!("/admin/compatibility?infusion_a={}&infusion_b={}", id1, id2);
+    apply_edit(&state, user.username, edit, &form.reason, &redirect).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FlushForm {
+    infusion_a: u32,
+    infusion_b: u32,
+    #[serde(default)]
+    flushable: bool,
+    reason: String,
+}
+
+pub async fn handler_set_flushable(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                   Form(form): Form<FlushForm>) -> Response {
+    let (id1, id2) = (form.infusion_a, form.infusion_b);
+    let edit = Edit::SetFlushable { id1, id2, flushable: form.flushable };
+
+    let redirect = format!("/admin/compatibility?infusion_a={}&infusion_b={}", id1, id2);
+    apply_edit(&state, user.username, edit, &form.reason, &redirect).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DedicatedLineForm {
+    infusion: u32,
+    /// Why it needs a line of its own. Left empty to let it share lines again.
+    #[serde(default)]
+    line_reason: String,
+    reason: String,
+}
+
+pub async fn handler_set_dedicated_line(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                        Form(form): Form<DedicatedLineForm>) -> Response {
+    let line_reason = Some(form.line_reason).filter(|reason| { !reason.trim().is_empty() });
+    let edit = Edit::SetDedicatedLine { id: form.infusion, reason: line_reason };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LinePartnerForm {
+    infusion: u32,
+    partner: u32,
+    #[serde(default)]
+    allowed: bool,
+    reason: String,
+}
+
+pub async fn handler_set_line_partner(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist,
+                                      Form(form): Form<LinePartnerForm>) -> Response {
+    let edit = Edit::SetLinePartner { id: form.infusion, partner: form.partner, allowed: form.allowed };
+    apply_edit(&state, user.username, edit, &form.reason, "/admin").await
+}
+
+/// Save an edit, then show the updated data
+async fn apply_edit(state: &Arc<AppState>, username: String, edit: Edit, reason: &str, redirect: &str) -> Response {
+    let reason = reason.trim().to_string();
+    if reason.is_empty() {
+        return error_response(state, StatusCode::BAD_REQUEST, "Please give a reason for the change");
+    }
+
+    let store = state.store.clone();
+    let result = tokio::task::spawn_blocking(move || { store.edit(&edit, &username, &reason) })
+        .await
+        .expect("Edit task panicked");
+
+    match result {
+        Ok(change) => {
+            println!("{} made change #{}: {}", change.username, change.id, change.description);
+            if let Err(error) = reload_dataset(state).await {
+                eprintln!("Reload after change #{} failed, keeping previous data: {}", change.id, error);
+            }
+            Redirect::to(redirect).into_response()
+        },
+        Err(StoreError::InvalidData(reason)) => error_response(state, StatusCode::BAD_REQUEST, &reason),
+        Err(error) => error_response(state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    }
+}
+
+pub async fn handler_accounts(state: State<Arc<AppState>>, Admin(user): Admin) -> Response {
+    #[derive(Serialize)]
+    struct AccountRow {
+        username: String,
+        role: Role,
+    }
+
+    let store = state.store.clone();
+    let accounts = match tokio::task::spawn_blocking(move || { store.accounts() }).await.expect("Account task panicked") {
+        Ok(accounts) => accounts,
+        Err(error) => return error_response(&state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    };
+    let accounts = accounts
+        .into_iter()
+        .map(|account| { AccountRow { username: account.username, role: account.role } })
+        .collect::<Vec<_>>();
+
+    let template = state.env.get_template("admin_accounts").expect("Template not found!");
+    let rendered = template
+        .render(context!(user => user, accounts => accounts, roles => Role::ALL))
+        .expect("Unable to render accounts page");
+
+    Html(rendered).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct AddAccountForm {
+    username: String,
+    password: String,
+    role: String,
+}
+
+pub async fn handler_add_account(state: State<Arc<AppState>>, Admin(user): Admin, Form(form): Form<AddAccountForm>) -> Response {
+    let username = form.username.trim().to_string();
+    if let Err(reason) = auth::check_username(&username) {
+        return error_response(&state, StatusCode::BAD_REQUEST, &reason);
+    }
+    if form.password.is_empty() {
+        return error_response(&state, StatusCode::BAD_REQUEST, "The password can't be empty");
+    }
+    let role = match form.role.parse::<Role>() {
+        Ok(role) => role,
+        Err(reason) => return error_response(&state, StatusCode::BAD_REQUEST, &reason),
+    };
+
+    let store = state.store.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        store.add_account(&Account { username, password_hash: auth::hash_password(&form.password), role })
+    })
+    .await
+    .expect("Account task panicked");
+
+    match result {
+        Ok(()) => {
+            println!("{} added {} account {}", user.username, role, form.username.trim());
+            Redirect::to("/admin/accounts").into_response()
+        },
+        Err(StoreError::InvalidData(reason)) => error_response(&state, StatusCode::BAD_REQUEST, &reason),
+        Err(error) => error_response(&state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoleForm {
+    role: String,
+}
+
+pub async fn handler_set_role(state: State<Arc<AppState>>, Admin(user): Admin,
+                              Path(username): Path<String>, Form(form): Form<RoleForm>) -> Response {
+    let role = match form.role.parse::<Role>() {
+        Ok(role) => role,
+        Err(reason) => return error_response(&state, StatusCode::BAD_REQUEST, &reason),
+    };
+    if username == user.username {
+        return error_response(&state, StatusCode::BAD_REQUEST, "You can't change your own role. Ask another admin.");
+    }
+
+    let store = state.store.clone();
+    let account = username.clone();
+    let result = tokio::task::spawn_blocking(move || { store.set_role(&account, role) }).await.expect("Account task panicked");
+    account_changed(&state, &user, result, &username, &format!("set the role of {} to {}", username, role))
+}
+
+pub async fn handler_remove_account(state: State<Arc<AppState>>, Admin(user): Admin, Path(username): Path<String>) -> Response {
+    if username == user.username {
+        return error_response(&state, StatusCode::BAD_REQUEST, "You can't remove your own account. Ask another admin.");
+    }
+
+    let store = state.store.clone();
+    let account = username.clone();
+    let result = tokio::task::spawn_blocking(move || { store.remove_account(&account) }).await.expect("Account task panicked");
+    account_changed(&state, &user, result, &username, &format!("removed account {}", username))
+}
+
+/// Sign the account out, so the change applies straight away, then show the updated accounts
+fn account_changed(state: &AppState, user: &User, result: Result<bool, StoreError>, username: &str, description: &str) -> Response {
+    match result {
+        Ok(true) => {
+            state.logins.end_sessions_for(username);
+            println!("{} {}", user.username, description);
+            Redirect::to("/admin/accounts").into_response()
+        },
+        Ok(false) => error_response(state, StatusCode::NOT_FOUND, &format!("There's no account named {}", username)),
+        Err(error) => error_response(state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditParams {
+    /// Dates as YYYY-MM-DD, in UTC
+    from: Option<String>,
+    to: Option<String>,
+    /// Infusion ID. Empty for any.
+    drug: Option<String>,
+    /// Set to also check the hash chain
+    #[serde(default)]
+    verify: bool,
+}
+
+/// Whole days, from the start of `from` to the end of `to`
+fn audit_query(from: Option<&str>, to: Option<&str>, drug: Option<&str>) -> Result<AuditQuery, String> {
+    let parse_date = |date: &str| {
+        NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| { format!("'{}' is not a date like 2024-01-31", date) })
+    };
+
+    Ok(AuditQuery {
+        from: from.map(parse_date).transpose()?.map(|date| { date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() }),
+        to: to.map(parse_date).transpose()?.map(|date| { date.and_hms_opt(23, 59, 59).unwrap().and_utc().timestamp() }),
+        infusion_id: drug.map(|id| { id.parse().map_err(|_| { format!("'{}' is not an infusion ID", id) }) }).transpose()?,
+        limit: AUDIT_PAGE_SIZE,
+    })
+}
+
+pub async fn handler_audit(state: State<Arc<AppState>>, Admin(user): Admin, Query(params): Query<AuditParams>) -> Response {
+    #[derive(Serialize)]
+    struct Entry {
+        id: u64,
+        logged_at: String,
+        record: SolveRecord,
+        /// Infusion name and when it runs, for intermittent infusions
+        schedules: Vec<(String, String)>,
+        hash: String,
+    }
+
+    let non_empty = |value: &Option<String>| { value.as_deref().map(str::trim).filter(|value| { !value.is_empty() }).map(String::from) };
+    let (from, to, drug) = (non_empty(&params.from), non_empty(&params.to), non_empty(&params.drug));
+    let query = audit_query(from.as_deref(), to.as_deref(), drug.as_deref());
+    let query = match query {
+        Ok(query) => query,
+        Err(reason) => return error_response(&state, StatusCode::BAD_REQUEST, &reason),
+    };
+
+    let store = state.store.clone();
+    let verify = params.verify;
+    let anchor = state.anchor.latest();
+    let result = tokio::task::spawn_blocking(move || {
+        let entries = store.query_audit(&query)?;
+        let verification = if verify { Some(store.verify_audit_log(anchor.as_ref())?) } else { None };
+        Ok::<_, StoreError>((entries, verification))
+    })
+    .await
+    .expect("Audit log task panicked");
+    let (entries, verification): (_, Option<AuditVerification>) = match result {
+        Ok(result) => result,
+        Err(error) => return error_response(&state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    };
+    let entries = entries
+        .into_iter()
+        .map(|entry| {
+            let schedules = entry.record.schedules
+                .iter()
+                .map(|(id, schedule)| {
+                    let name = entry.record.ivs.iter().flatten().chain(&entry.record.add).chain(&entry.record.remove)
+                        .find(|infusion| { infusion.id == *id })
+                        .and_then(|infusion| { infusion.name.clone() })
+                        .unwrap_or_else(|| { format!("#{}", id) });
+                    (name, schedule.to_string())
+                })
+                .collect();
+            Entry { id: entry.id, logged_at: format_time(entry.logged_at), record: entry.record, schedules, hash: entry.hash }
+        })
+        .collect::<Vec<_>>();
+
+    let dataset = state.dataset.load();
+    let infusions = dataset.infusions().collect::<Vec<_>>();
+
+    let template = state.env.get_template("admin_audit").expect("Template not found!");
+    let rendered = template
+        .render(context!(
+            user => user, entries => entries, infusions => infusions, verification => verification,
+            from => from, to => to, drug => drug, page_size => AUDIT_PAGE_SIZE,
+        ))
+        .expect("Unable to render audit log page");
+
+    Html(rendered).into_response()
+}
+
+fn error_response(state: &AppState, status: StatusCode, message: &str) -> Response {
+    let template = state.env.get_template("admin_error").expect("Template not found!");
+    let rendered = template
+        .render(context!(message => message))
+        .expect("Unable to render error page");
+
+    (status, Html(rendered)).into_response()
+}
@@ -0,0 +1,364 @@
+use crate::access::Device;
+use crate::db::StoreError;
+use crate::schedule::Schedule;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// `prev_hash` of the first entry in the log
+pub const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// An infusion as it was named when the solve ran
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditInfusion {
+    pub id: u32,
+    /// `None` if the ID wasn't in the dataset
+    pub name: Option<String>,
+}
+
+/// What the solver told the user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum SolveOutcome {
+    /// The infusions in each IV, in IV order
+    Solved {
+        ivs: Vec<Vec<String>>,
+        optimal: bool,
+        /// Incompatible infusions taking turns on an IV, which has to be flushed between them
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        flushes: Vec<(String, String)>,
+        /// Incompatible infusions sharing an IV only because they never run at the same time
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        time_shared: Vec<(String, String)>,
+    },
+    /// The current IVs already hold incompatible infusions
+    Conflict {
+        iv: u32,
+        conflicting_items: (String, String),
+        /// Why one of them needs a line of its own, if that's what keeps them apart
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        dedicated_line: Option<String>,
+    },
+    /// The request was rejected or couldn't be handled, so nothing was solved
+    Failed { reason: String },
+}
+
+/// One solve, with everything needed to reconstruct what the user was shown
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SolveRecord {
+    /// `None` if nobody was signed in
+    pub username: Option<String>,
+    pub dataset_version: Option<u32>,
+    /// The current IVs as entered
+    pub ivs: Vec<Vec<AuditInfusion>>,
+    /// The infusions to add
+    pub add: Vec<AuditInfusion>,
+    /// The infusions stopped, when consolidating the current IVs
+    #[serde(default)]
+    pub remove: Vec<AuditInfusion>,
+    /// Infusion ID -> when it runs, for intermittent infusions
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub schedules: BTreeMap<u32, Schedule>,
+    /// The patient's lines, if they were given
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub devices: Vec<Device>,
+    /// See `solver::STRATEGY` and `solver::CONSOLIDATE_STRATEGY`
+    pub strategy: String,
+    pub time_limit_ms: u64,
+    pub outcome: SolveOutcome,
+    /// The saved regimen the infusions were added to, if any
+    #[serde(default)]
+    pub regimen: Option<u32>,
+}
+
+impl SolveRecord {
+    /// Every infusion ID in the request, for looking entries up by drug
+    pub fn infusion_ids(&self) -> Vec<u32> {
+        let mut ids = self.ivs.iter().flatten().chain(&self.add).chain(&self.remove).map(|infusion| { infusion.id }).collect::<Vec<_>>();
+        ids.sort();
+        ids.dedup();
+
+        ids
+    }
+}
+
+/// An entry in the solve audit log.
+///
+/// Each entry's hash covers its contents and the previous entry's hash, so changing or
+/// removing an entry breaks the chain from that point on.
+#[derive(Debug, Clone)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub logged_at: i64,     // Unix timestamp
+    pub record: SolveRecord,
+    pub prev_hash: String,
+    pub hash: String,
+    /// The record exactly as stored and hashed
+    record_json: String,
+}
+
+impl AuditEntry {
+    /// The entry that follows one with ID `prev_id` and hash `prev_hash`
+    pub(crate) fn next(prev_id: u64, prev_hash: &str, logged_at: i64, record: &SolveRecord) -> Self {
+        let id = prev_id + 1;
+        let record_json = serde_json::to_string(record).expect("Failed to serialize audit record");
+        let hash = entry_hash(id, logged_at, &record_json, prev_hash);
+
+        Self { id, logged_at, record: record.clone(), prev_hash: prev_hash.to_string(), hash, record_json }
+    }
+
+    pub(crate) fn from_row(id: u64, logged_at: i64, record_json: String, prev_hash: String, hash: String) -> Result<Self, StoreError> {
+        let record = serde_json::from_str(&record_json)
+            .map_err(|e| { StoreError::InvalidData(format!("unreadable audit entry {}: {}", id, e)) })?;
+
+        Ok(Self { id, logged_at, record, prev_hash, hash, record_json })
+    }
+
+    pub(crate) fn record_json(&self) -> &str {
+        &self.record_json
+    }
+}
+
+fn entry_hash(id: u64, logged_at: i64, record_json: &str, prev_hash: &str) -> String {
+    let digest = Sha256::digest(format!("{}\n{}\n{}\n{}", id, logged_at, record_json, prev_hash));
+
+    digest.iter().map(|byte| { format!("{:02x}", byte) }).collect()
+}
+
+/// Which entries to look up. Results are newest first.
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// Unix timestamps, inclusive
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    /// Only entries whose request included this infusion
+    pub infusion_id: Option<u32>,
+    pub limit: u32,
+}
+
+/// The result of checking the hash chain
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct AuditVerification {
+    pub entries: u64,
+    /// The first entry that was changed, or whose predecessor is missing
+    pub first_broken: Option<u64>,
+    /// The entry named in the anchor file, if the log no longer holds it as it was written.
+    /// Entries were removed from the end of the log, or the whole log was rewritten.
+    pub missing_anchor: Option<u64>,
+}
+
+/// The ID and hash of the newest entry a web server has written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditAnchor {
+    pub id: u64,
+    pub hash: String,
+}
+
+/// Keeps the newest entry's ID and hash in a file outside the database.
+///
+/// The hash chain shows entries that were changed or removed from the middle of the log,
+/// but not entries removed from the end or a log rewritten from the start. The anchor does.
+pub struct AnchorFile {
+    path: PathBuf,
+    latest: Mutex<Option<AuditAnchor>>,
+}
+
+impl AnchorFile {
+    /// Read the anchor file at `path`, which doesn't have to exist yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, String> {
+        let path = path.into();
+        let latest = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                let anchor = contents.trim().split_once(' ').and_then(|(id, hash)| {
+                    Some(AuditAnchor { id: id.parse().ok()?, hash: hash.to_string() })
+                });
+                Some(anchor.ok_or_else(|| { format!("{} isn't an audit anchor file", path.display()) })?)
+            },
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(format!("Couldn't read {}: {}", path.display(), error)),
+        };
+
+        Ok(Self { path, latest: Mutex::new(latest) })
+    }
+
+    pub fn latest(&self) -> Option<AuditAnchor> {
+        self.latest.lock().unwrap().clone()
+    }
+
+    /// Record `entry` as the newest, unless a newer one has been recorded already
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut latest = self.latest.lock().unwrap();
+        if latest.as_ref().is_some_and(|anchor| { anchor.id >= entry.id }) {
+            return Ok(());
+        }
+
+        // Replaced in one step, so a crash can't leave half a file behind
+        let temp = self.path.with_extension("tmp");
+        fs::write(&temp, format!("{} {}\n", entry.id, entry.hash))?;
+        fs::rename(&temp, &self.path)?;
+        *latest = Some(AuditAnchor { id: entry.id, hash: entry.hash.clone() });
+
+        Ok(())
+    }
+}
+
+/// Checks entries one at a time, in ID order, so the whole log doesn't have to be in memory
+pub(crate) struct ChainVerifier {
+    prev_id: u64,
+    prev_hash: String,
+    /// The anchor, until the entry it names has been seen intact
+    anchor: Option<AuditAnchor>,
+    result: AuditVerification,
+}
+
+impl ChainVerifier {
+    pub fn new(anchor: Option<&AuditAnchor>) -> Self {
+        Self {
+            prev_id: 0,
+            prev_hash: GENESIS_HASH.to_string(),
+            anchor: anchor.cloned(),
+            result: AuditVerification { entries: 0, first_broken: None, missing_anchor: None },
+        }
+    }
+
+    pub fn check(&mut self, id: u64, logged_at: i64, record_json: &str, prev_hash: &str, hash: &str) {
+        self.result.entries += 1;
+        let intact = id == self.prev_id + 1
+            && prev_hash == self.prev_hash
+            && hash == entry_hash(id, logged_at, record_json, prev_hash);
+        if !intact && self.result.first_broken.is_none() {
+            self.result.first_broken = Some(id);
+        }
+        if self.anchor.as_ref().is_some_and(|anchor| { anchor.id == id && anchor.hash == hash }) {
+            self.anchor = None;
+        }
+
+        self.prev_id = id;
+        self.prev_hash = hash.to_string();
+    }
+
+    pub fn finish(mut self) -> AuditVerification {
+        self.result.missing_anchor = self.anchor.map(|anchor| { anchor.id });
+        self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{CompatibilityStore, MemoryStore};
+
+    fn record(id: u32) -> SolveRecord {
+        SolveRecord {
+            username: Some("nurse".to_string()),
+            dataset_version: Some(1),
+            ivs: vec![vec![AuditInfusion { id, name: Some(format!("#{}", id)) }]],
+            add: vec![AuditInfusion { id: 100, name: None }],
+            remove: Vec::new(),
+            schedules: BTreeMap::new(),
+            devices: Vec::new(),
+            strategy: "test".to_string(),
+            time_limit_ms: 1000,
+            outcome: SolveOutcome::Failed { reason: "test".to_string() },
+            regimen: None,
+        }
+    }
+
+    fn chain(length: u32) -> Vec<AuditEntry> {
+        let mut entries: Vec<AuditEntry> = Vec::new();
+        for id in 1..=length {
+            let (prev_id, prev_hash) = entries.last().map(|e| { (e.id, e.hash.as_str()) }).unwrap_or((0, GENESIS_HASH));
+            entries.push(AuditEntry::next(prev_id, prev_hash, 1_700_000_000, &record(id)));
+        }
+
+        entries
+    }
+
+    fn verify(entries: &[AuditEntry]) -> AuditVerification {
+        let head = chain(3).pop().map(|e| { AuditAnchor { id: e.id, hash: e.hash } });
+        let mut verifier = ChainVerifier::new(head.as_ref());
+        for e in entries {
+            verifier.check(e.id, e.logged_at, e.record_json(), &e.prev_hash, &e.hash);
+        }
+
+        verifier.finish()
+    }
+
+    #[test]
+    fn intact_chain_verifies() {
+        let result = verify(&chain(3));
+        assert_eq!((result.entries, result.first_broken, result.missing_anchor), (3, None, None));
+    }
+
+    #[test]
+    fn changed_entry_breaks_the_chain() {
+        let mut entries = chain(3);
+        entries[1].record_json = entries[1].record_json.replace("nurse", "admin");
+        assert_eq!(verify(&entries).first_broken, Some(2));
+
+        let mut entries = chain(3);
+        entries[1].logged_at += 1;
+        assert_eq!(verify(&entries).first_broken, Some(2));
+    }
+
+    #[test]
+    fn removed_entry_breaks_the_chain() {
+        let mut entries = chain(3);
+        entries.remove(1);
+        assert_eq!(verify(&entries).first_broken, Some(3));
+
+        // The chain itself can't show that the newest entry is gone, but the anchor does
+        let mut entries = chain(3);
+        entries.pop();
+        let result = verify(&entries);
+        assert_eq!((result.first_broken, result.missing_anchor), (None, Some(3)));
+        assert_eq!(verify(&[]).missing_anchor, Some(3));
+    }
+
+    #[test]
+    fn rewritten_chain_misses_the_anchor() {
+        let mut entries = chain(3);
+        let last = entries.pop().unwrap();
+        let mut changed = record(3);
+        changed.username = Some("admin".to_string());
+        entries.push(AuditEntry::next(2, &entries[1].hash, last.logged_at, &changed));
+        let result = verify(&entries);
+        assert_eq!((result.first_broken, result.missing_anchor), (None, Some(3)));
+    }
+
+    #[test]
+    fn anchor_file_keeps_the_newest_entry() {
+        let path = std::env::temp_dir().join(format!("iv-audit-anchor-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let entries = chain(3);
+
+        let anchors = AnchorFile::open(&path).unwrap();
+        assert_eq!(anchors.latest(), None);
+        anchors.record(&entries[1]).unwrap();
+        anchors.record(&entries[0]).unwrap();
+        let reopened = AnchorFile::open(&path).unwrap().latest().unwrap();
+        assert_eq!((reopened.id, reopened.hash.as_str()), (2, entries[1].hash.as_str()));
+
+        fs::write(&path, "not an anchor").unwrap();
+        assert!(AnchorFile::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stored_entries_verify_and_can_be_looked_up_by_infusion() {
+        let store = MemoryStore::new();
+        for id in 1..=3 {
+            store.log_solve(&record(id)).unwrap();
+        }
+        let result = store.verify_audit_log(None).unwrap();
+        assert_eq!((result.entries, result.first_broken), (3, None));
+
+        let found = store.query_audit(&AuditQuery { infusion_id: Some(2), limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(found.iter().map(|e| { e.id }).collect::<Vec<_>>(), [2]);
+        assert_eq!(record(1).infusion_ids(), [1, 100]);
+    }
+}
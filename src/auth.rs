@@ -0,0 +1,150 @@
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD};
+use base64::Engine;
+use openssl::hash::MessageDigest;
+use openssl::{memcmp, pkcs5, rand};
+use serde::Serialize;
+use std::fmt;
+use std::str::FromStr;
+
+const HASH_ITERATIONS: usize = 100_000;
+const SALT_LEN: usize = 16;
+const HASH_LEN: usize = 32;
+
+/// What someone is allowed to do. Each role can do everything the ones before it can.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Can look up compatibility data and work out IV assignments
+    Viewer,
+    /// Can also edit the data and reload it
+    Pharmacist,
+    /// Can also manage accounts
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 3] = [Role::Viewer, Role::Pharmacist, Role::Admin];
+
+    /// The name stored in the database and used in config files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Pharmacist => "pharmacist",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Role::ALL
+            .into_iter()
+            .find(|role| { role.name() == s })
+            .ok_or_else(|| { format!("'{}' is not one of viewer, pharmacist or admin", s) })
+    }
+}
+
+/// A local account that can sign in with a password
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
+}
+
+/// Check a new local username. `:` isn't allowed since it separates the username from the password
+/// in basic auth, and marks the identities of people who sign in through OpenID Connect.
+pub fn check_username(username: &str) -> Result<(), String> {
+    if username.is_empty() {
+        return Err("The username can't be empty".to_string());
+    }
+    if username.contains(':') {
+        return Err("The username can't contain ':'".to_string());
+    }
+
+    Ok(())
+}
+
+/// Hash a password for storage, as `pbkdf2-sha256$iterations$salt$hash`
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0; SALT_LEN];
+    rand::rand_bytes(&mut salt).expect("Failed to generate salt");
+    let hash = pbkdf2(password, &salt, HASH_ITERATIONS);
+
+    format!("pbkdf2-sha256${}${}${}", HASH_ITERATIONS, BASE64.encode(salt), BASE64.encode(hash))
+}
+
+/// Check a password against a hash made by `hash_password`
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    let parts = password_hash.split('$').collect::<Vec<_>>();
+    let [scheme, iterations, salt, hash] = parts[..] else { return false };
+    if scheme != "pbkdf2-sha256" {
+        return false;
+    }
+    let (Ok(iterations), Ok(salt), Ok(hash)) = (iterations.parse(), BASE64.decode(salt), BASE64.decode(hash)) else {
+        return false;
+    };
+
+    let computed = pbkdf2(password, &salt, iterations);
+    hash.len() == computed.len() && memcmp::eq(&hash, &computed)
+}
+
+/// A random string that can't be guessed, safe to use in URLs and cookies
+pub fn random_token() -> String {
+    let mut bytes = [0; 32];
+    rand::rand_bytes(&mut bytes).expect("Failed to generate token");
+
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn pbkdf2(password: &str, salt: &[u8], iterations: usize) -> [u8; HASH_LEN] {
+    let mut hash = [0; HASH_LEN];
+    pkcs5::pbkdf2_hmac(password.as_bytes(), salt, iterations, MessageDigest::sha256(), &mut hash)
+        .expect("Failed to hash password");
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passwords_verify_against_their_hash() {
+        let hash = hash_password("s3cret");
+        assert!(hash.starts_with(&format!("pbkdf2-sha256${}$", HASH_ITERATIONS)));
+        assert!(verify_password("s3cret", &hash));
+        assert!(!verify_password("S3cret", &hash));
+        assert!(!verify_password("", &hash));
+    }
+
+    #[test]
+    fn hashes_are_salted() {
+        assert_ne!(hash_password("s3cret"), hash_password("s3cret"));
+    }
+
+    #[test]
+    fn malformed_hashes_never_verify() {
+        let hash = hash_password("s3cret");
+        assert!(!verify_password("s3cret", &hash.replacen("pbkdf2-sha256", "md5", 1)));
+        assert!(!verify_password("s3cret", "pbkdf2-sha256$100000$not base64$x"));
+        assert!(!verify_password("s3cret", "s3cret"));
+        assert!(!verify_password("s3cret", ""));
+    }
+
+    #[test]
+    fn roles_parse_and_are_ordered() {
+        assert_eq!("pharmacist".parse::<Role>(), Ok(Role::Pharmacist));
+        assert!("root".parse::<Role>().is_err());
+        assert!(Role::Viewer < Role::Pharmacist && Role::Pharmacist < Role::Admin);
+        assert_eq!(Role::ALL.map(|role| { role.to_string() }), ["viewer", "pharmacist", "admin"]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::io;
+use std::process::{self, ExitCode};
+use clap::{Parser, Subcommand};
+use iv_compatibility::auth::{self, Account, Role};
+use iv_compatibility::config::Config;
+use iv_compatibility::db;
+
+/// Manage the local accounts that can sign in to the web server
+#[derive(Parser, Debug)]
+struct Args {
+    /// Config file to read the database settings from
+    #[arg(long)]
+    config: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Add an account. The password is read from the first line of standard input.
+    Add {
+        username: String,
+        /// viewer, pharmacist or admin
+        #[arg(long, default_value = "viewer")]
+        role: Role,
+    },
+    /// Change what an account is allowed to do
+    SetRole { username: String, role: Role },
+    /// Remove an account. Its past changes stay in the change log.
+    Remove { username: String },
+    /// List every account
+    List,
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let config = Config::load_or_exit(args.config.as_deref());
+    let store = db::connect(&config.db).expect("Failed to connect to the database!");
+    if let Err(error) = store.check_schema() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+
+    match args.command {
+        Command::Add { username, role } => {
+            if let Err(reason) = auth::check_username(&username) {
+                eprintln!("{}", reason);
+                return ExitCode::FAILURE;
+            }
+            let mut password = String::new();
+            io::stdin().read_line(&mut password).expect("Couldn't read the password!");
+            let password = password.trim_end_matches(['\r', '\n']);
+            if password.is_empty() {
+                eprintln!("The password can't be empty");
+                return ExitCode::FAILURE;
+            }
+
+            let account = Account { username, password_hash: auth::hash_password(password), role };
+            if let Err(error) = store.add_account(&account) {
+                eprintln!("{}", error);
+                return ExitCode::FAILURE;
+            }
+            println!("Added {} account {}", account.role, account.username);
+        },
+        Command::SetRole { username, role } => {
+            if !store.set_role(&username, role).expect("Failed to change role!") {
+                eprintln!("There's no account named {}", username);
+                return ExitCode::FAILURE;
+            }
+            println!("{} is now {}", username, role);
+        },
+        Command::Remove { username } => {
+            if !store.remove_account(&username).expect("Failed to remove account!") {
+                eprintln!("There's no account named {}", username);
+                return ExitCode::FAILURE;
+            }
+            println!("Removed account {}", username);
+        },
+        Command::List => {
+            for account in store.accounts().expect("Failed to load accounts!") {
+                println!("{}\t{}", account.username, account.role);
+            }
+        },
+    }
+
+    ExitCode::SUCCESS
+}
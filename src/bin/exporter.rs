@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+use clap::{Parser, ValueEnum};
+use serde::Serialize;
+use iv_compatibility::dataset::{Dataset, DatasetVersion};
+use iv_compatibility::config::Config;
+use iv_compatibility::db;
+use iv_compatibility::infusion::InfusionType;
+
+/// Export IV compatibility data from the database
+#[derive(Parser, Debug)]
+struct Args {
+    /// Output format. The matrix and long formats can be read back by the importer.
+    #[arg(long, value_enum, default_value = "matrix")]
+    format: FormatArg,
+    /// File to write to. Defaults to standard output.
+    #[arg(short, long)]
+    output: Option<String>,
+    /// Export the data as it was in this dataset version instead of the current data
+    #[arg(long)]
+    dataset_version: Option<u32>,
+    /// List the dataset versions instead of exporting
+    #[arg(long, conflicts_with = "dataset_version")]
+    list_versions: bool,
+    /// Config file to read the database settings from
+    #[arg(long)]
+    config: Option<String>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FormatArg {
+    /// Square matrix with one row and one column per infusion
+    Matrix,
+    /// One row per pair of infusions, with concentrations, diluents and references
+    Long,
+    Json,
+}
+
+#[derive(Serialize)]
+struct JsonInfusion<'a> {
+    id: u32,
+    name: &'a str,
+    #[serde(rename = "type")]
+    infusion_type: &'a str,
+    retired: bool,
+    central_only: bool,
+    dedicated_line: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonCompatibility<'a> {
+    infusion_a: &'a str,
+    infusion_b: &'a str,
+    compatible: u32,
+    incompatible: u32,
+    mixed: u32,
+    concentration: Option<&'a str>,
+    diluent: Option<&'a str>,
+    reference: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct JsonExport<'a> {
+    version: Option<&'a DatasetVersion>,
+    infusions: Vec<JsonInfusion<'a>>,
+    compatibility: Vec<JsonCompatibility<'a>>,
+    /// Pairs that can take turns on one IV with a flush in between
+    flushable: Vec<(&'a str, &'a str)>,
+    /// (infusion with a dedicated line, infusion allowed on it)
+    line_partners: Vec<(&'a str, &'a str)>,
+}
+
+fn type_name(infusion_type: InfusionType) -> &'static str {
+    match infusion_type {
+        InfusionType::Drug => "drug",
+        InfusionType::Solution => "solution",
+    }
+}
+
+/// Every infusion is both a row and a column, so every pair is written in both halves
+fn write_matrix(dataset: &Dataset, out: impl Write) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    let infusions = dataset.infusions().collect::<Vec<_>>();
+
+    let mut header = vec![String::new(), String::new()];
+    header.extend(infusions.iter().map(|inf| { inf.name.clone() }));
+    writer.write_record(&header)?;
+
+    for infusion in &infusions {
+        let mut row = vec![infusion.name.clone(), infusion.infusion_type.id().to_string()];
+        for other in &infusions {
+            let cell = match dataset.compatibility(infusion.id, other.id) {
+                Some(counts) if infusion.id != other.id => {
+                    format!("{}:{}:{}", counts.compatible, counts.incompatible, counts.mixed)
+                },
+                _ => String::new(),
+            };
+            row.push(cell);
+        }
+        writer.write_record(&row)?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_long(dataset: &Dataset, out: impl Write) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(out);
+    writer.write_record(["drug_a", "type_a", "drug_b", "type_b", "compatible", "incompatible", "mixed",
+                         "concentration", "diluent", "reference"])?;
+
+    let mut pairs = dataset.compatibility_pairs().collect::<Vec<_>>();
+    pairs.sort_unstable();
+
+    // Infusions without any compatibility data get a row of their own so they aren't lost
+    for infusion in dataset.infusions() {
+        if !pairs.iter().any(|(id1, id2)| { *id1 == infusion.id || *id2 == infusion.id }) {
+            let mut row = vec![infusion.name.clone(), infusion.infusion_type.id().to_string()];
+            row.resize(10, String::new());
+            writer.write_record(&row)?;
+        }
+    }
+
+    for (id1, id2) in pairs {
+        let infusion1 = dataset.infusion(id1).unwrap();
+        let infusion2 = dataset.infusion(id2).unwrap();
+        let counts = dataset.compatibility(id1, id2).unwrap();
+        let details = dataset.details(id1, id2).cloned().unwrap_or_default();
+        writer.write_record([
+            infusion1.name.clone(),
+            infusion1.infusion_type.id().to_string(),
+            infusion2.name.clone(),
+            infusion2.infusion_type.id().to_string(),
+            counts.compatible.to_string(),
+            counts.incompatible.to_string(),
+            counts.mixed.to_string(),
+            details.concentration.unwrap_or_default(),
+            details.diluent.unwrap_or_default(),
+            details.reference.unwrap_or_default(),
+        ])?;
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_json(dataset: &Dataset, mut out: impl Write) -> serde_json::Result<()> {
+    let infusions = dataset
+        .infusions()
+        .map(|inf| {
+            JsonInfusion {
+                id: inf.id,
+                name: &inf.name,
+                infusion_type: type_name(inf.infusion_type),
+                retired: inf.retired,
+                central_only: inf.central_only,
+                dedicated_line: inf.dedicated_line.as_deref(),
+            }
+        })
+        .collect();
+
+    let mut pairs = dataset.compatibility_pairs().collect::<Vec<_>>();
+    pairs.sort_unstable();
+    let compatibility = pairs
+        .into_iter()
+        .map(|(id1, id2)| {
+            let counts = dataset.compatibility(id1, id2).unwrap();
+            let details = dataset.details(id1, id2);
+            JsonCompatibility {
+                infusion_a: &dataset.infusion(id1).unwrap().name,
+                infusion_b: &dataset.infusion(id2).unwrap().name,
+                compatible: counts.compatible,
+                incompatible: counts.incompatible,
+                mixed: counts.mixed,
+                concentration: details.and_then(|d| { d.concentration.as_deref() }),
+                diluent: details.and_then(|d| { d.diluent.as_deref() }),
+                reference: details.and_then(|d| { d.reference.as_deref() }),
+            }
+        })
+        .collect();
+
+    let names = |pairs: Vec<(u32, u32)>| {
+        pairs
+            .into_iter()
+            .map(|(id1, id2)| { (dataset.infusion(id1).unwrap().name.as_str(), dataset.infusion(id2).unwrap().name.as_str()) })
+            .collect()
+    };
+    let mut flushable = dataset.flushable_pairs().collect::<Vec<_>>();
+    flushable.sort_unstable();
+    let mut line_partners = dataset.line_partners().collect::<Vec<_>>();
+    line_partners.sort_unstable();
+
+    let export = JsonExport {
+        version: dataset.version(),
+        infusions,
+        compatibility,
+        flushable: names(flushable),
+        line_partners: names(line_partners),
+    };
+    serde_json::to_writer_pretty(&mut out, &export)?;
+    writeln!(out).map_err(serde_json::Error::io)
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let config = Config::load_or_exit(args.config.as_deref());
+    let store = db::connect(&config.db).expect("Failed to connect to the database!");
+    if let Err(error) = store.check_schema() {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+    if args.list_versions {
+        for version in store.list_versions().expect("Failed to load versions from DB!") {
+            let active = if version.active { " [active]" } else { "" };
+            println!("{} sha256:{}{}", version, version.source_hash, active);
+        }
+        return;
+    }
+
+    let dataset = match args.dataset_version {
+        Some(id) => store.load_version(id)
+            .expect("Failed to load data from DB!")
+            .unwrap_or_else(|| { panic!("Dataset version {} doesn't exist!", id) }),
+        None => store.load_dataset().expect("Failed to load data from DB!"),
+    };
+
+    let out: Box<dyn Write> = match &args.output {
+        Some(path) => Box::new(File::create(path).expect("Couldn't create output file!")),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    match args.format {
+        FormatArg::Matrix => write_matrix(&dataset, out).expect("Failed to write CSV!"),
+        FormatArg::Long => write_long(&dataset, out).expect("Failed to write CSV!"),
+        FormatArg::Json => write_json(&dataset, out).expect("Failed to write JSON!"),
+    }
+}
@@ -1,123 +1,237 @@
-use std::fs::File;
-use std::io::{self, BufRead};
-use std::collections::HashMap;
-use configparser::ini::Ini;
-use mysql::*;
-use mysql::prelude::*;
-use std::env;
-
-#[derive(Debug)]
-struct InfusionInput<'a> {
-    name: String,
-    inf_type: u32,
-    compat: HashMap<&'a str, Vec<u32>>
+use std::fs;
+use std::path::Path;
+use std::process::{self, ExitCode};
+use std::sync::Arc;
+use clap::{Parser, ValueEnum};
+use iv_compatibility::config::Config;
+use iv_compatibility::db::{self, CompatibilityStore};
+use iv_compatibility::diff::{DatasetDiff, NamedData};
+use iv_compatibility::import::{AsymmetryRule, ImportData, ImportMode, ImportPlan, ImportSource};
+use iv_compatibility::import::parse::{self, Format, Severity};
+
+/// Import IV compatibility data from a CSV file, in either matrix or long format
+#[derive(Parser, Debug)]
+struct Args {
+    /// How to handle rows that already exist in the database
+    #[arg(long, value_enum, required_unless_present_any = ["dry_run", "diff", "migrate"])]
+    mode: Option<ModeArg>,
+    /// Check the file and report every problem without writing to the database.
+    /// If --mode is given, also show what the import would change.
+    #[arg(long)]
+    dry_run: bool,
+    /// Show how the file differs from the database without writing anything
+    #[arg(long, conflicts_with = "dry_run")]
+    diff: bool,
+    /// With --diff, compare against this file instead of the database
+    #[arg(long, requires = "diff")]
+    base: Option<String>,
+    /// With --diff, print the differences as JSON
+    #[arg(long, requires = "diff")]
+    json: bool,
+    /// What to do when the two halves of the matrix disagree about a pair
+    #[arg(long, value_enum, default_value = "fail")]
+    asymmetric: AsymmetryArg,
+    /// Layout of the input file. `auto` picks `long` if the first column is named drug_a.
+    #[arg(long, value_enum, default_value = "auto")]
+    format: FormatArg,
+    /// Field delimiter, e.g. ';' or 'tab'. Defaults to tab for .tsv files and ',' otherwise.
+    #[arg(long)]
+    delimiter: Option<String>,
+    /// Name for the dataset version created by the import. Defaults to the file name.
+    #[arg(long)]
+    version_name: Option<String>,
+    /// Config file to read the database settings from
+    #[arg(long)]
+    config: Option<String>,
+    /// Bring the database schema up to date without importing anything
+    #[arg(long, conflicts_with_all = ["mode", "dry_run", "diff", "file"])]
+    migrate: bool,
+    /// CSV file to import
+    #[arg(required_unless_present = "migrate")]
+    file: Option<String>,
 }
 
-fn setup_type_table(conn: &mut PooledConn) {
-    let existing: Vec<u32> = conn.query("SELECT id FROM infusion_type").unwrap();
-    if existing.len() == 2 {
-        return
-    }
-    println!("Inserting values into infusion_type table");
-    conn.query_drop("DELETE FROM infusion_type").expect("Table creation failed on clearing old table values");
-    conn.query_drop("INSERT INTO infusion_type (id, type) VALUES 
-        (1, 'Drug'),
-        (2, 'Solution')").expect("Table creation failed on insertion!");
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FormatArg {
+    Auto,
+    /// Square matrix with one row and one column per infusion
+    Matrix,
+    /// One row per pair of infusions
+    Long,
 }
 
-fn get_infusion_id_by_name(conn: &mut PooledConn, name: &str) -> Option<u32> {
-    conn.exec_first("SELECT id FROM infusion WHERE name=?", (name,)).expect("DB Query failed!")
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ModeArg {
+    /// Only add new rows, keeping existing rows even if the file disagrees
+    InsertOnly,
+    /// Add new rows and update existing rows that changed
+    Upsert,
+    /// Make the database match the file exactly, removing rows not in the file
+    Replace,
 }
 
-fn import_infusions(conn: &mut PooledConn, data: Vec<InfusionInput>) {
-    let mut name_id_map = HashMap::new();
-
-    for infusion in &data {
-        conn.exec_drop("INSERT IGNORE INTO infusion (name, type) VALUES (?, ?)",
-                    (infusion.name.as_str(), infusion.inf_type))
-                    .expect("DB insert failed!");
-
-        let id = get_infusion_id_by_name(conn, infusion.name.as_str()).unwrap();
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum AsymmetryArg {
+    /// Keep whichever entry comes first in the file
+    FirstWins,
+    /// Add the trial counts together
+    Sum,
+    /// Keep the fewest compatible and the most incompatible and mixed results
+    MostConservative,
+    /// Refuse to import the file
+    Fail,
+}
 
-        name_id_map.insert(infusion.name.as_str(), id);
+impl From<AsymmetryArg> for AsymmetryRule {
+    fn from(rule: AsymmetryArg) -> Self {
+        match rule {
+            AsymmetryArg::FirstWins => AsymmetryRule::FirstWins,
+            AsymmetryArg::Sum => AsymmetryRule::Sum,
+            AsymmetryArg::MostConservative => AsymmetryRule::MostConservative,
+            AsymmetryArg::Fail => AsymmetryRule::Fail,
+        }
     }
+}
 
-    for infusion in &data {
-        let id = *name_id_map.get(infusion.name.as_str()).expect("Invalid compatibility data, infusion not found!");
-        let ic = &infusion.compat;
-        for (other_name, compat_values) in ic.into_iter() {
-            let other_id = *name_id_map.get(other_name).expect("Invalid compatibility data, other infusion not found!");
-
-            let params = if id < other_id {
-                (id, other_id, compat_values[0], compat_values[1], compat_values[2])
-            } else {
-                (other_id, id, compat_values[0], compat_values[1], compat_values[2])
-            };
-
-            conn.exec_drop("INSERT IGNORE INTO infusion_compatibility
-                (infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results)
-                VALUES (?, ?, ?, ?, ?)",
-                params)
-                .expect("Infusion compatibility insert failed!");
+impl From<ModeArg> for ImportMode {
+    fn from(mode: ModeArg) -> Self {
+        match mode {
+            ModeArg::InsertOnly => ImportMode::InsertOnly,
+            ModeArg::Upsert => ImportMode::Upsert,
+            ModeArg::Replace => ImportMode::Replace,
         }
     }
 }
 
-fn connect_db() -> PooledConn {
-    let mut config = Ini::new();
-    config.load("./db.conf").expect("Failed to load DB config!");
-    let host = config.get("db", "host").expect("host not in db.conf!");
-    let db_name = config.get("db", "db_name").expect("db_name not in db.conf!");
-    let user = config.get("db", "user").expect("user not in db.conf!");
-    let password = config.get("db", "password").expect("password not in db.conf!");
-    let url = format!("mysql://{}:{}@{}/{}", user, password, host, db_name);
-
-    let pool = Pool::new(url.as_str()).expect("Unable to parse DB url! Fix your code!!!");
+/// Connect to the database. Imports bring the schema up to date first,
+/// while dry runs and diffs only check it since they mustn't write anything.
+fn open_store(args: &Args) -> Arc<dyn CompatibilityStore> {
+    let config = Config::load_or_exit(args.config.as_deref());
+    let store = db::connect(&config.db).expect("Failed to connect to the database!");
+
+    let result = if args.dry_run || args.diff {
+        store.check_schema()
+    } else {
+        store.migrate().map(|applied| {
+            for migration in applied {
+                eprintln!("Applied migration {}", migration);
+            }
+        })
+    };
+    if let Err(error) = result {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
 
-    pool.get_conn().expect("Failed to connect to DB!")
+    store
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: importer FILE");
-        return;
+/// Read and check an import file, printing any problems found.
+///
+/// Returns `None` if the file can't be imported as it is.
+fn load_file(path: &str, args: &Args) -> Option<ImportData> {
+    let contents = fs::read_to_string(path).expect("Couldn't read from file!");
+
+    let delimiter = match parse::delimiter_for(path, args.delimiter.as_deref()) {
+        Ok(delimiter) => delimiter,
+        Err(message) => {
+            eprintln!("{}", message);
+            return None;
+        }
+    };
+
+    let format = match args.format {
+        FormatArg::Auto => None,
+        FormatArg::Matrix => Some(Format::Matrix),
+        FormatArg::Long => Some(Format::Long),
+    };
+    let mut parsed = parse::parse(&contents, format, delimiter);
+
+    // The long format doesn't need types for infusions that are already in the database
+    if !parsed.untyped.is_empty() {
+        let current = open_store(args).load_dataset().expect("Failed to load current data!");
+        parsed.resolve_types(|name| { current.find_by_name(name).map(|inf| { inf.infusion_type }) });
     }
-    let filename = &args[1];
-    let file: File = File::open(filename).expect("Couldn't open file!");
-    let lines = io::BufReader::new(file).lines();
-    let mut lines = lines.map(|line| { line.expect("Couldn't read from file!")});
-    let headers = lines.next().expect("No data in file!");
 
-    let mut data: Vec<InfusionInput> = Vec::new();
-    let header_items: Vec<&str> = headers.split(',').skip(2).collect();
+    for problem in &parsed.problems {
+        eprintln!("{}: {}", path, problem);
+    }
+    if parsed.has_errors() {
+        let num_errors = parsed.problems.iter().filter(|p| { p.severity == Severity::Error }).count();
+        eprintln!("Found {} error(s) in {}, nothing was imported", num_errors, path);
+        return None;
+    }
 
-    for line in lines {
-        println!("{}", line);
-        let mut items = line.split(',');
-        let name = items.next().expect("Unexpected end of line!").to_string();
-        let inf_type = items.next().expect("Unexpected end of line!").parse().expect("Couldn't parse type!");
+    let rule = args.asymmetric.into();
+    let asymmetric = parsed.data.resolve_asymmetry(rule);
+    for pair in &asymmetric {
+        eprintln!("{}: asymmetric: {}", path, pair);
+    }
+    if rule == AsymmetryRule::Fail && !asymmetric.is_empty() {
+        eprintln!("Found {} asymmetric pair(s) in {}, nothing was imported. Use --asymmetric to choose how to resolve them.",
+            asymmetric.len(), path);
+        return None;
+    }
 
-        let items: Vec<&str> = items.collect();
+    Some(parsed.data)
+}
 
-        if items.len() != header_items.len() {
-            panic!("Header and data lines don't have the same size! {}", name);
-        }
+fn main() -> ExitCode {
+    let args = Args::parse();
 
-        let mut infusion = InfusionInput{ name, inf_type, compat: HashMap::new() };
-        for (i, item) in items.iter().enumerate() {
-            if *item == "" {
-                continue;
-            }
+    if args.migrate {
+        open_store(&args);
+        println!("The database schema is up to date");
+        return ExitCode::SUCCESS;
+    }
 
-            let compat_data: Vec<u32> = item.split(':').map(|n| { n.parse().expect("Invalid compatibility data!") }).collect();
-            infusion.compat.insert(header_items[i], compat_data);
+    let file = args.file.as_deref().expect("file is required");
+    let data = match load_file(file, &args) {
+        Some(data) => data,
+        None => return ExitCode::FAILURE,
+    };
+
+    if args.diff {
+        let base = match &args.base {
+            Some(path) => match load_file(path, &args) {
+                Some(base) => NamedData::from(&base),
+                None => return ExitCode::FAILURE,
+            },
+            None => {
+                let current = open_store(&args).load_dataset().expect("Failed to load current data!");
+                NamedData::from(&current)
+            },
+        };
+
+        let diff = DatasetDiff::new(&base, &NamedData::from(&data));
+        if args.json {
+            println!("{}", serde_json::to_string_pretty(&diff).expect("Failed to serialize diff!"));
+        } else {
+            print!("{}", diff);
         }
-        data.push(infusion);
+        return ExitCode::SUCCESS;
     }
 
-    let mut conn = connect_db();
-    setup_type_table(&mut conn);
+    if args.dry_run {
+        println!("No errors found in {}", file);
+        if let Some(mode) = args.mode {
+            let store = open_store(&args);
+            let current = store.load_dataset().expect("Failed to load current data!");
+            let plan = ImportPlan::new(&current, &data, mode.into()).expect("Invalid import data!");
+            println!("Dry run, nothing was written. The import would make these changes:");
+            println!("{}", plan.summary);
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let file_name = Path::new(file).file_name().map(|name| { name.to_string_lossy() }).unwrap_or_default();
+    let version_name = args.version_name.as_deref().unwrap_or(&file_name);
+    let source = ImportSource::new(version_name, &fs::read(file).expect("Couldn't read from file!"));
 
-    import_infusions(&mut conn, data);
-}
\ No newline at end of file
+    let store = open_store(&args);
+    let mode = args.mode.expect("--mode is required").into();
+    let summary = store.import(&data, mode, &source).expect("DB import failed!");
+    println!("{}", summary);
+
+    ExitCode::SUCCESS
+}
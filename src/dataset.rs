@@ -0,0 +1,298 @@
+use crate::infusion::{CompatibilityData, DedicatedLine, Infusion, InfusionType};
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InfusionRecord {
+    pub id: u32,
+    pub name: String,
+    pub infusion_type: InfusionType,
+    /// Retired infusions keep their data but can't be used in new solves
+    #[serde(default)]
+    pub retired: bool,
+    /// May only be given through central access
+    #[serde(default)]
+    pub central_only: bool,
+    /// Why it needs a line of its own, if it does
+    #[serde(default)]
+    pub dedicated_line: Option<String>,
+}
+
+/// Trial counts for a pair of infusions, as stored in `infusion_compatibility`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompatibilityCounts {
+    pub compatible: u32,
+    pub incompatible: u32,
+    pub mixed: u32,
+}
+
+/// Where a pair's trial counts come from, from the optional columns of the long import format
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompatibilityDetails {
+    pub concentration: Option<String>,
+    pub diluent: Option<String>,
+    pub reference: Option<String>,
+}
+
+impl CompatibilityDetails {
+    pub fn is_empty(&self) -> bool {
+        self.concentration.is_none() && self.diluent.is_none() && self.reference.is_none()
+    }
+}
+
+/// Each import creates a new version of the data, so past results can be reproduced
+#[derive(Debug, Clone, Serialize)]
+pub struct DatasetVersion {
+    pub id: u32,
+    pub name: String,
+    pub created_at: i64,        // Unix timestamp
+    pub source_hash: String,    // SHA-256 of the imported file
+    pub active: bool,
+}
+
+impl fmt::Display for DatasetVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let created_at = DateTime::from_timestamp(self.created_at, 0)
+            .map(|t| { t.format("%Y-%m-%d %H:%M UTC").to_string() })
+            .unwrap_or_else(|| { self.created_at.to_string() });
+        write!(f, "#{} {} ({})", self.id, self.name, created_at)
+    }
+}
+
+/// An immutable snapshot of all infusion and compatibility data.
+///
+/// The web server keeps one of these in memory and swaps it out whenever
+/// the data is reloaded, so solving a problem never touches the database.
+#[derive(Debug, Clone, Default)]
+pub struct Dataset {
+    infusions: BTreeMap<u32, InfusionRecord>,
+    compatibility: HashMap<(u32, u32), CompatibilityCounts>, // (lower id, higher id) -> counts
+    /// (lower id, higher id) -> details, for pairs with compatibility data that have any
+    details: HashMap<(u32, u32), CompatibilityDetails>,
+    /// (lower id, higher id) of pairs that can take turns on one IV with a flush in between
+    flushable: HashSet<(u32, u32)>,
+    /// (id with a dedicated line, id of an infusion that may still share it)
+    line_partners: HashSet<(u32, u32)>,
+    version: Option<DatasetVersion>,
+}
+
+/// How a dataset is stored with its version, as JSON
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    infusions: Vec<InfusionRecord>,
+    compatibility: Vec<(u32, u32, CompatibilityCounts)>,
+    #[serde(default)]
+    flushable: Vec<(u32, u32)>,
+    #[serde(default)]
+    line_partners: Vec<(u32, u32)>,
+    // Left out when empty so snapshots of data without details keep their hashes
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<(u32, u32, CompatibilityDetails)>,
+}
+
+impl Dataset {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The version this data belongs to, if any imports have been made
+    pub fn version(&self) -> Option<&DatasetVersion> {
+        self.version.as_ref()
+    }
+
+    pub fn set_version(&mut self, version: Option<DatasetVersion>) {
+        self.version = version;
+    }
+
+    pub fn to_snapshot(&self) -> String {
+        let mut compatibility = self.compatibility
+            .iter()
+            .map(|((id1, id2), counts)| { (*id1, *id2, *counts) })
+            .collect::<Vec<_>>();
+        compatibility.sort_unstable_by_key(|(id1, id2, _)| { (*id1, *id2) });
+
+        let mut flushable = self.flushable.iter().copied().collect::<Vec<_>>();
+        flushable.sort_unstable();
+
+        let mut line_partners = self.line_partners.iter().copied().collect::<Vec<_>>();
+        line_partners.sort_unstable();
+
+        let mut details = self.details
+            .iter()
+            .map(|((id1, id2), details)| { (*id1, *id2, details.clone()) })
+            .collect::<Vec<_>>();
+        details.sort_unstable_by_key(|(id1, id2, _)| { (*id1, *id2) });
+
+        let snapshot = Snapshot {
+            infusions: self.infusions.values().cloned().collect(),
+            compatibility,
+            flushable,
+            line_partners,
+            details,
+        };
+        serde_json::to_string(&snapshot).expect("Failed to serialize dataset")
+    }
+
+    pub fn from_snapshot(snapshot: &str, version: DatasetVersion) -> serde_json::Result<Self> {
+        let snapshot: Snapshot = serde_json::from_str(snapshot)?;
+
+        let mut dataset = Self::new();
+        for infusion in snapshot.infusions {
+            dataset.add_infusion(infusion);
+        }
+        for (id1, id2, counts) in snapshot.compatibility {
+            dataset.add_compatibility(id1, id2, counts);
+        }
+        for (id1, id2) in snapshot.flushable {
+            dataset.set_flushable(id1, id2, true);
+        }
+        for (id, partner) in snapshot.line_partners {
+            dataset.set_line_partner(id, partner, true);
+        }
+        for (id1, id2, details) in snapshot.details {
+            dataset.set_details(id1, id2, details);
+        }
+        dataset.version = Some(version);
+
+        Ok(dataset)
+    }
+
+    pub fn add_infusion(&mut self, infusion: InfusionRecord) {
+        self.infusions.insert(infusion.id, infusion);
+    }
+
+    pub fn add_compatibility(&mut self, id1: u32, id2: u32, counts: CompatibilityCounts) {
+        self.compatibility.insert(pair_key(id1, id2), counts);
+    }
+
+    /// Set where a pair's counts come from. Empty details are removed.
+    pub fn set_details(&mut self, id1: u32, id2: u32, details: CompatibilityDetails) {
+        if details.is_empty() {
+            self.details.remove(&pair_key(id1, id2));
+        } else {
+            self.details.insert(pair_key(id1, id2), details);
+        }
+    }
+
+    pub fn set_flushable(&mut self, id1: u32, id2: u32, flushable: bool) {
+        if flushable {
+            self.flushable.insert(pair_key(id1, id2));
+        } else {
+            self.flushable.remove(&pair_key(id1, id2));
+        }
+    }
+
+    pub fn set_line_partner(&mut self, id: u32, partner: u32, allowed: bool) {
+        if allowed {
+            self.line_partners.insert((id, partner));
+        } else {
+            self.line_partners.remove(&(id, partner));
+        }
+    }
+
+    pub fn remove_compatibility(&mut self, id1: u32, id2: u32) {
+        self.compatibility.remove(&pair_key(id1, id2));
+        self.details.remove(&pair_key(id1, id2));
+    }
+
+    /// All infusions, ordered by ID
+    pub fn infusions(&self) -> impl Iterator<Item = &InfusionRecord> {
+        self.infusions.values()
+    }
+
+    pub fn infusion(&self, id: u32) -> Option<&InfusionRecord> {
+        self.infusions.get(&id)
+    }
+
+    pub fn find_by_name(&self, name: &str) -> Option<&InfusionRecord> {
+        self.infusions.values().find(|inf| inf.name == name)
+    }
+
+    pub fn compatibility(&self, id1: u32, id2: u32) -> Option<&CompatibilityCounts> {
+        self.compatibility.get(&pair_key(id1, id2))
+    }
+
+    /// Where the pair's counts come from, if that was imported
+    pub fn details(&self, id1: u32, id2: u32) -> Option<&CompatibilityDetails> {
+        self.details.get(&pair_key(id1, id2))
+    }
+
+    /// Every pair with compatibility data, as (lower ID, higher ID)
+    pub fn compatibility_pairs(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.compatibility.keys().copied()
+    }
+
+    /// Whether the pair can take turns on one IV, with a flush in between
+    pub fn is_flushable(&self, id1: u32, id2: u32) -> bool {
+        self.flushable.contains(&pair_key(id1, id2))
+    }
+
+    /// Every flushable pair, as (lower ID, higher ID)
+    pub fn flushable_pairs(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.flushable.iter().copied()
+    }
+
+    /// Whether `partner` may share the dedicated line of infusion `id`
+    pub fn is_line_partner(&self, id: u32, partner: u32) -> bool {
+        self.line_partners.contains(&(id, partner))
+    }
+
+    /// Every (id with a dedicated line, id that may share it) pair
+    pub fn line_partners(&self) -> impl Iterator<Item = (u32, u32)> + '_ {
+        self.line_partners.iter().copied()
+    }
+
+    /// Build the solver's view of the requested infusions.
+    ///
+    /// IDs that aren't in the dataset are left out of the returned map. Retired infusions are
+    /// included, since they may already be running, and `SolveRequest::validate` keeps them from being added.
+    pub fn load_infusions(&self, ids: &[u32]) -> HashMap<u32, Infusion> {
+        let mut infusion_map = HashMap::new();
+        for id in ids {
+            if let Some(record) = self.infusions.get(id) {
+                let mut infusion = Infusion::new(record.id, record.name.clone(), record.infusion_type);
+                infusion.set_central_only(record.central_only);
+                infusion.set_retired(record.retired);
+                if let Some(reason) = &record.dedicated_line {
+                    let allowed_with = self.line_partners
+                        .iter()
+                        .filter(|(id, _)| { *id == record.id })
+                        .map(|(_, partner)| { *partner })
+                        .collect();
+                    infusion.set_dedicated_line(Some(DedicatedLine { reason: reason.clone(), allowed_with }));
+                }
+                infusion_map.insert(record.id, infusion);
+            }
+        }
+
+        let loaded_ids = infusion_map.keys().copied().collect::<Vec<_>>();
+        for (i, id1) in loaded_ids.iter().enumerate() {
+            for id2 in &loaded_ids[i + 1..] {
+                if let Some(counts) = self.compatibility(*id1, *id2) {
+                    let compat_data = Arc::new(
+                        CompatibilityData::new(counts.compatible, counts.incompatible, counts.mixed)
+                    );
+
+                    let infusion1 = infusion_map.get_mut(id1).unwrap();
+                    infusion1.add_compatibility_data(*id2, &compat_data);
+
+                    let infusion2 = infusion_map.get_mut(id2).unwrap();
+                    infusion2.add_compatibility_data(*id1, &compat_data);
+                }
+                if self.is_flushable(*id1, *id2) {
+                    infusion_map.get_mut(id1).unwrap().allow_flush(*id2);
+                    infusion_map.get_mut(id2).unwrap().allow_flush(*id1);
+                }
+            }
+        }
+
+        infusion_map
+    }
+}
+
+fn pair_key(id1: u32, id2: u32) -> (u32, u32) {
+    if id1 < id2 { (id1, id2) } else { (id2, id1) }
+}
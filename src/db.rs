@@ -1,65 +1,206 @@
-use mysql::{Pool, PooledConn};
-use mysql::prelude::*;
-use configparser::ini::Ini;
-use std::collections::HashMap;
-use std::rc::Rc;
-use crate::infusion::{CompatibilityData, Infusion, InfusionType};
-
-pub fn connect_db(config_path: &str) -> Pool {
-    let mut config = Ini::new();
-    config.load(config_path).expect("Failed to load DB config!");
-    let host = config.get("db", "host").expect("host not in db.conf!");
-    let db_name = config.get("db", "db_name").expect("db_name not in db.conf!");
-    let user = config.get("db", "user").expect("user not in db.conf!");
-    let password = config.get("db", "password").expect("password not in db.conf!");
-    let url = format!("mysql://{}:{}@{}/{}", user, password, host, db_name);
-
-    let pool = Pool::new(url.as_str()).expect("Unable to parse DB url! Fix your code!!!");
-
-    pool
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::{error, fmt};
+use crate::access::Device;
+use crate::audit::{AuditAnchor, AuditEntry, AuditQuery, AuditVerification, SolveRecord};
+use crate::auth::{Account, Role};
+use crate::config::DbConfig;
+use crate::dataset::{Dataset, DatasetVersion};
+use crate::edit::{ChangeRecord, Edit};
+use crate::import::{ImportData, ImportMode, ImportSource, ImportSummary};
+use crate::infusion::InfusionType;
+use crate::regimen::Regimen;
+use crate::schedule::Schedule;
+use sha2::{Digest, Sha256};
+
+pub mod memory;
+pub mod migrations;
+pub mod mysql;
+pub mod sqlite;
+
+pub use self::memory::MemoryStore;
+pub use self::migrations::Migration;
+pub use self::mysql::MysqlStore;
+pub use self::sqlite::SqliteStore;
+
+#[derive(Debug)]
+pub enum StoreError {
+    Mysql(::mysql::Error),
+    Sqlite(rusqlite::Error),
+    /// The stored data doesn't make sense, e.g. an unknown infusion type
+    InvalidData(String),
+    /// The database schema is older or newer than this program expects
+    SchemaMismatch { applied: u32, expected: u32 },
+    /// Another program held a lock for too long
+    LockTimeout(&'static str),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Mysql(e) => write!(f, "MySQL error: {}", e),
+            StoreError::Sqlite(e) => write!(f, "SQLite error: {}", e),
+            StoreError::InvalidData(reason) => write!(f, "Invalid data: {}", reason),
+            StoreError::LockTimeout(name) => write!(f, "Timed out waiting for the {} lock", name),
+            StoreError::SchemaMismatch { applied, expected } if applied < expected => {
+                write!(f, "The database schema is at version {} but version {} is needed. Run `importer --migrate` to update it.",
+                    applied, expected)
+            },
+            StoreError::SchemaMismatch { applied, expected } => {
+                write!(f, "The database schema is at version {}, which is newer than this program supports ({}). Update the program.",
+                    applied, expected)
+            },
+        }
+    }
 }
 
-pub fn load_infusions(conn: &mut PooledConn, ids: Vec<&u32>) -> HashMap<u32, Infusion> {
-    let mut infusion_map = HashMap::new();
-
-    // load basic infusion info
-    // no risk of SQL injection since we know all values are u32
-    let ids_param = ids.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(",");
-    let results = conn
-        .query(format!("SELECT id, name, type FROM infusion WHERE id IN ({ids_param})"))
-        .expect("Failed loading infusion data from DB");
-
-    for (id, name, inf_type_id) in results {
-        let inf_type = match inf_type_id {
-            1 => InfusionType::Drug,
-            2 => InfusionType::Solution,
-            _ => panic!("Unknown infusion type")
-        };
-        let infusion = Infusion::new(id, name, inf_type);
-        infusion_map.insert(infusion.id(), infusion);
+impl error::Error for StoreError {}
+
+impl From<::mysql::Error> for StoreError {
+    fn from(e: ::mysql::Error) -> Self {
+        StoreError::Mysql(e)
     }
+}
 
-    // load infusion compatibility info
-    // no risk of SQL injection since we know all values are u32
-    let ids_param = infusion_map.keys().map(|i| { i.to_string() }).collect::<Vec<_>>().join(",");
-    let results = conn
-        .query(
-            format!(
-                "SELECT infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results
-                FROM infusion_compatibility
-                WHERE infusion_a IN ({ids_param}) AND infusion_b in ({ids_param})"
-            )
-        ).expect("Failed loading compatibility data from DB");
-
-    for (id1, id2, compatible, incompatible, mixed) in results {
-        let compat_data = Rc::new(CompatibilityData::new(compatible, incompatible, mixed));
-        
-        let infusion1 = infusion_map.get_mut(&id1).unwrap();
-        infusion1.add_compatibility_data(id2, &compat_data);
-
-        let infusion2 = infusion_map.get_mut(&id2).unwrap();
-        infusion2.add_compatibility_data(id1, &compat_data);
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> Self {
+        StoreError::Sqlite(e)
     }
+}
+
+/// Persistent storage for infusion and compatibility data
+pub trait CompatibilityStore: Send + Sync {
+    /// Read every infusion and compatibility record into memory, tagged with the active version
+    fn load_dataset(&self) -> Result<Dataset, StoreError>;
+
+    /// Bring the stored data in line with `data`, as described by `mode`,
+    /// and record the result as a new active dataset version
+    fn import(&self, data: &ImportData, mode: ImportMode, source: &ImportSource) -> Result<ImportSummary, StoreError>;
+
+    /// Every dataset version, oldest first
+    fn list_versions(&self) -> Result<Vec<DatasetVersion>, StoreError>;
+
+    /// Read the data as it was when a version was created, or `None` if there's no such version
+    fn load_version(&self, id: u32) -> Result<Option<Dataset>, StoreError>;
+
+    /// Make a single change, recording who made it and why, and create a new dataset version for it
+    fn edit(&self, edit: &Edit, username: &str, reason: &str) -> Result<ChangeRecord, StoreError>;
+
+    /// The most recent changes made with `edit`, newest first
+    fn list_changes(&self, limit: u32) -> Result<Vec<ChangeRecord>, StoreError>;
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError>;
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StoreError>;
+
+    /// Fails if an account with the same username exists
+    fn add_account(&self, account: &Account) -> Result<(), StoreError>;
+
+    /// Returns false if there was no such account
+    fn remove_account(&self, username: &str) -> Result<bool, StoreError>;
+
+    /// Returns false if there was no such account
+    fn set_role(&self, username: &str, role: Role) -> Result<bool, StoreError>;
+
+    /// Append a solve to the audit log, chained to the entry before it
+    fn log_solve(&self, record: &SolveRecord) -> Result<AuditEntry, StoreError>;
+
+    fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError>;
+
+    /// Check every entry in the audit log against its hash and the one before it,
+    /// and that the log still holds the anchored entry
+    fn verify_audit_log(&self, anchor: Option<&AuditAnchor>) -> Result<AuditVerification, StoreError>;
+
+    /// Every saved regimen, by label
+    fn regimens(&self) -> Result<Vec<Regimen>, StoreError>;
+
+    fn regimen(&self, id: u32) -> Result<Option<Regimen>, StoreError>;
+
+    /// Fails if a regimen with the same label exists
+    fn add_regimen(&self, label: &str, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>, devices: &[Device],
+                   username: &str) -> Result<Regimen, StoreError>;
+
+    /// Replace a regimen's layout. Fails if it has changed since `revision`.
+    /// Returns `None` if there's no such regimen.
+    fn update_regimen(&self, id: u32, revision: u32, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>,
+                      devices: &[Device], username: &str) -> Result<Option<Regimen>, StoreError>;
+
+    /// Returns false if there was no such regimen
+    fn remove_regimen(&self, id: u32) -> Result<bool, StoreError>;
+
+    /// Apply any schema migrations the database hasn't had yet, returning the ones applied
+    fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError>;
 
-    infusion_map
-}
\ No newline at end of file
+    /// Check that the schema is up to date without changing anything
+    fn check_schema(&self) -> Result<(), StoreError>;
+}
+
+/// Open the store described by `config`
+pub fn connect(config: &DbConfig) -> Result<Arc<dyn CompatibilityStore>, StoreError> {
+    Ok(match config {
+        DbConfig::Mysql(opts) => Arc::new(MysqlStore::new(opts.clone())?),
+        DbConfig::Sqlite { path } => Arc::new(SqliteStore::open(path)?),
+        DbConfig::Memory => Arc::new(MemoryStore::new()),
+    })
+}
+
+/// Current time as a Unix timestamp, for new dataset versions
+pub(crate) fn now() -> i64 {
+    chrono::Utc::now().timestamp()
+}
+
+/// SHA-256 of a snapshot, as hex. Versions with the same data share one stored snapshot.
+pub(crate) fn snapshot_hash(snapshot: &str) -> String {
+    Sha256::digest(snapshot).iter().map(|byte| { format!("{:02x}", byte) }).collect()
+}
+
+pub(crate) fn read_snapshot(snapshot: &str, version: DatasetVersion) -> Result<Dataset, StoreError> {
+    Dataset::from_snapshot(snapshot, version)
+        .map_err(|e| StoreError::InvalidData(format!("unreadable snapshot: {}", e)))
+}
+
+pub(crate) fn account(username: String, password_hash: String, role: &str) -> Result<Account, StoreError> {
+    let role = role.parse().map_err(|e| StoreError::InvalidData(format!("account '{}': {}", username, e)))?;
+
+    Ok(Account { username, password_hash, role })
+}
+
+/// id, label, ivs, schedules, devices, revision, updated_at, updated_by
+pub(crate) type RegimenRow = (u32, String, String, Option<String>, Option<String>, u32, i64, String);
+
+/// Regimen layouts, schedules and devices are stored as JSON
+pub(crate) fn regimen((id, label, ivs, schedules, devices, revision, updated_at, updated_by): RegimenRow)
+                      -> Result<Regimen, StoreError> {
+    let invalid = |e: serde_json::Error| { StoreError::InvalidData(format!("regimen '{}': {}", label, e)) };
+    let ivs = serde_json::from_str(&ivs).map_err(invalid)?;
+    let schedules = schedules.as_deref().map(serde_json::from_str).transpose().map_err(invalid)?.unwrap_or_default();
+    let devices = devices.as_deref().map(serde_json::from_str).transpose().map_err(invalid)?.unwrap_or_default();
+
+    Ok(Regimen { id, label, ivs, schedules, devices, revision, updated_at, updated_by })
+}
+
+/// `None` if there are no schedules, which is how they're stored
+pub(crate) fn schedules_json(schedules: &BTreeMap<u32, Schedule>) -> Option<String> {
+    if schedules.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::to_string(schedules).expect("Failed to serialize schedules"))
+}
+
+/// `None` if the devices weren't given, which is how they're stored
+pub(crate) fn devices_json(devices: &[Device]) -> Option<String> {
+    if devices.is_empty() {
+        return None;
+    }
+
+    Some(serde_json::to_string(devices).expect("Failed to serialize devices"))
+}
+
+pub(crate) fn stale_regimen(label: &str) -> StoreError {
+    StoreError::InvalidData(format!("regimen '{}' was changed by someone else in the meantime", label))
+}
+
+pub(crate) fn infusion_type(id: u32) -> Result<InfusionType, StoreError> {
+    InfusionType::from_id(id).ok_or_else(|| StoreError::InvalidData(format!("unknown infusion type {}", id)))
+}
@@ -0,0 +1,298 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use crate::access::Device;
+use crate::audit::{AuditAnchor, AuditEntry, AuditQuery, AuditVerification, ChainVerifier, SolveRecord, GENESIS_HASH};
+use crate::auth::{Account, Role};
+use crate::dataset::{Dataset, DatasetVersion, InfusionRecord};
+use crate::edit::{self, ChangeRecord, Edit};
+use crate::import::{ImportData, ImportMode, ImportPlan, ImportSource, ImportSummary};
+use crate::regimen::Regimen;
+use crate::schedule::Schedule;
+use super::{now, stale_regimen, CompatibilityStore, Migration, StoreError};
+
+/// Keeps all data in memory. Nothing is persisted, so this is mostly useful for testing.
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    dataset: Dataset,
+    next_id: u32,
+    versions: Vec<Dataset>,     // every imported dataset, tagged with its version
+    changes: Vec<ChangeRecord>,
+    accounts: BTreeMap<String, Account>,
+    audit: Vec<AuditEntry>,
+    regimens: BTreeMap<u32, Regimen>,
+    next_regimen_id: u32,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl State {
+    /// Apply `plan` and snapshot the result as a new active version
+    fn apply_and_snapshot(&mut self, plan: &ImportPlan, source: &ImportSource) -> DatasetVersion {
+        // Apply the changes to a copy so readers never see a partial import
+        let mut dataset = self.dataset.clone();
+
+        for (id1, id2) in &plan.remove_compatibility {
+            dataset.remove_compatibility(*id1, *id2);
+        }
+        for (id1, id2, flushable) in &plan.set_flushable {
+            dataset.set_flushable(*id1, *id2, *flushable);
+        }
+        for (id, partner, allowed) in &plan.set_line_partners {
+            dataset.set_line_partner(*id, *partner, *allowed);
+        }
+        for (id, infusion_type) in &plan.update_infusions {
+            let mut infusion = dataset.infusion(*id).unwrap().clone();
+            infusion.infusion_type = *infusion_type;
+            dataset.add_infusion(infusion);
+        }
+        for (id, name) in &plan.rename_infusions {
+            let mut infusion = dataset.infusion(*id).unwrap().clone();
+            infusion.name = name.clone();
+            dataset.add_infusion(infusion);
+        }
+        for (id, retired) in &plan.retire_infusions {
+            let mut infusion = dataset.infusion(*id).unwrap().clone();
+            infusion.retired = *retired;
+            dataset.add_infusion(infusion);
+        }
+        for (id, central_only) in &plan.central_only_infusions {
+            let mut infusion = dataset.infusion(*id).unwrap().clone();
+            infusion.central_only = *central_only;
+            dataset.add_infusion(infusion);
+        }
+        for (id, dedicated_line) in &plan.dedicated_line_infusions {
+            let mut infusion = dataset.infusion(*id).unwrap().clone();
+            infusion.dedicated_line = dedicated_line.clone();
+            dataset.add_infusion(infusion);
+        }
+        for infusion in &plan.add_infusions {
+            self.next_id += 1;
+            dataset.add_infusion(InfusionRecord {
+                id: self.next_id,
+                name: infusion.name.clone(),
+                infusion_type: infusion.infusion_type,
+                retired: false,
+                central_only: false,
+                dedicated_line: None,
+            });
+        }
+        for compat in &plan.add_compatibility {
+            let id1 = dataset.find_by_name(&compat.infusion_a).unwrap().id;
+            let id2 = dataset.find_by_name(&compat.infusion_b).unwrap().id;
+            dataset.add_compatibility(id1, id2, compat.counts);
+            dataset.set_details(id1, id2, compat.details.clone().unwrap_or_default());
+        }
+        for (id1, id2, counts) in &plan.update_compatibility {
+            dataset.add_compatibility(*id1, *id2, *counts);
+        }
+        for (id1, id2, details) in &plan.set_details {
+            dataset.set_details(*id1, *id2, details.clone());
+        }
+
+        for old in self.versions.iter_mut() {
+            let version = old.version().cloned().map(|version| { DatasetVersion { active: false, ..version } });
+            old.set_version(version);
+        }
+        let version = DatasetVersion {
+            id: self.versions.len() as u32 + 1,
+            name: source.name.clone(),
+            created_at: now(),
+            source_hash: source.source_hash.clone(),
+            active: true,
+        };
+        dataset.set_version(Some(version.clone()));
+        self.versions.push(dataset.clone());
+        self.dataset = dataset;
+
+        version
+    }
+}
+
+impl CompatibilityStore for MemoryStore {
+    fn load_dataset(&self) -> Result<Dataset, StoreError> {
+        Ok(self.state.lock().unwrap().dataset.clone())
+    }
+
+    fn import(&self, data: &ImportData, mode: ImportMode, source: &ImportSource) -> Result<ImportSummary, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let mut plan = ImportPlan::new(&state.dataset, data, mode)?;
+        plan.summary.version = Some(state.apply_and_snapshot(&plan, source));
+
+        Ok(plan.summary)
+    }
+
+    fn list_versions(&self) -> Result<Vec<DatasetVersion>, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.versions.iter().filter_map(|dataset| { dataset.version().cloned() }).collect())
+    }
+
+    fn load_version(&self, id: u32) -> Result<Option<Dataset>, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.versions.iter().find(|dataset| { dataset.version().map(|v| { v.id }) == Some(id) }).cloned())
+    }
+
+    fn edit(&self, edit: &Edit, username: &str, reason: &str) -> Result<ChangeRecord, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let (plan, description) = edit.plan(&state.dataset)?;
+        let source = edit::version_source(username, &description, reason);
+        let version = state.apply_and_snapshot(&plan, &source);
+
+        let record = ChangeRecord {
+            id: state.changes.len() as u32 + 1,
+            changed_at: now(),
+            username: username.to_string(),
+            description,
+            reason: reason.to_string(),
+            dataset_version: version.id,
+        };
+        state.changes.push(record.clone());
+
+        Ok(record)
+    }
+
+    fn list_changes(&self, limit: u32) -> Result<Vec<ChangeRecord>, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        Ok(state.changes.iter().rev().take(limit as usize).cloned().collect())
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        Ok(self.state.lock().unwrap().accounts.values().cloned().collect())
+    }
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StoreError> {
+        Ok(self.state.lock().unwrap().accounts.get(username).cloned())
+    }
+
+    fn add_account(&self, account: &Account) -> Result<(), StoreError> {
+        let mut state = self.state.lock().unwrap();
+        if state.accounts.contains_key(&account.username) {
+            return Err(StoreError::InvalidData(format!("account '{}' already exists", account.username)));
+        }
+        state.accounts.insert(account.username.clone(), account.clone());
+
+        Ok(())
+    }
+
+    fn remove_account(&self, username: &str) -> Result<bool, StoreError> {
+        Ok(self.state.lock().unwrap().accounts.remove(username).is_some())
+    }
+
+    fn set_role(&self, username: &str, role: Role) -> Result<bool, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(account) = state.accounts.get_mut(username) else { return Ok(false) };
+        account.role = role;
+
+        Ok(true)
+    }
+
+    fn log_solve(&self, record: &SolveRecord) -> Result<AuditEntry, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let entry = match state.audit.last() {
+            Some(last) => AuditEntry::next(last.id, &last.hash, now(), record),
+            None => AuditEntry::next(0, GENESIS_HASH, now(), record),
+        };
+        state.audit.push(entry.clone());
+
+        Ok(entry)
+    }
+
+    fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let state = self.state.lock().unwrap();
+        let (from, to) = (query.from.unwrap_or(i64::MIN), query.to.unwrap_or(i64::MAX));
+
+        Ok(state.audit
+            .iter()
+            .rev()
+            .filter(|entry| { entry.logged_at >= from && entry.logged_at <= to })
+            .filter(|entry| { query.infusion_id.is_none_or(|id| { entry.record.infusion_ids().contains(&id) }) })
+            .take(query.limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    fn verify_audit_log(&self, anchor: Option<&AuditAnchor>) -> Result<AuditVerification, StoreError> {
+        let state = self.state.lock().unwrap();
+
+        let mut verifier = ChainVerifier::new(anchor);
+        for entry in &state.audit {
+            verifier.check(entry.id, entry.logged_at, entry.record_json(), &entry.prev_hash, &entry.hash);
+        }
+
+        Ok(verifier.finish())
+    }
+
+    fn regimens(&self) -> Result<Vec<Regimen>, StoreError> {
+        let mut regimens = self.state.lock().unwrap().regimens.values().cloned().collect::<Vec<_>>();
+        regimens.sort_by(|a, b| { a.label.cmp(&b.label) });
+
+        Ok(regimens)
+    }
+
+    fn regimen(&self, id: u32) -> Result<Option<Regimen>, StoreError> {
+        Ok(self.state.lock().unwrap().regimens.get(&id).cloned())
+    }
+
+    fn add_regimen(&self, label: &str, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>, devices: &[Device],
+                   username: &str) -> Result<Regimen, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        if state.regimens.values().any(|regimen| { regimen.label == label }) {
+            return Err(StoreError::InvalidData(format!("regimen '{}' already exists", label)));
+        }
+        state.next_regimen_id += 1;
+        let regimen = Regimen {
+            id: state.next_regimen_id,
+            label: label.to_string(),
+            ivs: ivs.to_vec(),
+            schedules: schedules.clone(),
+            devices: devices.to_vec(),
+            revision: 1,
+            updated_at: now(),
+            updated_by: username.to_string(),
+        };
+        state.regimens.insert(regimen.id, regimen.clone());
+
+        Ok(regimen)
+    }
+
+    fn update_regimen(&self, id: u32, revision: u32, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>,
+                      devices: &[Device], username: &str) -> Result<Option<Regimen>, StoreError> {
+        let mut state = self.state.lock().unwrap();
+        let Some(regimen) = state.regimens.get_mut(&id) else { return Ok(None) };
+        if regimen.revision != revision {
+            return Err(stale_regimen(&regimen.label));
+        }
+        regimen.ivs = ivs.to_vec();
+        regimen.schedules = schedules.clone();
+        regimen.devices = devices.to_vec();
+        regimen.revision += 1;
+        regimen.updated_at = now();
+        regimen.updated_by = username.to_string();
+
+        Ok(Some(regimen.clone()))
+    }
+
+    fn remove_regimen(&self, id: u32) -> Result<bool, StoreError> {
+        Ok(self.state.lock().unwrap().regimens.remove(&id).is_some())
+    }
+
+    /// There's no schema to migrate
+    fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        Ok(Vec::new())
+    }
+
+    fn check_schema(&self) -> Result<(), StoreError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+use super::StoreError;
+
+/// A change to the database schema, applied once and recorded in `schema_migrations`.
+///
+/// Migrations are numbered from 1 without gaps. Never edit one that has been released;
+/// add a new one instead.
+#[derive(Debug)]
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub(crate) sql: &'static str,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}_{}", self.version, self.name)
+    }
+}
+
+pub(crate) const MYSQL: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/mysql/0001_initial.sql") },
+    Migration { version: 2, name: "dataset_versions", sql: include_str!("../migrations/mysql/0002_dataset_versions.sql") },
+    Migration { version: 3, name: "admin_edits", sql: include_str!("../migrations/mysql/0003_admin_edits.sql") },
+    Migration { version: 4, name: "account_roles", sql: include_str!("../migrations/mysql/0004_account_roles.sql") },
+    Migration { version: 5, name: "solve_audit", sql: include_str!("../migrations/mysql/0005_solve_audit.sql") },
+    Migration { version: 6, name: "regimens", sql: include_str!("../migrations/mysql/0006_regimens.sql") },
+    Migration { version: 7, name: "regimen_schedules", sql: include_str!("../migrations/mysql/0007_regimen_schedules.sql") },
+    Migration { version: 8, name: "flush_pairs", sql: include_str!("../migrations/mysql/0008_flush_pairs.sql") },
+    Migration { version: 9, name: "central_access", sql: include_str!("../migrations/mysql/0009_central_access.sql") },
+    Migration { version: 10, name: "dedicated_lines", sql: include_str!("../migrations/mysql/0010_dedicated_lines.sql") },
+    Migration { version: 11, name: "regimen_devices", sql: include_str!("../migrations/mysql/0011_regimen_devices.sql") },
+    Migration { version: 12, name: "snapshot_store", sql: include_str!("../migrations/mysql/0012_snapshot_store.sql") },
+    Migration { version: 13, name: "compatibility_details", sql: include_str!("../migrations/mysql/0013_compatibility_details.sql") },
+];
+
+pub(crate) const SQLITE: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("../migrations/sqlite/0001_initial.sql") },
+    Migration { version: 2, name: "dataset_versions", sql: include_str!("../migrations/sqlite/0002_dataset_versions.sql") },
+    Migration { version: 3, name: "admin_edits", sql: include_str!("../migrations/sqlite/0003_admin_edits.sql") },
+    Migration { version: 4, name: "account_roles", sql: include_str!("../migrations/sqlite/0004_account_roles.sql") },
+    Migration { version: 5, name: "solve_audit", sql: include_str!("../migrations/sqlite/0005_solve_audit.sql") },
+    Migration { version: 6, name: "regimens", sql: include_str!("../migrations/sqlite/0006_regimens.sql") },
+    Migration { version: 7, name: "regimen_schedules", sql: include_str!("../migrations/sqlite/0007_regimen_schedules.sql") },
+    Migration { version: 8, name: "flush_pairs", sql: include_str!("../migrations/sqlite/0008_flush_pairs.sql") },
+    Migration { version: 9, name: "central_access", sql: include_str!("../migrations/sqlite/0009_central_access.sql") },
+    Migration { version: 10, name: "dedicated_lines", sql: include_str!("../migrations/sqlite/0010_dedicated_lines.sql") },
+    Migration { version: 11, name: "regimen_devices", sql: include_str!("../migrations/sqlite/0011_regimen_devices.sql") },
+    Migration { version: 12, name: "snapshot_store", sql: include_str!("../migrations/sqlite/0012_snapshot_store.sql") },
+    Migration { version: 13, name: "compatibility_details", sql: include_str!("../migrations/sqlite/0013_compatibility_details.sql") },
+];
+
+/// The statements in a migration, in order. Statements end with `;` at the end of a line.
+pub(crate) fn statements(sql: &str) -> Vec<&str> {
+    let mut statements = Vec::new();
+    let (mut start, mut offset) = (0, 0);
+    for line in sql.split_inclusive('\n') {
+        offset += line.len();
+        if line.trim_end().ends_with(';') {
+            statements.push(sql[start..offset].trim());
+            start = offset;
+        }
+    }
+
+    statements
+}
+
+/// The migrations that still need to be applied to a schema at version `applied`
+pub(crate) fn pending(applied: u32, migrations: &'static [Migration]) -> Result<Vec<&'static Migration>, StoreError> {
+    let latest = migrations.last().map(|m| { m.version }).unwrap_or(0);
+    if applied > latest {
+        return Err(StoreError::SchemaMismatch { applied, expected: latest });
+    }
+
+    Ok(migrations.iter().filter(|m| { m.version > applied }).collect())
+}
+
+/// Fail unless a schema at version `applied` is exactly what this program expects
+pub(crate) fn check(applied: u32, migrations: &'static [Migration]) -> Result<(), StoreError> {
+    match pending(applied, migrations)?.last() {
+        Some(latest) => Err(StoreError::SchemaMismatch { applied, expected: latest.version }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_have_no_gaps() {
+        for list in [MYSQL, SQLITE] {
+            for (i, migration) in list.iter().enumerate() {
+                assert_eq!(migration.version, i as u32 + 1);
+            }
+        }
+        assert_eq!(MYSQL.len(), SQLITE.len());
+    }
+
+    #[test]
+    fn splits_statements() {
+        let sql = "-- comment\nCREATE TABLE a (\n    id INT\n);\n\nINSERT INTO a VALUES (1);\n-- trailing comment\n";
+        assert_eq!(statements(sql), vec!["-- comment\nCREATE TABLE a (\n    id INT\n);", "INSERT INTO a VALUES (1);"]);
+    }
+
+    #[test]
+    fn triggers_are_dropped_before_being_created() {
+        let statements = statements(MYSQL[4].sql);
+        assert_eq!(statements.len(), 10);
+        for (i, _) in statements.iter().enumerate().filter(|(_, s)| { s.contains("CREATE TRIGGER") }) {
+            assert!(statements[i - 1].contains("DROP TRIGGER IF EXISTS"));
+        }
+    }
+
+    #[test]
+    fn pending_and_check() {
+        assert_eq!(pending(3, SQLITE).unwrap().len(), SQLITE.len() - 3);
+        assert!(check(SQLITE.len() as u32, SQLITE).is_ok());
+        assert!(matches!(check(1, SQLITE), Err(StoreError::SchemaMismatch { applied: 1, .. })));
+        assert!(matches!(pending(99, SQLITE), Err(StoreError::SchemaMismatch { applied: 99, .. })));
+    }
+}
@@ -0,0 +1,527 @@
+use mysql::{Opts, Pool, PooledConn, TxOpts, Value};
+use mysql::prelude::*;
+use std::collections::{BTreeMap, HashMap};
+use crate::access::Device;
+use crate::audit::{AuditAnchor, AuditEntry, AuditQuery, AuditVerification, ChainVerifier, SolveRecord, GENESIS_HASH};
+use crate::auth::{Account, Role};
+use crate::dataset::{CompatibilityCounts, CompatibilityDetails, Dataset, DatasetVersion, InfusionRecord};
+use crate::edit::{self, ChangeRecord, Edit};
+use crate::import::{ImportData, ImportMode, ImportPlan, ImportSource, ImportSummary};
+use crate::regimen::Regimen;
+use crate::schedule::Schedule;
+use super::{account, devices_json, infusion_type, migrations, now, read_snapshot, regimen, schedules_json};
+use super::{snapshot_hash, stale_regimen, CompatibilityStore, Migration, RegimenRow, StoreError};
+
+/// MySQL's error for adding a column that already exists
+const ER_DUP_FIELDNAME: u16 = 1060;
+
+/// Maximum number of rows in a single multi-row INSERT
+const INSERT_BATCH_SIZE: usize = 500;
+
+const VERSION_COLUMNS: &str = "id, name, created_at, source_hash, active";
+const AUDIT_COLUMNS: &str = "id, logged_at, record, prev_hash, hash";
+const REGIMEN_COLUMNS: &str = "id, label, ivs, schedules, devices, revision, updated_at, updated_by";
+
+type VersionRow = (u32, String, i64, String, bool);
+/// infusion_a, infusion_b, the three counts, concentration, diluent, reference
+type CompatibilityRow = (u32, u32, u32, u32, u32, Option<String>, Option<String>, Option<String>);
+
+pub struct MysqlStore {
+    pool: Pool,
+}
+
+impl MysqlStore {
+    pub fn new(opts: Opts) -> Result<Self, StoreError> {
+        let pool = Pool::new(opts)?;
+
+        Ok(Self { pool })
+    }
+
+    /// The newest migration applied, or 0 for a new database
+    fn schema_version<Q: Queryable>(conn: &mut Q) -> Result<u32, StoreError> {
+        let has_table: Option<bool> = conn.query_first(
+            "SELECT COUNT(*) > 0 FROM information_schema.tables
+            WHERE table_schema = DATABASE() AND table_name = 'schema_migrations'"
+        )?;
+        if has_table != Some(true) {
+            return Ok(0);
+        }
+
+        let version: Option<u32> = conn.query_first("SELECT COALESCE(MAX(version), 0) FROM schema_migrations")?;
+
+        Ok(version.unwrap_or(0))
+    }
+
+    fn read_dataset<Q: Queryable>(conn: &mut Q) -> Result<Dataset, StoreError> {
+        let mut dataset = Dataset::new();
+
+        let infusions: Vec<(u32, String, u32, bool, bool, Option<String>)> =
+            conn.query("SELECT id, name, type, retired, central_only, dedicated_line FROM infusion")?;
+        for (id, name, inf_type_id, retired, central_only, dedicated_line) in infusions {
+            let infusion_type = infusion_type(inf_type_id)?;
+            dataset.add_infusion(InfusionRecord { id, name, infusion_type, retired, central_only, dedicated_line });
+        }
+
+        let results: Vec<CompatibilityRow> = conn.query(
+            "SELECT infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results,
+                concentration, diluent, reference
+            FROM infusion_compatibility"
+        )?;
+        for (id1, id2, compatible, incompatible, mixed, concentration, diluent, reference) in results {
+            dataset.add_compatibility(id1, id2, CompatibilityCounts { compatible, incompatible, mixed });
+            dataset.set_details(id1, id2, CompatibilityDetails { concentration, diluent, reference });
+        }
+
+        let flushable: Vec<(u32, u32)> = conn.query("SELECT infusion_a, infusion_b FROM flushable_pair")?;
+        for (id1, id2) in flushable {
+            dataset.set_flushable(id1, id2, true);
+        }
+
+        let partners: Vec<(u32, u32)> = conn.query("SELECT infusion, partner FROM dedicated_line_partner")?;
+        for (id, partner) in partners {
+            dataset.set_line_partner(id, partner, true);
+        }
+
+        Ok(dataset)
+    }
+
+    fn to_version((id, name, created_at, source_hash, active): VersionRow) -> DatasetVersion {
+        DatasetVersion { id, name, created_at, source_hash, active }
+    }
+
+    fn active_version<Q: Queryable>(conn: &mut Q) -> Result<Option<DatasetVersion>, StoreError> {
+        let row: Option<VersionRow> = conn.query_first(
+            format!("SELECT {} FROM dataset_version WHERE active=TRUE", VERSION_COLUMNS)
+        )?;
+
+        Ok(row.map(Self::to_version))
+    }
+
+    /// Snapshot `dataset` as a new version and make it the active one
+    fn create_version<Q: Queryable>(conn: &mut Q, source: &ImportSource, dataset: &Dataset) -> Result<DatasetVersion, StoreError> {
+        let created_at = now();
+        conn.query_drop("UPDATE dataset_version SET active=FALSE WHERE active=TRUE")?;
+        let snapshot = dataset.to_snapshot();
+        let hash = snapshot_hash(&snapshot);
+        conn.exec_drop("INSERT IGNORE INTO dataset_snapshot (hash, snapshot) VALUES (?, ?)", (&hash, snapshot))?;
+        conn.exec_drop("INSERT INTO dataset_version (name, created_at, source_hash, active, snapshot, snapshot_hash)
+            VALUES (?, ?, ?, TRUE, '', ?)",
+            (&source.name, created_at, &source.source_hash, &hash))?;
+        let id: Option<u32> = conn.query_first("SELECT LAST_INSERT_ID()")?;
+
+        Ok(DatasetVersion {
+            id: id.expect("LAST_INSERT_ID() returned nothing"),
+            name: source.name.clone(),
+            created_at,
+            source_hash: source.source_hash.clone(),
+            active: true,
+        })
+    }
+
+    fn apply_plan<Q: Queryable>(conn: &mut Q, plan: &ImportPlan) -> Result<(), StoreError> {
+        conn.exec_batch("DELETE FROM infusion_compatibility WHERE infusion_a=? AND infusion_b=?",
+                        plan.remove_compatibility.iter())?;
+        conn.exec_batch("DELETE FROM flushable_pair WHERE infusion_a=? AND infusion_b=?",
+                        plan.set_flushable.iter().filter(|(_, _, flushable)| { !flushable }).map(|(id1, id2, _)| { (id1, id2) }))?;
+        conn.exec_batch("INSERT IGNORE INTO flushable_pair (infusion_a, infusion_b) VALUES (?, ?)",
+                        plan.set_flushable.iter().filter(|(_, _, flushable)| { *flushable }).map(|(id1, id2, _)| { (id1, id2) }))?;
+        conn.exec_batch("DELETE FROM dedicated_line_partner WHERE infusion=? AND partner=?",
+                        plan.set_line_partners.iter().filter(|(_, _, allowed)| { !allowed }).map(|(id, partner, _)| { (id, partner) }))?;
+        conn.exec_batch("INSERT IGNORE INTO dedicated_line_partner (infusion, partner) VALUES (?, ?)",
+                        plan.set_line_partners.iter().filter(|(_, _, allowed)| { *allowed }).map(|(id, partner, _)| { (id, partner) }))?;
+        conn.exec_batch("UPDATE infusion SET type=? WHERE id=?",
+                        plan.update_infusions.iter().map(|(id, infusion_type)| { (infusion_type.id(), id) }))?;
+        conn.exec_batch("UPDATE infusion SET name=? WHERE id=?",
+                        plan.rename_infusions.iter().map(|(id, name)| { (name, id) }))?;
+        conn.exec_batch("UPDATE infusion SET retired=? WHERE id=?",
+                        plan.retire_infusions.iter().map(|(id, retired)| { (retired, id) }))?;
+        conn.exec_batch("UPDATE infusion SET central_only=? WHERE id=?",
+                        plan.central_only_infusions.iter().map(|(id, central_only)| { (central_only, id) }))?;
+        conn.exec_batch("UPDATE infusion SET dedicated_line=? WHERE id=?",
+                        plan.dedicated_line_infusions.iter().map(|(id, dedicated_line)| { (dedicated_line, id) }))?;
+
+        let infusion_rows = plan.add_infusions
+            .iter()
+            .map(|infusion| { vec![Value::from(infusion.name.as_str()), Value::from(infusion.infusion_type.id())] })
+            .collect::<Vec<_>>();
+        Self::insert_rows(conn, "infusion (name, type)", &infusion_rows)?;
+
+        let name_id_map: HashMap<String, u32> = conn
+            .query_map("SELECT name, id FROM infusion", |(name, id)| { (name, id) })?
+            .into_iter()
+            .collect();
+        let compat_rows = plan.add_compatibility
+            .iter()
+            .map(|compat| {
+                let id = name_id_map[&compat.infusion_a];
+                let other_id = name_id_map[&compat.infusion_b];
+                let (id1, id2) = if id < other_id { (id, other_id) } else { (other_id, id) };
+                let counts = compat.counts;
+                let details = compat.details.clone().unwrap_or_default();
+
+                let mut row = vec![id1, id2, counts.compatible, counts.incompatible, counts.mixed]
+                    .into_iter()
+                    .map(Value::from)
+                    .collect::<Vec<_>>();
+                row.extend([details.concentration, details.diluent, details.reference].into_iter().map(Value::from));
+                row
+            })
+            .collect::<Vec<_>>();
+        Self::insert_rows(conn,
+            "infusion_compatibility (infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results, \
+             concentration, diluent, reference)",
+            &compat_rows)?;
+
+        conn.exec_batch("UPDATE infusion_compatibility
+            SET compatible_results=?, incompatible_results=?, mixed_results=?
+            WHERE infusion_a=? AND infusion_b=?",
+            plan.update_compatibility.iter().map(|(id1, id2, counts)| {
+                (counts.compatible, counts.incompatible, counts.mixed, id1, id2)
+            }))?;
+        conn.exec_batch("UPDATE infusion_compatibility
+            SET concentration=?, diluent=?, reference=?
+            WHERE infusion_a=? AND infusion_b=?",
+            plan.set_details.iter().map(|(id1, id2, details)| {
+                (&details.concentration, &details.diluent, &details.reference, id1, id2)
+            }))?;
+
+        Ok(())
+    }
+
+    /// Apply `plan` and snapshot the result as a new active version
+    fn apply_and_snapshot<Q: Queryable>(conn: &mut Q, plan: &ImportPlan, source: &ImportSource) -> Result<DatasetVersion, StoreError> {
+        Self::apply_plan(conn, plan)?;
+        let dataset = Self::read_dataset(conn)?;
+
+        Self::create_version(conn, source, &dataset)
+    }
+
+    /// Run `f` while holding the named lock, waiting up to `timeout` seconds for it.
+    /// The lock is released afterwards, whether `f` succeeds or not.
+    fn with_lock<T>(conn: &mut PooledConn, name: &'static str, timeout: u32,
+                    f: impl FnOnce(&mut PooledConn) -> Result<T, StoreError>) -> Result<T, StoreError> {
+        // 1 if the lock was taken, 0 on a timeout and NULL on an error
+        let locked: Option<Option<i64>> = conn.exec_first("SELECT GET_LOCK(?, ?)", (name, timeout))?;
+        if locked != Some(Some(1)) {
+            return Err(StoreError::LockTimeout(name));
+        }
+
+        let result = f(conn);
+        let released = conn.exec_drop("SELECT RELEASE_LOCK(?)", (name,));
+        let value = result?;
+        released?;
+
+        Ok(value)
+    }
+
+    fn apply_migrations<Q: Queryable>(conn: &mut Q) -> Result<Vec<&'static Migration>, StoreError> {
+        conn.query_drop("CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INT UNSIGNED PRIMARY KEY,
+            name VARCHAR(255) NOT NULL,
+            applied_at BIGINT NOT NULL
+        )")?;
+
+        // MySQL commits each DDL statement straight away, so progress is recorded per statement
+        // and a migration that failed partway carries on where it stopped
+        conn.query_drop("CREATE TABLE IF NOT EXISTS schema_migration_step (
+            version INT UNSIGNED NOT NULL,
+            step INT UNSIGNED NOT NULL,
+            PRIMARY KEY (version, step)
+        )")?;
+
+        let pending = migrations::pending(Self::schema_version(conn)?, migrations::MYSQL)?;
+        for migration in &pending {
+            let done: Vec<u32> = conn.exec("SELECT step FROM schema_migration_step WHERE version=?", (migration.version,))?;
+            for (step, statement) in migrations::statements(migration.sql).into_iter().enumerate() {
+                let step = step as u32;
+                if done.contains(&step) {
+                    continue;
+                }
+                match conn.query_drop(statement) {
+                    // The column was added, but the program stopped before recording it
+                    Err(mysql::Error::MySqlError(e)) if e.code == ER_DUP_FIELDNAME && statement.contains("ADD COLUMN") => {},
+                    result => result?,
+                }
+                conn.exec_drop("INSERT INTO schema_migration_step (version, step) VALUES (?, ?)", (migration.version, step))?;
+            }
+            conn.exec_drop("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?, ?, ?)",
+                           (migration.version, migration.name, now()))?;
+            conn.exec_drop("DELETE FROM schema_migration_step WHERE version=?", (migration.version,))?;
+        }
+
+        Ok(pending)
+    }
+
+    fn append_audit<Q: Queryable>(conn: &mut Q, record: &SolveRecord) -> Result<AuditEntry, StoreError> {
+        let last: Option<(u64, String)> = conn.query_first("SELECT id, hash FROM solve_audit ORDER BY id DESC LIMIT 1")?;
+        let (prev_id, prev_hash) = last.unwrap_or((0, GENESIS_HASH.to_string()));
+        let entry = AuditEntry::next(prev_id, &prev_hash, now(), record);
+
+        conn.exec_drop(format!("INSERT INTO solve_audit ({}) VALUES (?, ?, ?, ?, ?)", AUDIT_COLUMNS),
+            (entry.id, entry.logged_at, entry.record_json(), &entry.prev_hash, &entry.hash))?;
+        let rows = record.infusion_ids()
+            .into_iter()
+            .map(|infusion_id| { vec![Value::from(infusion_id), Value::from(entry.id)] })
+            .collect::<Vec<_>>();
+        Self::insert_rows(conn, "solve_audit_infusion (infusion_id, audit_id)", &rows)?;
+
+        Ok(entry)
+    }
+
+    fn find_regimen<Q: Queryable>(conn: &mut Q, id: u32) -> Result<Option<Regimen>, StoreError> {
+        let row: Option<RegimenRow> = conn.exec_first(format!("SELECT {} FROM regimen WHERE id=?", REGIMEN_COLUMNS), (id,))?;
+
+        row.map(regimen).transpose()
+    }
+
+    /// Insert rows with as few statements as possible.
+    /// `table` is the table name followed by its column list.
+    fn insert_rows<Q: Queryable>(conn: &mut Q, table: &str, rows: &[Vec<Value>]) -> Result<(), StoreError> {
+        for batch in rows.chunks(INSERT_BATCH_SIZE) {
+            let row_placeholder = format!("({})", vec!["?"; batch[0].len()].join(", "));
+            let placeholders = vec![row_placeholder; batch.len()].join(", ");
+            let params = batch.iter().flatten().cloned().collect::<Vec<_>>();
+
+            conn.exec_drop(format!("INSERT INTO {} VALUES {}", table, placeholders), params)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl CompatibilityStore for MysqlStore {
+    fn load_dataset(&self) -> Result<Dataset, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let mut dataset = Self::read_dataset(&mut conn)?;
+        dataset.set_version(Self::active_version(&mut conn)?);
+
+        Ok(dataset)
+    }
+
+    fn import(&self, data: &ImportData, mode: ImportMode, source: &ImportSource) -> Result<ImportSummary, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        // Everything happens in one transaction, which is rolled back if it's dropped without committing
+        let mut tx = conn.start_transaction(TxOpts::default())?;
+
+        let current = Self::read_dataset(&mut tx)?;
+        let mut plan = ImportPlan::new(&current, data, mode)?;
+        plan.summary.version = Some(Self::apply_and_snapshot(&mut tx, &plan, source)?);
+        tx.commit()?;
+
+        Ok(plan.summary)
+    }
+
+    fn list_versions(&self) -> Result<Vec<DatasetVersion>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let rows: Vec<VersionRow> = conn.query(format!("SELECT {} FROM dataset_version ORDER BY id", VERSION_COLUMNS))?;
+
+        Ok(rows.into_iter().map(Self::to_version).collect())
+    }
+
+    fn load_version(&self, id: u32) -> Result<Option<Dataset>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let row: Option<(u32, String, i64, String, bool, String)> = conn.exec_first(
+            format!("SELECT {}, COALESCE(s.snapshot, v.snapshot) FROM dataset_version v
+                LEFT JOIN dataset_snapshot s ON s.hash = v.snapshot_hash WHERE id=?", VERSION_COLUMNS),
+            (id,)
+        )?;
+
+        row.map(|(id, name, created_at, source_hash, active, snapshot)| {
+            read_snapshot(&snapshot, Self::to_version((id, name, created_at, source_hash, active)))
+        }).transpose()
+    }
+
+    fn edit(&self, edit: &Edit, username: &str, reason: &str) -> Result<ChangeRecord, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        let mut tx = conn.start_transaction(TxOpts::default())?;
+        let current = Self::read_dataset(&mut tx)?;
+        let (plan, description) = edit.plan(&current)?;
+        let source = edit::version_source(username, &description, reason);
+        let version = Self::apply_and_snapshot(&mut tx, &plan, &source)?;
+
+        let changed_at = now();
+        tx.exec_drop("INSERT INTO change_log (changed_at, username, description, reason, dataset_version)
+            VALUES (?, ?, ?, ?, ?)", (changed_at, username, &description, reason, version.id))?;
+        let id: Option<u32> = tx.query_first("SELECT LAST_INSERT_ID()")?;
+        let record = ChangeRecord {
+            id: id.expect("LAST_INSERT_ID() returned nothing"),
+            changed_at,
+            username: username.to_string(),
+            description,
+            reason: reason.to_string(),
+            dataset_version: version.id,
+        };
+        tx.commit()?;
+
+        Ok(record)
+    }
+
+    fn list_changes(&self, limit: u32) -> Result<Vec<ChangeRecord>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let changes = conn.exec_map(
+            "SELECT id, changed_at, username, description, reason, dataset_version
+            FROM change_log ORDER BY id DESC LIMIT ?",
+            (limit,),
+            |(id, changed_at, username, description, reason, dataset_version)| {
+                ChangeRecord { id, changed_at, username, description, reason, dataset_version }
+            }
+        )?;
+
+        Ok(changes)
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let rows: Vec<(String, String, String)> = conn.query("SELECT username, password_hash, role FROM account ORDER BY username")?;
+
+        rows.into_iter().map(|(username, password_hash, role)| { account(username, password_hash, &role) }).collect()
+    }
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let row: Option<(String, String, String)> = conn.exec_first(
+            "SELECT username, password_hash, role FROM account WHERE username=?", (username,)
+        )?;
+
+        row.map(|(username, password_hash, role)| { account(username, password_hash, &role) }).transpose()
+    }
+
+    fn add_account(&self, account: &Account) -> Result<(), StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        conn.exec_drop("INSERT IGNORE INTO account (username, password_hash, role, created_at) VALUES (?, ?, ?, ?)",
+            (&account.username, &account.password_hash, account.role.name(), now()))?;
+        if conn.affected_rows() == 0 {
+            return Err(StoreError::InvalidData(format!("account '{}' already exists", account.username)));
+        }
+
+        Ok(())
+    }
+
+    fn remove_account(&self, username: &str) -> Result<bool, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        conn.exec_drop("DELETE FROM account WHERE username=?", (username,))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    fn set_role(&self, username: &str, role: Role) -> Result<bool, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        conn.exec_drop("UPDATE account SET role=? WHERE username=?", (role.name(), username))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    fn log_solve(&self, record: &SolveRecord) -> Result<AuditEntry, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        // Entries are appended one at a time, even by several servers, so the chain doesn't fork
+        Self::with_lock(&mut conn, "iv_compatibility_audit", 10, |conn| {
+            let mut tx = conn.start_transaction(TxOpts::default())?;
+            let entry = Self::append_audit(&mut tx, record)?;
+            tx.commit()?;
+            Ok(entry)
+        })
+    }
+
+    fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let rows: Vec<(u64, i64, String, String, String)> = conn.exec(
+            format!("SELECT {} FROM solve_audit
+            WHERE logged_at BETWEEN ? AND ?
+            AND (? IS NULL OR id IN (SELECT audit_id FROM solve_audit_infusion WHERE infusion_id=?))
+            ORDER BY id DESC LIMIT ?", AUDIT_COLUMNS),
+            (query.from.unwrap_or(i64::MIN), query.to.unwrap_or(i64::MAX), query.infusion_id, query.infusion_id, query.limit)
+        )?;
+
+        rows.into_iter()
+            .map(|(id, logged_at, record, prev_hash, hash)| { AuditEntry::from_row(id, logged_at, record, prev_hash, hash) })
+            .collect()
+    }
+
+    fn verify_audit_log(&self, anchor: Option<&AuditAnchor>) -> Result<AuditVerification, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        let mut verifier = ChainVerifier::new(anchor);
+        for row in conn.query_iter(format!("SELECT {} FROM solve_audit ORDER BY id", AUDIT_COLUMNS))? {
+            let (id, logged_at, record, prev_hash, hash): (u64, i64, String, String, String) = mysql::from_row(row?);
+            verifier.check(id, logged_at, &record, &prev_hash, &hash);
+        }
+
+        Ok(verifier.finish())
+    }
+
+    fn regimens(&self) -> Result<Vec<Regimen>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let rows: Vec<RegimenRow> = conn.query(format!("SELECT {} FROM regimen ORDER BY label", REGIMEN_COLUMNS))?;
+
+        rows.into_iter().map(regimen).collect()
+    }
+
+    fn regimen(&self, id: u32) -> Result<Option<Regimen>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        Self::find_regimen(&mut conn, id)
+    }
+
+    fn add_regimen(&self, label: &str, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>, devices: &[Device],
+                   username: &str) -> Result<Regimen, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let ivs_json = serde_json::to_string(ivs).expect("Failed to serialize regimen");
+        let updated_at = now();
+        conn.exec_drop("INSERT IGNORE INTO regimen (label, ivs, schedules, devices, revision, updated_at, updated_by)
+                        VALUES (?, ?, ?, ?, 1, ?, ?)",
+            (label, ivs_json, schedules_json(schedules), devices_json(devices), updated_at, username))?;
+        if conn.affected_rows() == 0 {
+            return Err(StoreError::InvalidData(format!("regimen '{}' already exists", label)));
+        }
+
+        Ok(Regimen {
+            id: conn.last_insert_id() as u32,
+            label: label.to_string(),
+            ivs: ivs.to_vec(),
+            schedules: schedules.clone(),
+            devices: devices.to_vec(),
+            revision: 1,
+            updated_at,
+            updated_by: username.to_string(),
+        })
+    }
+
+    fn update_regimen(&self, id: u32, revision: u32, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>,
+                      devices: &[Device], username: &str) -> Result<Option<Regimen>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        let Some(current) = Self::find_regimen(&mut conn, id)? else { return Ok(None) };
+
+        let ivs_json = serde_json::to_string(ivs).expect("Failed to serialize regimen");
+        conn.exec_drop("UPDATE regimen SET ivs=?, schedules=?, devices=?, revision=revision + 1, updated_at=?, updated_by=?
+                        WHERE id=? AND revision=?",
+            (ivs_json, schedules_json(schedules), devices_json(devices), now(), username, id, revision))?;
+        if conn.affected_rows() == 0 {
+            return Err(stale_regimen(&current.label));
+        }
+
+        Self::find_regimen(&mut conn, id)
+    }
+
+    fn remove_regimen(&self, id: u32) -> Result<bool, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+        conn.exec_drop("DELETE FROM regimen WHERE id=?", (id,))?;
+
+        Ok(conn.affected_rows() > 0)
+    }
+
+    fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        // Stop two programs migrating at once. MySQL commits DDL straight away, so a transaction can't be used.
+        Self::with_lock(&mut conn, "iv_compatibility_migrate", 60, Self::apply_migrations)
+    }
+
+    fn check_schema(&self) -> Result<(), StoreError> {
+        let mut conn = self.pool.get_conn()?;
+
+        migrations::check(Self::schema_version(&mut conn)?, migrations::MYSQL)
+    }
+}
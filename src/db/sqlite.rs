@@ -0,0 +1,565 @@
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use crate::access::Device;
+use crate::audit::{AuditAnchor, AuditEntry, AuditQuery, AuditVerification, ChainVerifier, SolveRecord, GENESIS_HASH};
+use crate::auth::{Account, Role};
+use crate::dataset::{CompatibilityCounts, CompatibilityDetails, Dataset, DatasetVersion, InfusionRecord};
+use crate::edit::{self, ChangeRecord, Edit};
+use crate::import::{ImportData, ImportMode, ImportPlan, ImportSource, ImportSummary};
+use crate::regimen::Regimen;
+use crate::schedule::Schedule;
+use super::{account, devices_json, infusion_type, migrations, now, read_snapshot, regimen, schedules_json};
+use super::{snapshot_hash, stale_regimen, CompatibilityStore, Migration, RegimenRow, StoreError};
+
+const VERSION_COLUMNS: &str = "id, name, created_at, source_hash, active";
+
+const AUDIT_COLUMNS: &str = "id, logged_at, record, prev_hash, hash";
+const REGIMEN_COLUMNS: &str = "id, label, ivs, schedules, devices, revision, updated_at, updated_by";
+
+/// Stores everything in a single SQLite file, for setups without a database server
+pub struct SqliteStore {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database file. The tables are created by `migrate`.
+    pub fn open(path: &str) -> Result<Self, StoreError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+
+        Ok(Self { conn: Mutex::new(conn) })
+    }
+
+    /// The newest migration applied, or 0 for a new database
+    fn schema_version(conn: &Connection) -> Result<u32, StoreError> {
+        let has_table: bool = conn.query_row(
+            "SELECT COUNT(*) > 0 FROM sqlite_master WHERE type='table' AND name='schema_migrations'", [], |row| { row.get(0) }
+        )?;
+        if !has_table {
+            return Ok(0);
+        }
+
+        Ok(conn.query_row("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", [], |row| { row.get(0) })?)
+    }
+
+    fn read_dataset(conn: &Connection) -> Result<Dataset, StoreError> {
+        let mut dataset = Dataset::new();
+
+        let mut stmt = conn.prepare("SELECT id, name, type, retired, central_only, dedicated_line FROM infusion")?;
+        let infusions = stmt.query_map([], |row| {
+            Ok((row.get::<_, u32>(0)?, row.get::<_, String>(1)?, row.get::<_, u32>(2)?, row.get::<_, bool>(3)?,
+                row.get::<_, bool>(4)?, row.get::<_, Option<String>>(5)?))
+        })?;
+        for infusion in infusions {
+            let (id, name, inf_type_id, retired, central_only, dedicated_line) = infusion?;
+            let infusion_type = infusion_type(inf_type_id)?;
+            dataset.add_infusion(InfusionRecord { id, name, infusion_type, retired, central_only, dedicated_line });
+        }
+
+        let mut stmt = conn.prepare(
+            "SELECT infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results,
+                concentration, diluent, reference
+            FROM infusion_compatibility"
+        )?;
+        let results = stmt.query_map([], |row| {
+            let counts = CompatibilityCounts {
+                compatible: row.get(2)?,
+                incompatible: row.get(3)?,
+                mixed: row.get(4)?,
+            };
+            let details = CompatibilityDetails {
+                concentration: row.get(5)?,
+                diluent: row.get(6)?,
+                reference: row.get(7)?,
+            };
+            Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?, counts, details))
+        })?;
+        for result in results {
+            let (id1, id2, counts, details) = result?;
+            dataset.add_compatibility(id1, id2, counts);
+            dataset.set_details(id1, id2, details);
+        }
+
+        let mut stmt = conn.prepare("SELECT infusion_a, infusion_b FROM flushable_pair")?;
+        let pairs = stmt.query_map([], |row| { Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)) })?;
+        for pair in pairs {
+            let (id1, id2) = pair?;
+            dataset.set_flushable(id1, id2, true);
+        }
+
+        let mut stmt = conn.prepare("SELECT infusion, partner FROM dedicated_line_partner")?;
+        let partners = stmt.query_map([], |row| { Ok((row.get::<_, u32>(0)?, row.get::<_, u32>(1)?)) })?;
+        for partner in partners {
+            let (id, partner) = partner?;
+            dataset.set_line_partner(id, partner, true);
+        }
+
+        Ok(dataset)
+    }
+
+    fn read_version(row: &Row) -> rusqlite::Result<DatasetVersion> {
+        Ok(DatasetVersion {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            created_at: row.get(2)?,
+            source_hash: row.get(3)?,
+            active: row.get(4)?,
+        })
+    }
+
+    fn active_version(conn: &Connection) -> Result<Option<DatasetVersion>, StoreError> {
+        let query = format!("SELECT {} FROM dataset_version WHERE active=1", VERSION_COLUMNS);
+
+        Ok(conn.query_row(&query, [], Self::read_version).optional()?)
+    }
+
+    /// Snapshot `dataset` as a new version and make it the active one
+    fn create_version(conn: &Connection, source: &ImportSource, dataset: &Dataset) -> Result<DatasetVersion, StoreError> {
+        let created_at = now();
+        conn.execute("UPDATE dataset_version SET active=0 WHERE active=1", [])?;
+        let snapshot = dataset.to_snapshot();
+        let hash = snapshot_hash(&snapshot);
+        conn.execute("INSERT OR IGNORE INTO dataset_snapshot (hash, snapshot) VALUES (?1, ?2)", params![hash, snapshot])?;
+        conn.execute("INSERT INTO dataset_version (name, created_at, source_hash, active, snapshot, snapshot_hash)
+            VALUES (?1, ?2, ?3, 1, '', ?4)",
+            params![source.name, created_at, source.source_hash, hash])?;
+
+        Ok(DatasetVersion {
+            id: conn.last_insert_rowid() as u32,
+            name: source.name.clone(),
+            created_at,
+            source_hash: source.source_hash.clone(),
+            active: true,
+        })
+    }
+
+    fn apply_plan(conn: &Connection, plan: &ImportPlan) -> Result<(), StoreError> {
+        // Reuse one prepared statement per kind of change, which is fast inside a transaction
+        let mut stmt = conn.prepare("DELETE FROM infusion_compatibility WHERE infusion_a=?1 AND infusion_b=?2")?;
+        for (id1, id2) in &plan.remove_compatibility {
+            stmt.execute(params![id1, id2])?;
+        }
+        let mut remove_stmt = conn.prepare("DELETE FROM flushable_pair WHERE infusion_a=?1 AND infusion_b=?2")?;
+        let mut add_stmt = conn.prepare("INSERT OR IGNORE INTO flushable_pair (infusion_a, infusion_b) VALUES (?1, ?2)")?;
+        for (id1, id2, flushable) in &plan.set_flushable {
+            let stmt = if *flushable { &mut add_stmt } else { &mut remove_stmt };
+            stmt.execute(params![id1, id2])?;
+        }
+        let mut remove_stmt = conn.prepare("DELETE FROM dedicated_line_partner WHERE infusion=?1 AND partner=?2")?;
+        let mut add_stmt = conn.prepare("INSERT OR IGNORE INTO dedicated_line_partner (infusion, partner) VALUES (?1, ?2)")?;
+        for (id, partner, allowed) in &plan.set_line_partners {
+            let stmt = if *allowed { &mut add_stmt } else { &mut remove_stmt };
+            stmt.execute(params![id, partner])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion SET type=?1 WHERE id=?2")?;
+        for (id, infusion_type) in &plan.update_infusions {
+            stmt.execute(params![infusion_type.id(), id])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion SET name=?1 WHERE id=?2")?;
+        for (id, name) in &plan.rename_infusions {
+            stmt.execute(params![name, id])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion SET retired=?1 WHERE id=?2")?;
+        for (id, retired) in &plan.retire_infusions {
+            stmt.execute(params![retired, id])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion SET central_only=?1 WHERE id=?2")?;
+        for (id, central_only) in &plan.central_only_infusions {
+            stmt.execute(params![central_only, id])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion SET dedicated_line=?1 WHERE id=?2")?;
+        for (id, dedicated_line) in &plan.dedicated_line_infusions {
+            stmt.execute(params![dedicated_line, id])?;
+        }
+        let mut stmt = conn.prepare("INSERT INTO infusion (name, type) VALUES (?1, ?2)")?;
+        for infusion in &plan.add_infusions {
+            stmt.execute(params![infusion.name, infusion.infusion_type.id()])?;
+        }
+
+        let name_id_map: HashMap<String, u32> = conn
+            .prepare("SELECT name, id FROM infusion")?
+            .query_map([], |row| { Ok((row.get(0)?, row.get(1)?)) })?
+            .collect::<Result<_, _>>()?;
+        let mut stmt = conn.prepare("INSERT INTO infusion_compatibility
+            (infusion_a, infusion_b, compatible_results, incompatible_results, mixed_results, concentration, diluent, reference)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)")?;
+        for compat in &plan.add_compatibility {
+            let id = name_id_map[&compat.infusion_a];
+            let other_id = name_id_map[&compat.infusion_b];
+            let (id1, id2) = if id < other_id { (id, other_id) } else { (other_id, id) };
+            let counts = compat.counts;
+            let details = compat.details.clone().unwrap_or_default();
+
+            stmt.execute(params![id1, id2, counts.compatible, counts.incompatible, counts.mixed,
+                                 details.concentration, details.diluent, details.reference])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion_compatibility
+            SET compatible_results=?1, incompatible_results=?2, mixed_results=?3
+            WHERE infusion_a=?4 AND infusion_b=?5")?;
+        for (id1, id2, counts) in &plan.update_compatibility {
+            stmt.execute(params![counts.compatible, counts.incompatible, counts.mixed, id1, id2])?;
+        }
+        let mut stmt = conn.prepare("UPDATE infusion_compatibility
+            SET concentration=?1, diluent=?2, reference=?3
+            WHERE infusion_a=?4 AND infusion_b=?5")?;
+        for (id1, id2, details) in &plan.set_details {
+            stmt.execute(params![details.concentration, details.diluent, details.reference, id1, id2])?;
+        }
+
+        Ok(())
+    }
+
+    /// Apply `plan` and snapshot the result as a new active version
+    fn apply_and_snapshot(conn: &Connection, plan: &ImportPlan, source: &ImportSource) -> Result<DatasetVersion, StoreError> {
+        Self::apply_plan(conn, plan)?;
+        let dataset = Self::read_dataset(conn)?;
+
+        Self::create_version(conn, source, &dataset)
+    }
+
+    fn read_change(row: &Row) -> rusqlite::Result<ChangeRecord> {
+        Ok(ChangeRecord {
+            id: row.get(0)?,
+            changed_at: row.get(1)?,
+            username: row.get(2)?,
+            description: row.get(3)?,
+            reason: row.get(4)?,
+            dataset_version: row.get(5)?,
+        })
+    }
+
+    fn read_account(row: &Row) -> rusqlite::Result<(String, String, String)> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+    }
+
+    fn read_audit_row(row: &Row) -> rusqlite::Result<(u64, i64, String, String, String)> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+    }
+
+    fn read_regimen(row: &Row) -> rusqlite::Result<RegimenRow> {
+        Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?, row.get(6)?, row.get(7)?))
+    }
+
+    fn find_regimen(conn: &Connection, id: u32) -> Result<Option<Regimen>, StoreError> {
+        let row = conn
+            .query_row(&format!("SELECT {} FROM regimen WHERE id=?1", REGIMEN_COLUMNS), params![id], Self::read_regimen)
+            .optional()?;
+
+        row.map(regimen).transpose()
+    }
+}
+
+impl CompatibilityStore for SqliteStore {
+    fn load_dataset(&self) -> Result<Dataset, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut dataset = Self::read_dataset(&conn)?;
+        dataset.set_version(Self::active_version(&conn)?);
+
+        Ok(dataset)
+    }
+
+    fn import(&self, data: &ImportData, mode: ImportMode, source: &ImportSource) -> Result<ImportSummary, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        // Everything happens in one transaction, which is rolled back if it's dropped without committing
+        let tx = conn.transaction()?;
+        let current = Self::read_dataset(&tx)?;
+        let mut plan = ImportPlan::new(&current, data, mode)?;
+        plan.summary.version = Some(Self::apply_and_snapshot(&tx, &plan, source)?);
+        tx.commit()?;
+
+        Ok(plan.summary)
+    }
+
+    fn list_versions(&self) -> Result<Vec<DatasetVersion>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM dataset_version ORDER BY id", VERSION_COLUMNS))?;
+        let versions = stmt.query_map([], Self::read_version)?.collect::<Result<_, _>>()?;
+
+        Ok(versions)
+    }
+
+    fn load_version(&self, id: u32) -> Result<Option<Dataset>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let query = format!("SELECT {}, COALESCE(s.snapshot, v.snapshot) FROM dataset_version v
+            LEFT JOIN dataset_snapshot s ON s.hash = v.snapshot_hash WHERE id=?1", VERSION_COLUMNS);
+        let row = conn
+            .query_row(&query, params![id], |row| { Ok((Self::read_version(row)?, row.get::<_, String>(5)?)) })
+            .optional()?;
+
+        row.map(|(version, snapshot)| { read_snapshot(&snapshot, version) }).transpose()
+    }
+
+    fn edit(&self, edit: &Edit, username: &str, reason: &str) -> Result<ChangeRecord, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+
+        let tx = conn.transaction()?;
+        let current = Self::read_dataset(&tx)?;
+        let (plan, description) = edit.plan(&current)?;
+        let source = edit::version_source(username, &description, reason);
+        let version = Self::apply_and_snapshot(&tx, &plan, &source)?;
+
+        let changed_at = now();
+        tx.execute("INSERT INTO change_log (changed_at, username, description, reason, dataset_version)
+            VALUES (?1, ?2, ?3, ?4, ?5)", params![changed_at, username, description, reason, version.id])?;
+        let record = ChangeRecord {
+            id: tx.last_insert_rowid() as u32,
+            changed_at,
+            username: username.to_string(),
+            description,
+            reason: reason.to_string(),
+            dataset_version: version.id,
+        };
+        tx.commit()?;
+
+        Ok(record)
+    }
+
+    fn list_changes(&self, limit: u32) -> Result<Vec<ChangeRecord>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT id, changed_at, username, description, reason, dataset_version
+            FROM change_log ORDER BY id DESC LIMIT ?1")?;
+        let changes = stmt.query_map(params![limit], Self::read_change)?.collect::<Result<_, _>>()?;
+
+        Ok(changes)
+    }
+
+    fn accounts(&self) -> Result<Vec<Account>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT username, password_hash, role FROM account ORDER BY username")?;
+        let rows = stmt.query_map([], Self::read_account)?.collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter().map(|(username, password_hash, role)| { account(username, password_hash, &role) }).collect()
+    }
+
+    fn account(&self, username: &str) -> Result<Option<Account>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let row = conn
+            .query_row("SELECT username, password_hash, role FROM account WHERE username=?1", params![username], Self::read_account)
+            .optional()?;
+
+        row.map(|(username, password_hash, role)| { account(username, password_hash, &role) }).transpose()
+    }
+
+    fn add_account(&self, account: &Account) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let added = conn.execute("INSERT OR IGNORE INTO account (username, password_hash, role, created_at) VALUES (?1, ?2, ?3, ?4)",
+            params![account.username, account.password_hash, account.role.name(), now()])?;
+        if added == 0 {
+            return Err(StoreError::InvalidData(format!("account '{}' already exists", account.username)));
+        }
+
+        Ok(())
+    }
+
+    fn remove_account(&self, username: &str) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+
+        Ok(conn.execute("DELETE FROM account WHERE username=?1", params![username])? > 0)
+    }
+
+    fn set_role(&self, username: &str, role: Role) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+
+        Ok(conn.execute("UPDATE account SET role=?1 WHERE username=?2", params![role.name(), username])? > 0)
+    }
+
+    fn log_solve(&self, record: &SolveRecord) -> Result<AuditEntry, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+
+        let last: Option<(u64, String)> = tx
+            .query_row("SELECT id, hash FROM solve_audit ORDER BY id DESC LIMIT 1", [], |row| { Ok((row.get(0)?, row.get(1)?)) })
+            .optional()?;
+        let (prev_id, prev_hash) = last.unwrap_or((0, GENESIS_HASH.to_string()));
+        let entry = AuditEntry::next(prev_id, &prev_hash, now(), record);
+
+        tx.execute(&format!("INSERT INTO solve_audit ({}) VALUES (?1, ?2, ?3, ?4, ?5)", AUDIT_COLUMNS),
+            params![entry.id, entry.logged_at, entry.record_json(), entry.prev_hash, entry.hash])?;
+        for infusion_id in record.infusion_ids() {
+            tx.execute("INSERT INTO solve_audit_infusion (infusion_id, audit_id) VALUES (?1, ?2)", params![infusion_id, entry.id])?;
+        }
+        tx.commit()?;
+
+        Ok(entry)
+    }
+
+    fn query_audit(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {} FROM solve_audit
+            WHERE logged_at BETWEEN ?1 AND ?2
+            AND (?3 IS NULL OR id IN (SELECT audit_id FROM solve_audit_infusion WHERE infusion_id=?3))
+            ORDER BY id DESC LIMIT ?4", AUDIT_COLUMNS
+        ))?;
+        let params = params![query.from.unwrap_or(i64::MIN), query.to.unwrap_or(i64::MAX), query.infusion_id, query.limit];
+        let rows = stmt.query_map(params, Self::read_audit_row)?.collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter()
+            .map(|(id, logged_at, record, prev_hash, hash)| { AuditEntry::from_row(id, logged_at, record, prev_hash, hash) })
+            .collect()
+    }
+
+    fn verify_audit_log(&self, anchor: Option<&AuditAnchor>) -> Result<AuditVerification, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM solve_audit ORDER BY id", AUDIT_COLUMNS))?;
+
+        let mut verifier = ChainVerifier::new(anchor);
+        for row in stmt.query_map([], Self::read_audit_row)? {
+            let (id, logged_at, record, prev_hash, hash) = row?;
+            verifier.check(id, logged_at, &record, &prev_hash, &hash);
+        }
+
+        Ok(verifier.finish())
+    }
+
+    fn regimens(&self) -> Result<Vec<Regimen>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(&format!("SELECT {} FROM regimen ORDER BY label", REGIMEN_COLUMNS))?;
+        let rows = stmt.query_map([], Self::read_regimen)?.collect::<Result<Vec<_>, _>>()?;
+
+        rows.into_iter().map(regimen).collect()
+    }
+
+    fn regimen(&self, id: u32) -> Result<Option<Regimen>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+
+        Self::find_regimen(&conn, id)
+    }
+
+    fn add_regimen(&self, label: &str, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>, devices: &[Device],
+                   username: &str) -> Result<Regimen, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let ivs_json = serde_json::to_string(ivs).expect("Failed to serialize regimen");
+        let updated_at = now();
+        let added = conn.execute(
+            "INSERT OR IGNORE INTO regimen (label, ivs, schedules, devices, revision, updated_at, updated_by)
+             VALUES (?1, ?2, ?3, ?4, 1, ?5, ?6)",
+            params![label, ivs_json, schedules_json(schedules), devices_json(devices), updated_at, username],
+        )?;
+        if added == 0 {
+            return Err(StoreError::InvalidData(format!("regimen '{}' already exists", label)));
+        }
+
+        Ok(Regimen {
+            id: conn.last_insert_rowid() as u32,
+            label: label.to_string(),
+            ivs: ivs.to_vec(),
+            schedules: schedules.clone(),
+            devices: devices.to_vec(),
+            revision: 1,
+            updated_at,
+            updated_by: username.to_string(),
+        })
+    }
+
+    fn update_regimen(&self, id: u32, revision: u32, ivs: &[Vec<u32>], schedules: &BTreeMap<u32, Schedule>,
+                      devices: &[Device], username: &str) -> Result<Option<Regimen>, StoreError> {
+        let conn = self.conn.lock().unwrap();
+        let Some(current) = Self::find_regimen(&conn, id)? else { return Ok(None) };
+
+        let ivs_json = serde_json::to_string(ivs).expect("Failed to serialize regimen");
+        let updated = conn.execute(
+            "UPDATE regimen SET ivs=?1, schedules=?2, devices=?3, revision=revision + 1, updated_at=?4, updated_by=?5
+             WHERE id=?6 AND revision=?7",
+            params![ivs_json, schedules_json(schedules), devices_json(devices), now(), username, id, revision],
+        )?;
+        if updated == 0 {
+            return Err(stale_regimen(&current.label));
+        }
+
+        Self::find_regimen(&conn, id)
+    }
+
+    fn remove_regimen(&self, id: u32) -> Result<bool, StoreError> {
+        let conn = self.conn.lock().unwrap();
+
+        Ok(conn.execute("DELETE FROM regimen WHERE id=?1", params![id])? > 0)
+    }
+
+    fn migrate(&self) -> Result<Vec<&'static Migration>, StoreError> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.execute_batch("CREATE TABLE IF NOT EXISTS schema_migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        );")?;
+
+        let pending = migrations::pending(Self::schema_version(&conn)?, migrations::SQLITE)?;
+        for migration in &pending {
+            // Each migration is applied completely or not at all
+            let tx = conn.transaction()?;
+            tx.execute_batch(migration.sql)?;
+            tx.execute("INSERT INTO schema_migrations (version, name, applied_at) VALUES (?1, ?2, ?3)",
+                params![migration.version, migration.name, now()])?;
+            tx.commit()?;
+        }
+
+        Ok(pending)
+    }
+
+    fn check_schema(&self) -> Result<(), StoreError> {
+        let conn = self.conn.lock().unwrap();
+
+        migrations::check(Self::schema_version(&conn)?, migrations::SQLITE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::import::parse;
+
+    #[test]
+    fn versions_with_the_same_data_share_a_snapshot() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.migrate().unwrap();
+        let contents = ",,Heparin,Propofol\nHeparin,1,,0:1:0\nPropofol,1,0:1:0,\n";
+        let data = parse::parse_matrix(contents, b',').data;
+        for _ in 0..2 {
+            store.import(&data, ImportMode::Replace, &ImportSource::new("test", contents.as_bytes())).unwrap();
+        }
+
+        let conn = store.conn.lock().unwrap();
+        let count = |table: &str| -> u32 { conn.query_row(&format!("SELECT COUNT(*) FROM {}", table), [], |row| { row.get(0) }).unwrap() };
+        assert_eq!((count("dataset_version"), count("dataset_snapshot")), (2, 1));
+        drop(conn);
+
+        let first = store.load_version(1).unwrap().unwrap();
+        assert_eq!(first.to_snapshot(), store.load_dataset().unwrap().to_snapshot());
+        assert!(first.find_by_name("Propofol").is_some());
+    }
+
+    #[test]
+    fn compatibility_details_are_stored_and_kept() {
+        let store = SqliteStore::open(":memory:").unwrap();
+        store.migrate().unwrap();
+        let import = |contents: &str, parsed: parse::ParseResult| {
+            store.import(&parsed.data, ImportMode::Replace, &ImportSource::new("test", contents.as_bytes())).unwrap()
+        };
+        let details = |store: &SqliteStore| {
+            let dataset = store.load_dataset().unwrap();
+            let id = |name| { dataset.find_by_name(name).unwrap().id };
+            dataset.details(id("Heparin"), id("Propofol")).cloned()
+        };
+
+        let long = "drug_a,type_a,drug_b,type_b,compatible,incompatible,mixed,diluent,reference\n\
+                    Heparin,1,Propofol,1,0,1,0,D5W,Trissel 2023\n";
+        import(long, parse::parse_long(long, b','));
+        let stored = details(&store).unwrap();
+        assert_eq!((stored.concentration, stored.diluent.as_deref(), stored.reference.as_deref()), (None, Some("D5W"), Some("Trissel 2023")));
+
+        // The matrix format has nowhere to put them, so they're left alone
+        let matrix = ",,Heparin,Propofol\nHeparin,1,,0:1:0\nPropofol,1,0:1:0,\n";
+        let summary = import(matrix, parse::parse_matrix(matrix, b','));
+        assert_eq!(summary.compatibility.unchanged, 1);
+        assert!(details(&store).is_some());
+        assert!(store.load_version(1).unwrap().unwrap().details(1, 2).is_some());
+
+        let cleared = long.replace("D5W,Trissel 2023", ",");
+        let summary = import(&cleared, parse::parse_long(&cleared, b','));
+        assert_eq!(summary.compatibility.updated, 1);
+        assert!(details(&store).is_none());
+    }
+}
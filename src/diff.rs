@@ -0,0 +1,184 @@
+use crate::dataset::{CompatibilityCounts, Dataset};
+use crate::import::ImportData;
+use crate::infusion::{Compatibility, CompatibilityData, InfusionType};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Infusion and compatibility data keyed by name, so data from
+/// the database and from files can be compared
+#[derive(Debug, Default)]
+pub struct NamedData {
+    infusions: BTreeMap<String, InfusionType>,
+    pairs: BTreeMap<(String, String), CompatibilityCounts>,   // (lower name, higher name) -> counts
+}
+
+impl From<&Dataset> for NamedData {
+    fn from(dataset: &Dataset) -> Self {
+        let mut data = Self::default();
+        for infusion in dataset.infusions() {
+            data.infusions.insert(infusion.name.clone(), infusion.infusion_type);
+        }
+        for (id1, id2) in dataset.compatibility_pairs() {
+            let name1 = &dataset.infusion(id1).unwrap().name;
+            let name2 = &dataset.infusion(id2).unwrap().name;
+            data.pairs.insert(pair_key(name1, name2), *dataset.compatibility(id1, id2).unwrap());
+        }
+
+        data
+    }
+}
+
+impl From<&ImportData> for NamedData {
+    /// If a pair appears more than once the first entry wins, as it does when importing
+    fn from(import: &ImportData) -> Self {
+        let mut data = Self::default();
+        for infusion in &import.infusions {
+            data.infusions.entry(infusion.name.clone()).or_insert(infusion.infusion_type);
+        }
+        for compat in &import.compatibility {
+            data.pairs.entry(pair_key(&compat.infusion_a, &compat.infusion_b)).or_insert(compat.counts);
+        }
+
+        data
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TypeChange {
+    pub infusion: String,
+    pub old: InfusionType,
+    pub new: InfusionType,
+}
+
+/// A pair whose counts differ. Missing counts mean there's no data for the pair.
+#[derive(Debug, Serialize)]
+pub struct PairChange {
+    pub infusion_a: String,
+    pub infusion_b: String,
+    pub old: Option<CompatibilityCounts>,
+    pub new: Option<CompatibilityCounts>,
+    pub old_verdict: Compatibility,
+    pub new_verdict: Compatibility,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DatasetDiff {
+    pub added_infusions: Vec<String>,
+    pub removed_infusions: Vec<String>,
+    pub type_changes: Vec<TypeChange>,
+    /// Pairs where the solver's verdict changes, e.g. compatible becoming incompatible.
+    /// Only pairs of infusions that are in both datasets are compared.
+    pub verdict_changes: Vec<PairChange>,
+    /// Pairs where only the counts change
+    pub count_changes: Vec<PairChange>,
+}
+
+impl DatasetDiff {
+    pub fn new(old: &NamedData, new: &NamedData) -> Self {
+        let added_infusions: Vec<String> = new.infusions.keys().filter(|n| { !old.infusions.contains_key(*n) }).cloned().collect();
+        let removed_infusions: Vec<String> = old.infusions.keys().filter(|n| { !new.infusions.contains_key(*n) }).cloned().collect();
+        let type_changes = old.infusions
+            .iter()
+            .filter_map(|(name, old_type)| {
+                new.infusions.get(name)
+                    .filter(|new_type| { *new_type != old_type })
+                    .map(|new_type| { TypeChange { infusion: name.clone(), old: *old_type, new: *new_type } })
+            })
+            .collect();
+
+        let mut verdict_changes = Vec::new();
+        let mut count_changes = Vec::new();
+        let all_pairs: BTreeSet<_> = old.pairs.keys().chain(new.pairs.keys()).collect();
+        for pair in all_pairs {
+            // Pairs with an added or removed infusion are covered by the infusion lists
+            let in_both = |name: &String| { old.infusions.contains_key(name) && new.infusions.contains_key(name) };
+            if !in_both(&pair.0) || !in_both(&pair.1) {
+                continue;
+            }
+
+            let old_counts = old.pairs.get(pair).copied();
+            let new_counts = new.pairs.get(pair).copied();
+            if old_counts == new_counts {
+                continue;
+            }
+
+            let change = PairChange {
+                infusion_a: pair.0.clone(),
+                infusion_b: pair.1.clone(),
+                old: old_counts,
+                new: new_counts,
+                old_verdict: verdict(old_counts),
+                new_verdict: verdict(new_counts),
+            };
+            if change.old_verdict != change.new_verdict {
+                verdict_changes.push(change);
+            } else {
+                count_changes.push(change);
+            }
+        }
+
+        Self { added_infusions, removed_infusions, type_changes, verdict_changes, count_changes }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added_infusions.is_empty() && self.removed_infusions.is_empty() && self.type_changes.is_empty()
+            && self.verdict_changes.is_empty() && self.count_changes.is_empty()
+    }
+}
+
+impl fmt::Display for DatasetDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No differences");
+        }
+
+        writeln!(f, "Verdict changes ({}):", self.verdict_changes.len())?;
+        for change in &self.verdict_changes {
+            writeln!(f, "  {}", change)?;
+        }
+        writeln!(f, "Infusions added ({}):", self.added_infusions.len())?;
+        for name in &self.added_infusions {
+            writeln!(f, "  {}", name)?;
+        }
+        writeln!(f, "Infusions removed ({}):", self.removed_infusions.len())?;
+        for name in &self.removed_infusions {
+            writeln!(f, "  {}", name)?;
+        }
+        writeln!(f, "Type changes ({}):", self.type_changes.len())?;
+        for change in &self.type_changes {
+            writeln!(f, "  {}: {:?} → {:?}", change.infusion, change.old, change.new)?;
+        }
+        writeln!(f, "Count changes with the same verdict ({}):", self.count_changes.len())?;
+        for change in &self.count_changes {
+            writeln!(f, "  {}", change)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for PairChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let describe = |counts: Option<CompatibilityCounts>, verdict: Compatibility| {
+            match counts {
+                Some(c) => format!("{:?} ({}:{}:{})", verdict, c.compatible, c.incompatible, c.mixed),
+                None => format!("{:?} (no data)", verdict),
+            }
+        };
+        write!(f, "{} + {}: {} → {}", self.infusion_a, self.infusion_b,
+            describe(self.old, self.old_verdict), describe(self.new, self.new_verdict))
+    }
+}
+
+/// The verdict the solver would reach. Pairs without data are treated as incompatible.
+fn verdict(counts: Option<CompatibilityCounts>) -> Compatibility {
+    match counts {
+        Some(c) => *CompatibilityData::new(c.compatible, c.incompatible, c.mixed).compatibility(),
+        None => Compatibility::Incompatible,
+    }
+}
+
+fn pair_key(name1: &str, name2: &str) -> (String, String) {
+    if name1 < name2 { (name1.to_string(), name2.to_string()) } else { (name2.to_string(), name1.to_string()) }
+}
@@ -0,0 +1,312 @@
+use crate::dataset::{CompatibilityCounts, Dataset};
+use crate::db::StoreError;
+use crate::import::{ImportPlan, ImportSource, NewCompatibility, NewInfusion, MAX_COUNT};
+use crate::infusion::InfusionType;
+use serde::Serialize;
+
+/// Longest reason an infusion can be given for needing a dedicated line
+pub const MAX_DEDICATED_REASON_LEN: usize = 255;
+
+/// A single change made from the admin pages
+#[derive(Debug, Clone)]
+pub enum Edit {
+    AddInfusion { name: String, infusion_type: InfusionType },
+    RenameInfusion { id: u32, name: String },
+    /// Retire an infusion, or bring a retired one back
+    RetireInfusion { id: u32, retired: bool },
+    /// Require central access for an infusion, or stop requiring it
+    SetCentralOnly { id: u32, central_only: bool },
+    /// Give an infusion a line of its own for `reason`, or let it share lines again with `None`
+    SetDedicatedLine { id: u32, reason: Option<String> },
+    /// Allow or stop allowing `partner` on the dedicated line of infusion `id`
+    SetLinePartner { id: u32, partner: u32, allowed: bool },
+    /// Add or replace the compatibility record for a pair
+    SetCompatibility { id1: u32, id2: u32, counts: CompatibilityCounts },
+    RemoveCompatibility { id1: u32, id2: u32 },
+    /// Allow or stop allowing a pair to take turns on one IV, with a flush in between
+    SetFlushable { id1: u32, id2: u32, flushable: bool },
+}
+
+/// Who changed what and why, as stored in `change_log`
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeRecord {
+    pub id: u32,
+    pub changed_at: i64,    // Unix timestamp
+    pub username: String,
+    pub description: String,
+    pub reason: String,
+    /// The dataset version created by the change
+    pub dataset_version: u32,
+}
+
+impl Edit {
+    /// Check that the edit makes sense for `current`, and work out the changes
+    /// it makes along with a description of them for the change log
+    pub fn plan(&self, current: &Dataset) -> Result<(ImportPlan, String), StoreError> {
+        let mut plan = ImportPlan::default();
+        let infusion_name = |id: u32| {
+            current
+                .infusion(id)
+                .map(|infusion| { infusion.name.clone() })
+                .ok_or_else(|| { StoreError::InvalidData(format!("infusion {} doesn't exist", id)) })
+        };
+
+        let description = match self {
+            Edit::AddInfusion { name, infusion_type } => {
+                let name = check_name(current, name)?;
+                plan.add_infusions.push(NewInfusion { name: name.to_string(), infusion_type: *infusion_type });
+                plan.summary.infusions.added += 1;
+
+                format!("Added {:?} '{}'", infusion_type, name)
+            },
+            Edit::RenameInfusion { id, name } => {
+                let old_name = infusion_name(*id)?;
+                let name = check_name(current, name)?;
+                plan.rename_infusions.push((*id, name.to_string()));
+                plan.summary.infusions.updated += 1;
+
+                format!("Renamed '{}' to '{}'", old_name, name)
+            },
+            Edit::RetireInfusion { id, retired } => {
+                let name = infusion_name(*id)?;
+                plan.retire_infusions.push((*id, *retired));
+                plan.summary.infusions.updated += 1;
+
+                if *retired { format!("Retired '{}'", name) } else { format!("Restored '{}'", name) }
+            },
+            Edit::SetCentralOnly { id, central_only } => {
+                let name = infusion_name(*id)?;
+                plan.central_only_infusions.push((*id, *central_only));
+                plan.summary.infusions.updated += 1;
+
+                if *central_only {
+                    format!("Made '{}' central access only", name)
+                } else {
+                    format!("Allowed '{}' through peripheral access", name)
+                }
+            },
+            Edit::SetDedicatedLine { id, reason } => {
+                let name = infusion_name(*id)?;
+                let current_reason = current.infusion(*id).unwrap().dedicated_line.as_deref();
+                let reason = reason.as_deref().map(check_dedicated_reason).transpose()?;
+                if reason == current_reason {
+                    return Err(StoreError::InvalidData(format!("nothing to change for '{}'", name)));
+                }
+                plan.dedicated_line_infusions.push((*id, reason.map(str::to_string)));
+                plan.summary.infusions.updated += 1;
+
+                match (reason, current_reason) {
+                    (Some(reason), None) => format!("Gave '{}' a dedicated line: {}", name, reason),
+                    (Some(reason), Some(_)) => format!("Changed why '{}' needs a dedicated line to: {}", name, reason),
+                    (None, _) => {
+                        // Partners only mean something while there's a dedicated line to share
+                        for (_, partner) in current.line_partners().filter(|(line_id, _)| { line_id == id }) {
+                            plan.set_line_partners.push((*id, partner, false));
+                        }
+                        format!("Let '{}' share lines again", name)
+                    },
+                }
+            },
+            Edit::SetLinePartner { id, partner, allowed } => {
+                let (name, partner_name) = (infusion_name(*id)?, infusion_name(*partner)?);
+                if id == partner {
+                    return Err(StoreError::InvalidData("an infusion can't be paired with itself".to_string()));
+                }
+                if current.infusion(*id).unwrap().dedicated_line.is_none() {
+                    return Err(StoreError::InvalidData(format!("'{}' doesn't have a dedicated line", name)));
+                }
+                if current.is_line_partner(*id, *partner) == *allowed {
+                    return Err(StoreError::InvalidData(format!("nothing to change for '{}' and '{}'", name, partner_name)));
+                }
+                plan.set_line_partners.push((*id, *partner, *allowed));
+
+                if *allowed {
+                    format!("Allowed '{}' on the dedicated line of '{}'", partner_name, name)
+                } else {
+                    format!("Stopped allowing '{}' on the dedicated line of '{}'", partner_name, name)
+                }
+            },
+            Edit::SetCompatibility { id1, id2, counts } => {
+                let (name1, name2) = (infusion_name(*id1)?, infusion_name(*id2)?);
+                if id1 == id2 {
+                    return Err(StoreError::InvalidData("an infusion can't be paired with itself".to_string()));
+                }
+                if [counts.compatible, counts.incompatible, counts.mixed].iter().any(|count| { *count > MAX_COUNT }) {
+                    return Err(StoreError::InvalidData(format!("counts can't be more than {}", MAX_COUNT)));
+                }
+
+                let (low, high) = if id1 < id2 { (*id1, *id2) } else { (*id2, *id1) };
+                let change = match current.compatibility(low, high) {
+                    Some(old) => {
+                        plan.update_compatibility.push((low, high, *counts));
+                        plan.summary.compatibility.updated += 1;
+                        format!("from {} to", format_counts(old))
+                    },
+                    None => {
+                        let compat = NewCompatibility { infusion_a: name1.clone(), infusion_b: name2.clone(), counts: *counts, details: None };
+                        plan.add_compatibility.push(compat);
+                        plan.summary.compatibility.added += 1;
+                        "to".to_string()
+                    },
+                };
+
+                format!("Set compatibility of '{}' and '{}' {} {}", name1, name2, change, format_counts(counts))
+            },
+            Edit::RemoveCompatibility { id1, id2 } => {
+                let (name1, name2) = (infusion_name(*id1)?, infusion_name(*id2)?);
+                let old = current.compatibility(*id1, *id2).ok_or_else(|| {
+                    StoreError::InvalidData(format!("there's no compatibility record for '{}' and '{}'", name1, name2))
+                })?;
+
+                let (low, high) = if id1 < id2 { (*id1, *id2) } else { (*id2, *id1) };
+                plan.remove_compatibility.push((low, high));
+                plan.summary.compatibility.removed += 1;
+
+                format!("Removed compatibility of '{}' and '{}' (was {})", name1, name2, format_counts(old))
+            },
+            Edit::SetFlushable { id1, id2, flushable } => {
+                let (name1, name2) = (infusion_name(*id1)?, infusion_name(*id2)?);
+                if id1 == id2 {
+                    return Err(StoreError::InvalidData("an infusion can't be paired with itself".to_string()));
+                }
+                if current.is_flushable(*id1, *id2) == *flushable {
+                    return Err(StoreError::InvalidData(format!("nothing to change for '{}' and '{}'", name1, name2)));
+                }
+
+                let (low, high) = if id1 < id2 { (*id1, *id2) } else { (*id2, *id1) };
+                plan.set_flushable.push((low, high, *flushable));
+
+                if *flushable {
+                    format!("Allowed flushing between '{}' and '{}'", name1, name2)
+                } else {
+                    format!("Stopped allowing flushing between '{}' and '{}'", name1, name2)
+                }
+            },
+        };
+
+        Ok((plan, description))
+    }
+}
+
+/// Each edit creates a dataset version, named after the person who made it
+pub(crate) fn version_source(username: &str, description: &str, reason: &str) -> ImportSource {
+    ImportSource::new(&format!("Edit by {}", username), format!("{}\n{}", description, reason).as_bytes())
+}
+
+fn check_name<'a>(current: &Dataset, name: &'a str) -> Result<&'a str, StoreError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(StoreError::InvalidData("the name can't be empty".to_string()));
+    }
+    if current.find_by_name(name).is_some() {
+        return Err(StoreError::InvalidData(format!("an infusion named '{}' already exists", name)));
+    }
+
+    Ok(name)
+}
+
+fn check_dedicated_reason(reason: &str) -> Result<&str, StoreError> {
+    let reason = reason.trim();
+    if reason.is_empty() {
+        return Err(StoreError::InvalidData("say why the infusion needs a dedicated line".to_string()));
+    }
+    if reason.chars().count() > MAX_DEDICATED_REASON_LEN {
+        return Err(StoreError::InvalidData(format!("the reason can't be longer than {} characters", MAX_DEDICATED_REASON_LEN)));
+    }
+
+    Ok(reason)
+}
+
+/// Counts in the compatible:incompatible:mixed form used by import files
+fn format_counts(counts: &CompatibilityCounts) -> String {
+    format!("{}:{}:{}", counts.compatible, counts.incompatible, counts.mixed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dataset::InfusionRecord;
+
+    /// Heparin (1) and Propofol (2), where Propofol has a dedicated line Heparin may share
+    fn dataset() -> Dataset {
+        let mut dataset = Dataset::new();
+        for (id, name) in [(1, "Heparin"), (2, "Propofol")] {
+            dataset.add_infusion(InfusionRecord {
+                id,
+                name: name.to_string(),
+                infusion_type: InfusionType::Drug,
+                retired: false,
+                central_only: false,
+                dedicated_line: (id == 2).then(|| { "lipid emulsion".to_string() }),
+            });
+        }
+        dataset.set_line_partner(2, 1, true);
+
+        dataset
+    }
+
+    fn error(edit: Edit) -> String {
+        match edit.plan(&dataset()) {
+            Err(StoreError::InvalidData(reason)) => reason,
+            other => panic!("expected invalid data, got {:?}", other.map(|(_, description)| { description })),
+        }
+    }
+
+    #[test]
+    fn dedicated_line_reasons() {
+        let (plan, description) = Edit::SetDedicatedLine { id: 1, reason: Some(" heparin lock ".to_string()) }.plan(&dataset()).unwrap();
+        assert_eq!(plan.dedicated_line_infusions, [(1, Some("heparin lock".to_string()))]);
+        assert_eq!(description, "Gave 'Heparin' a dedicated line: heparin lock");
+
+        assert_eq!(error(Edit::SetDedicatedLine { id: 1, reason: Some(" ".to_string()) }), "say why the infusion needs a dedicated line");
+        assert_eq!(error(Edit::SetDedicatedLine { id: 2, reason: Some("lipid emulsion".to_string()) }), "nothing to change for 'Propofol'");
+        assert!(error(Edit::SetDedicatedLine { id: 1, reason: Some("x".repeat(MAX_DEDICATED_REASON_LEN + 1)) }).contains("longer"));
+    }
+
+    #[test]
+    fn sharing_lines_again_drops_partners() {
+        let (plan, description) = Edit::SetDedicatedLine { id: 2, reason: None }.plan(&dataset()).unwrap();
+        assert_eq!(plan.dedicated_line_infusions, [(2, None)]);
+        assert_eq!(plan.set_line_partners, [(2, 1, false)]);
+        assert_eq!(description, "Let 'Propofol' share lines again");
+    }
+
+    #[test]
+    fn line_partners_need_a_dedicated_line() {
+        assert_eq!(error(Edit::SetLinePartner { id: 1, partner: 2, allowed: true }), "'Heparin' doesn't have a dedicated line");
+        assert_eq!(error(Edit::SetLinePartner { id: 2, partner: 2, allowed: true }), "an infusion can't be paired with itself");
+        assert_eq!(error(Edit::SetLinePartner { id: 2, partner: 1, allowed: true }), "nothing to change for 'Propofol' and 'Heparin'");
+
+        let (plan, _) = Edit::SetLinePartner { id: 2, partner: 1, allowed: false }.plan(&dataset()).unwrap();
+        assert_eq!(plan.set_line_partners, [(2, 1, false)]);
+    }
+
+    #[test]
+    fn compatibility_counts() {
+        let counts = |compatible| { CompatibilityCounts { compatible, incompatible: 0, mixed: 0 } };
+        let (plan, description) = Edit::SetCompatibility { id1: 2, id2: 1, counts: counts(3) }.plan(&dataset()).unwrap();
+        assert_eq!(plan.add_compatibility.len(), 1);
+        assert_eq!(description, "Set compatibility of 'Propofol' and 'Heparin' to 3:0:0");
+
+        assert_eq!(error(Edit::SetCompatibility { id1: 1, id2: 2, counts: counts(MAX_COUNT + 1) }), "counts can't be more than 255");
+        assert_eq!(error(Edit::SetCompatibility { id1: 1, id2: 1, counts: counts(1) }), "an infusion can't be paired with itself");
+        assert_eq!(error(Edit::SetCompatibility { id1: 1, id2: 3, counts: counts(1) }), "infusion 3 doesn't exist");
+        assert_eq!(error(Edit::RemoveCompatibility { id1: 1, id2: 2 }), "there's no compatibility record for 'Heparin' and 'Propofol'");
+    }
+
+    #[test]
+    fn flushable_pairs() {
+        let (plan, _) = Edit::SetFlushable { id1: 2, id2: 1, flushable: true }.plan(&dataset()).unwrap();
+        assert_eq!(plan.set_flushable, [(1, 2, true)]);
+        assert_eq!(error(Edit::SetFlushable { id1: 1, id2: 2, flushable: false }), "nothing to change for 'Heparin' and 'Propofol'");
+    }
+
+    #[test]
+    fn names() {
+        let (plan, _) = Edit::RenameInfusion { id: 1, name: " Heparin sodium ".to_string() }.plan(&dataset()).unwrap();
+        assert_eq!(plan.rename_infusions, [(1, "Heparin sodium".to_string())]);
+        assert_eq!(error(Edit::AddInfusion { name: "Propofol".to_string(), infusion_type: InfusionType::Drug }),
+                   "an infusion named 'Propofol' already exists");
+        assert_eq!(error(Edit::AddInfusion { name: "".to_string(), infusion_type: InfusionType::Drug }), "the name can't be empty");
+    }
+}
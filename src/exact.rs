@@ -0,0 +1,376 @@
+use crate::solver::SolveBudget;
+use itertools::Itertools;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+/// The search ran out of time or was cancelled
+pub(crate) struct Interrupted;
+
+/// Exact graph coloring by backtracking (DSatur branch and bound).
+///
+/// Preset nodes keep the colors they were given, so the user's current
+/// IVs are never rearranged. Only the remaining nodes are searched.
+pub(crate) struct ExactSearch<'a> {
+    budget: &'a SolveBudget,
+    nodes: Vec<u32>,
+    adjacent: HashMap<u32, Vec<u32>>,
+    coloring: HashMap<u32, u32>,
+    forbidden: HashMap<u32, Vec<u32>>,  // node -> colors it can't have
+    reserved: u32,                      // colors that always exist, so aren't interchangeable
+    steps: u64,
+}
+
+impl<'a> ExactSearch<'a> {
+    /// `adjacent` must not contain self loops. `preset` maps already colored nodes to their colors.
+    pub(crate) fn new(adjacent: HashMap<u32, Vec<u32>>, preset: HashMap<u32, u32>, budget: &'a SolveBudget) -> Self {
+        let nodes = adjacent.keys().filter(|n| { !preset.contains_key(n) }).copied().collect();
+
+        Self {
+            budget,
+            nodes,
+            adjacent,
+            coloring: preset,
+            forbidden: HashMap::new(),
+            reserved: 0,
+            steps: 0,
+        }
+    }
+
+    /// Keep nodes away from some colors. Colors below `reserved` are all tried, since
+    /// they're no longer interchangeable.
+    pub(crate) fn restrict(&mut self, forbidden: HashMap<u32, Vec<u32>>, reserved: u32) {
+        self.forbidden = forbidden;
+        self.reserved = reserved;
+    }
+
+    /// Find a coloring which uses at most `max_colors` colors.
+    ///
+    /// Returns `Ok(None)` if no such coloring exists.
+    pub(crate) fn find_coloring(&mut self, max_colors: u32) -> Result<Option<HashMap<u32, u32>>, Interrupted> {
+        let preset = self.coloring.clone();
+        let used = preset.values().map(|c| { c + 1 }).max().unwrap_or(0);
+
+        let found = self.color_remaining(used, max_colors)?;
+        let result = if found { Some(self.coloring.clone()) } else { None };
+        self.coloring = preset;
+
+        Ok(result)
+    }
+
+    fn color_remaining(&mut self, used: u32, max_colors: u32) -> Result<bool, Interrupted> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(1024) && self.budget.is_exhausted() {
+            return Err(Interrupted);
+        }
+
+        let node = match self.most_saturated() {
+            Some(node) => node,
+            None => return Ok(true),
+        };
+
+        // Trying one new color is enough since unused colors are interchangeable
+        let limit = std::cmp::min(std::cmp::max(used, self.reserved) + 1, max_colors);
+        for color in 0..limit {
+            if self.forbidden.get(&node).is_some_and(|colors| { colors.contains(&color) }) {
+                continue;
+            }
+            let conflict = self.adjacent[&node]
+                .iter()
+                .any(|adj| { self.coloring.get(adj) == Some(&color) });
+            if conflict {
+                continue;
+            }
+
+            self.coloring.insert(node, color);
+            if self.color_remaining(std::cmp::max(used, color + 1), max_colors)? {
+                return Ok(true);
+            }
+            self.coloring.remove(&node);
+        }
+
+        Ok(false)
+    }
+
+    /// The uncolored node with the most distinct neighboring colors,
+    /// with ties broken by the number of neighbors
+    fn most_saturated(&self) -> Option<u32> {
+        self.nodes
+            .iter()
+            .filter(|n| { !self.coloring.contains_key(n) })
+            .max_by_key(|n| {
+                let mut adj_colors = self.adjacent[n]
+                    .iter()
+                    .filter_map(|adj| { self.coloring.get(adj) })
+                    .collect::<Vec<_>>();
+                adj_colors.sort_unstable();
+                adj_colors.dedup();
+
+                (adj_colors.len(), self.adjacent[n].len())
+            })
+            .copied()
+    }
+}
+
+/// Branch and bound search for the coloring that differs least from a preferred one.
+///
+/// Used to consolidate IVs: the colors are the current IVs and each node prefers the
+/// IV it's in now, so the closest coloring is the one that moves the fewest infusions.
+pub(crate) struct ClosestSearch<'a> {
+    budget: &'a SolveBudget,
+    nodes: Vec<u32>,    // in the order they're colored
+    adjacent: HashMap<u32, Vec<u32>>,
+    preferred: HashMap<u32, u32>,
+    coloring: HashMap<u32, u32>,
+    forbidden: HashMap<u32, Vec<u32>>,  // node -> colors it can't have
+    usage: Vec<u32>,    // color -> number of nodes with that color
+    best: Option<(u32, HashMap<u32, u32>)>,
+    steps: u64,
+}
+
+impl<'a> ClosestSearch<'a> {
+    /// `adjacent` must not contain self loops. Every node needs a color in `preferred`,
+    /// and colors are taken from `0..num_colors`.
+    pub(crate) fn new(adjacent: HashMap<u32, Vec<u32>>, preferred: HashMap<u32, u32>, num_colors: u32,
+                      budget: &'a SolveBudget) -> Self {
+        // Nodes with the most neighbors go first, so conflicts show up early
+        let nodes = adjacent.keys().copied().sorted_by_key(|n| { (Reverse(adjacent[n].len()), *n) }).collect();
+
+        Self {
+            budget,
+            nodes,
+            adjacent,
+            preferred,
+            coloring: HashMap::new(),
+            forbidden: HashMap::new(),
+            usage: vec![0; num_colors as usize],
+            best: None,
+            steps: 0,
+        }
+    }
+
+    /// Number of nodes whose color isn't the preferred one
+    fn cost(&self, coloring: &HashMap<u32, u32>) -> u32 {
+        coloring.iter().filter(|(node, color)| { self.preferred[node] != **color }).count() as u32
+    }
+
+    /// Keep nodes away from some colors. Their preferred colors must still be allowed.
+    pub(crate) fn restrict(&mut self, forbidden: HashMap<u32, Vec<u32>>) {
+        self.forbidden = forbidden;
+    }
+
+    /// Only look for colorings that are closer than `coloring`
+    pub(crate) fn set_upper_bound(&mut self, coloring: HashMap<u32, u32>) {
+        self.best = Some((self.cost(&coloring), coloring));
+    }
+
+    /// Find the closest coloring that uses at most `max_used` distinct colors.
+    ///
+    /// Returns the best coloring found, which is the upper bound if nothing closer was found,
+    /// and whether it's proven to be the closest.
+    pub(crate) fn find(mut self, max_used: u32) -> (Option<HashMap<u32, u32>>, bool) {
+        let complete = self.search(0, 0, 0, max_used).is_ok();
+
+        (self.best.map(|(_, coloring)| { coloring }), complete)
+    }
+
+    fn search(&mut self, index: usize, cost: u32, used: u32, max_used: u32) -> Result<(), Interrupted> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(1024) && self.budget.is_exhausted() {
+            return Err(Interrupted);
+        }
+        if let Some((best_cost, _)) = &self.best {
+            if cost + self.moves_forced(index, used, max_used) >= *best_cost {
+                return Ok(());
+            }
+        }
+
+        let Some(&node) = self.nodes.get(index) else {
+            self.best = Some((cost, self.coloring.clone()));
+            return Ok(());
+        };
+
+        // Staying put first, then every other color. Unused colors that no remaining
+        // node prefers are interchangeable, so only one of them is tried.
+        let preferred = self.preferred[&node];
+        let others = (0..self.usage.len() as u32).filter(|color| { *color != preferred });
+        let mut tried_spare = false;
+        for color in std::iter::once(preferred).chain(others) {
+            let unused = self.usage[color as usize] == 0;
+            if unused && used == max_used {
+                continue;
+            }
+            if self.forbidden.get(&node).is_some_and(|colors| { colors.contains(&color) }) {
+                continue;
+            }
+            if unused && color != preferred && !self.preferred_later(index, color) {
+                if tried_spare {
+                    continue;
+                }
+                tried_spare = true;
+            }
+            if self.adjacent[&node].iter().any(|adj| { self.coloring.get(adj) == Some(&color) }) {
+                continue;
+            }
+
+            self.coloring.insert(node, color);
+            self.usage[color as usize] += 1;
+            let result = self.search(index + 1, cost + u32::from(color != preferred), used + u32::from(unused), max_used);
+            self.usage[color as usize] -= 1;
+            self.coloring.remove(&node);
+            result?;
+        }
+
+        Ok(())
+    }
+
+    fn preferred_later(&self, index: usize, color: u32) -> bool {
+        self.nodes[index + 1..].iter().any(|n| { self.preferred[n] == color })
+    }
+
+    /// A lower bound on how many nodes from `index` on won't get their preferred color:
+    /// those whose preferred color is taken by a neighbor, plus the smallest groups of nodes
+    /// preferring colors that can't all be used within `max_used`
+    fn moves_forced(&self, index: usize, used: u32, max_used: u32) -> u32 {
+        let mut blocked = 0;
+        let mut waiting: HashMap<u32, u32> = HashMap::new();    // unused color -> nodes that could still get it
+        for node in &self.nodes[index..] {
+            let preferred = self.preferred[node];
+            if self.adjacent[node].iter().any(|adj| { self.coloring.get(adj) == Some(&preferred) }) {
+                blocked += 1;
+            } else if self.usage[preferred as usize] == 0 {
+                *waiting.entry(preferred).or_insert(0) += 1;
+            }
+        }
+
+        let spare = (max_used - used) as usize;
+        let dropped = waiting.into_values().sorted().rev().skip(spare).sum::<u32>();
+
+        blocked + dropped
+    }
+}
+
+/// Rename the colors in `coloring` so as many nodes as possible get their preferred color.
+/// Colors are matched up greedily, biggest overlap first, and taken from `0..num_colors`.
+pub(crate) fn relabel(coloring: &HashMap<u32, u32>, preferred: &HashMap<u32, u32>, num_colors: u32) -> HashMap<u32, u32> {
+    let mut overlap: HashMap<(u32, u32), u32> = HashMap::new();
+    for (node, color) in coloring {
+        *overlap.entry((*color, preferred[node])).or_insert(0) += 1;
+    }
+
+    let mut mapping: HashMap<u32, u32> = HashMap::new();
+    let mut taken = HashSet::new();
+    for ((from, to), _) in overlap.into_iter().sorted_by_key(|(pair, count)| { (Reverse(*count), *pair) }) {
+        if !mapping.contains_key(&from) && !taken.contains(&to) {
+            mapping.insert(from, to);
+            taken.insert(to);
+        }
+    }
+    let mut spare = (0..num_colors).filter(|color| { !taken.contains(color) });
+    for from in coloring.values().unique().sorted() {
+        if !mapping.contains_key(from) {
+            mapping.insert(*from, spare.next().expect("Fewer colors than the coloring uses"));
+        }
+    }
+
+    coloring.iter().map(|(node, color)| { (*node, mapping[color]) }).collect()
+}
+
+/// Size of a clique found greedily. Every clique member needs its own color,
+/// so this is a lower bound on the number of colors.
+pub(crate) fn greedy_clique_size(adjacent: &HashMap<u32, Vec<u32>>) -> u32 {
+    let mut best = 0;
+    for start in adjacent.keys() {
+        let mut clique = vec![*start];
+        let mut candidates = adjacent[start].clone();
+        candidates.sort_unstable_by_key(|n| { std::cmp::Reverse(adjacent[n].len()) });
+
+        for candidate in candidates {
+            if clique.iter().all(|member| { adjacent[member].contains(&candidate) }) {
+                clique.push(candidate);
+            }
+        }
+        best = std::cmp::max(best, clique.len() as u32);
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Adjacency lists for an undirected graph
+    fn graph(nodes: u32, edges: &[(u32, u32)]) -> HashMap<u32, Vec<u32>> {
+        let mut adjacent: HashMap<u32, Vec<u32>> = (0..nodes).map(|n| { (n, Vec::new()) }).collect();
+        for (a, b) in edges {
+            adjacent.get_mut(a).unwrap().push(*b);
+            adjacent.get_mut(b).unwrap().push(*a);
+        }
+
+        adjacent
+    }
+
+    fn budget() -> SolveBudget {
+        SolveBudget::new(Duration::from_secs(5))
+    }
+
+    fn num_colors(coloring: &HashMap<u32, u32>) -> usize {
+        coloring.values().unique().count()
+    }
+
+    #[test]
+    fn odd_cycle_needs_three_colors() {
+        let budget = budget();
+        let mut search = ExactSearch::new(graph(5, &[(0, 1), (1, 2), (2, 3), (3, 4), (4, 0)]), HashMap::new(), &budget);
+        assert!(search.find_coloring(2).ok().unwrap().is_none());
+        assert_eq!(num_colors(&search.find_coloring(3).ok().unwrap().unwrap()), 3);
+    }
+
+    #[test]
+    fn preset_nodes_keep_their_colors() {
+        let budget = budget();
+        let preset = HashMap::from([(0, 1), (1, 0)]);
+        let mut search = ExactSearch::new(graph(3, &[(0, 2)]), preset, &budget);
+        let coloring = search.find_coloring(2).ok().unwrap().unwrap();
+        assert_eq!((coloring[&0], coloring[&1], coloring[&2]), (1, 0, 0));
+    }
+
+    #[test]
+    fn forbidden_colors_are_avoided() {
+        let budget = budget();
+        let mut search = ExactSearch::new(graph(2, &[]), HashMap::new(), &budget);
+        search.restrict(HashMap::from([(1, vec![0])]), 2);
+        let coloring = search.find_coloring(2).ok().unwrap().unwrap();
+        assert_eq!(coloring[&1], 1);
+        assert!(search.find_coloring(1).ok().unwrap().is_none());
+    }
+
+    #[test]
+    fn closest_coloring_moves_the_fewest_nodes() {
+        // 0 and 1 are in color 0, 2 is alone in color 1 and fits with either
+        let budget = budget();
+        let preferred = HashMap::from([(0, 0), (1, 0), (2, 1)]);
+        let (coloring, proven) = ClosestSearch::new(graph(3, &[]), preferred.clone(), 2, &budget).find(1);
+        let coloring = coloring.unwrap();
+        assert!(proven);
+        assert_eq!(coloring.values().unique().collect_vec(), [&0]);
+
+        let mut search = ClosestSearch::new(graph(3, &[]), preferred, 2, &budget);
+        search.restrict(HashMap::from([(0, vec![1]), (1, vec![1]), (2, vec![0])]));
+        assert_eq!(search.find(1).0, None);
+    }
+
+    #[test]
+    fn relabels_to_match_the_preferred_colors() {
+        let coloring = HashMap::from([(0, 5), (1, 5), (2, 7)]);
+        let preferred = HashMap::from([(0, 1), (1, 1), (2, 0)]);
+        assert_eq!(relabel(&coloring, &preferred, 2), preferred);
+    }
+
+    #[test]
+    fn finds_a_clique() {
+        assert_eq!(greedy_clique_size(&graph(4, &[(0, 1), (1, 2), (2, 0), (2, 3)])), 3);
+        assert_eq!(greedy_clique_size(&graph(2, &[])), 1);
+    }
+}
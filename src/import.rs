@@ -0,0 +1,542 @@
+use crate::dataset::{CompatibilityCounts, CompatibilityDetails, Dataset, DatasetVersion};
+use crate::db::StoreError;
+use crate::infusion::InfusionType;
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+pub mod parse;
+
+/// An infusion to be imported, identified by its name since it may not have an ID yet
+#[derive(Debug, Clone)]
+pub struct NewInfusion {
+    pub name: String,
+    pub infusion_type: InfusionType,
+}
+
+/// Compatibility data to be imported for a pair of infusions, identified by name
+#[derive(Debug, Clone)]
+pub struct NewCompatibility {
+    pub infusion_a: String,
+    pub infusion_b: String,
+    pub counts: CompatibilityCounts,
+    /// `None` if the file has no columns for them, which keeps any details already stored
+    pub details: Option<CompatibilityDetails>,
+}
+
+/// Where imported data came from, recorded with the dataset version it creates
+#[derive(Debug, Clone)]
+pub struct ImportSource {
+    pub name: String,
+    pub source_hash: String,    // SHA-256 of the file contents, as hex
+}
+
+impl ImportSource {
+    pub fn new(name: &str, contents: &[u8]) -> Self {
+        let source_hash = Sha256::digest(contents)
+            .iter()
+            .map(|byte| { format!("{:02x}", byte) })
+            .collect();
+
+        Self { name: name.to_string(), source_hash }
+    }
+}
+
+/// Largest count that fits in the `TINYINT UNSIGNED` result columns
+pub const MAX_COUNT: u32 = 255;
+
+/// Everything read from an import file
+#[derive(Debug, Default)]
+pub struct ImportData {
+    pub infusions: Vec<NewInfusion>,
+    pub compatibility: Vec<NewCompatibility>,
+}
+
+/// How to settle a pair whose entries in the file disagree,
+/// e.g. when the A→B and B→A cells of the matrix differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsymmetryRule {
+    /// Keep whichever entry comes first in the file
+    FirstWins,
+    /// Add the trial counts together, capped at `MAX_COUNT`
+    Sum,
+    /// Keep the fewest compatible and the most incompatible and mixed results
+    MostConservative,
+    /// Refuse to import the file
+    Fail,
+}
+
+/// A pair of infusions with disagreeing entries in the file
+#[derive(Debug, Clone)]
+pub struct AsymmetricPair {
+    pub first: NewCompatibility,
+    pub second: NewCompatibility,
+    /// Adding the counts together went past `MAX_COUNT`, so the result was capped
+    pub capped: bool,
+}
+
+impl fmt::Display for AsymmetricPair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let counts = |c: &NewCompatibility| {
+            format!("{}:{}:{}", c.counts.compatible, c.counts.incompatible, c.counts.mixed)
+        };
+        write!(f, "{} → {} is {} but {} → {} is {}",
+            self.first.infusion_a, self.first.infusion_b, counts(&self.first),
+            self.second.infusion_a, self.second.infusion_b, counts(&self.second))?;
+        if self.capped {
+            write!(f, " (the sum is capped at {})", MAX_COUNT)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl ImportData {
+    /// Merge all entries for the same pair into one, using `rule` where they disagree.
+    ///
+    /// Returns every disagreement found. With `AsymmetryRule::Fail` the data is left unchanged.
+    pub fn resolve_asymmetry(&mut self, rule: AsymmetryRule) -> Vec<AsymmetricPair> {
+        let mut merged: Vec<NewCompatibility> = Vec::new();
+        let mut pair_index: HashMap<(String, String), usize> = HashMap::new();
+        let mut asymmetric = Vec::new();
+
+        for compat in &self.compatibility {
+            let key = if compat.infusion_a < compat.infusion_b {
+                (compat.infusion_a.clone(), compat.infusion_b.clone())
+            } else {
+                (compat.infusion_b.clone(), compat.infusion_a.clone())
+            };
+
+            let index = match pair_index.get(&key) {
+                Some(index) => *index,
+                None => {
+                    pair_index.insert(key, merged.len());
+                    merged.push(compat.clone());
+                    continue;
+                }
+            };
+
+            let existing = &mut merged[index];
+            if existing.counts == compat.counts {
+                continue;
+            }
+            let mut pair = AsymmetricPair { first: existing.clone(), second: compat.clone(), capped: false };
+
+            let (a, b) = (existing.counts, compat.counts);
+            match rule {
+                AsymmetryRule::FirstWins | AsymmetryRule::Fail => {},
+                AsymmetryRule::Sum => {
+                    let sum = CompatibilityCounts {
+                        compatible: a.compatible + b.compatible,
+                        incompatible: a.incompatible + b.incompatible,
+                        mixed: a.mixed + b.mixed,
+                    };
+                    pair.capped = [sum.compatible, sum.incompatible, sum.mixed].iter().any(|count| { *count > MAX_COUNT });
+                    existing.counts = CompatibilityCounts {
+                        compatible: sum.compatible.min(MAX_COUNT),
+                        incompatible: sum.incompatible.min(MAX_COUNT),
+                        mixed: sum.mixed.min(MAX_COUNT),
+                    };
+                },
+                AsymmetryRule::MostConservative => {
+                    existing.counts = CompatibilityCounts {
+                        compatible: a.compatible.min(b.compatible),
+                        incompatible: a.incompatible.max(b.incompatible),
+                        mixed: a.mixed.max(b.mixed),
+                    };
+                },
+            }
+            asymmetric.push(pair);
+        }
+
+        if rule != AsymmetryRule::Fail || asymmetric.is_empty() {
+            self.compatibility = merged;
+        }
+
+        asymmetric
+    }
+}
+
+/// How imported rows are reconciled with rows that already exist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportMode {
+    /// Only add new rows. Existing rows are kept even if the file disagrees.
+    InsertOnly,
+    /// Add new rows and overwrite existing rows that changed
+    Upsert,
+    /// Like upsert, but also retire infusions and remove compatibility records that aren't in the file
+    Replace,
+}
+
+/// Row counts for one table
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RowCounts {
+    pub added: u32,
+    pub updated: u32,
+    pub unchanged: u32,
+    /// Rows that differ from the file but were kept because of insert-only mode
+    pub skipped: u32,
+    pub removed: u32,
+    /// Infusions that were retired rather than removed, because they aren't in the file
+    pub retired: u32,
+}
+
+impl fmt::Display for RowCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} added, {} updated, {} unchanged, {} skipped, {} removed",
+            self.added, self.updated, self.unchanged, self.skipped, self.removed)?;
+        if self.retired > 0 {
+            write!(f, ", {} retired", self.retired)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ImportSummary {
+    pub infusions: RowCounts,
+    pub compatibility: RowCounts,
+    /// The dataset version created by the import, once it has been written
+    pub version: Option<DatasetVersion>,
+}
+
+impl fmt::Display for ImportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Infusions: {}", self.infusions)?;
+        write!(f, "Compatibility records: {}", self.compatibility)?;
+        if let Some(version) = &self.version {
+            write!(f, "\nCreated dataset version {}", version)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The changes needed to bring a store's data in line with an import file,
+/// or to make an edit from the admin pages
+#[derive(Debug, Default)]
+pub struct ImportPlan {
+    pub add_infusions: Vec<NewInfusion>,
+    pub update_infusions: Vec<(u32, InfusionType)>,                  // existing ID -> new type
+    pub rename_infusions: Vec<(u32, String)>,                        // existing ID -> new name
+    pub retire_infusions: Vec<(u32, bool)>,                          // existing ID -> retired or not
+    pub central_only_infusions: Vec<(u32, bool)>,                    // existing ID -> central access only or not
+    pub dedicated_line_infusions: Vec<(u32, Option<String>)>,        // existing ID -> why it needs a line of its own, if it does
+    pub add_compatibility: Vec<NewCompatibility>,                    // may refer to infusions being added
+    pub update_compatibility: Vec<(u32, u32, CompatibilityCounts)>,  // (lower ID, higher ID) -> new counts
+    pub set_details: Vec<(u32, u32, CompatibilityDetails)>,         // (lower ID, higher ID) -> new details
+    pub remove_compatibility: Vec<(u32, u32)>,
+    pub set_flushable: Vec<(u32, u32, bool)>,                        // (lower ID, higher ID) -> flushable or not
+    pub set_line_partners: Vec<(u32, u32, bool)>,                    // (ID with a dedicated line, partner ID) -> allowed or not
+    pub summary: ImportSummary,
+}
+
+impl ImportPlan {
+    /// Work out how `data` should be applied on top of `current`.
+    ///
+    /// If a pair appears more than once in `data` the first entry wins.
+    pub fn new(current: &Dataset, data: &ImportData, mode: ImportMode) -> Result<Self, StoreError> {
+        let mut plan = Self::default();
+
+        let mut incoming_names = HashSet::new();
+        for infusion in &data.infusions {
+            if !incoming_names.insert(infusion.name.as_str()) {
+                continue;
+            }
+
+            match current.find_by_name(&infusion.name) {
+                None => {
+                    plan.add_infusions.push(infusion.clone());
+                    plan.summary.infusions.added += 1;
+                },
+                Some(existing) if existing.infusion_type == infusion.infusion_type => {
+                    plan.summary.infusions.unchanged += 1;
+                },
+                Some(_) if mode == ImportMode::InsertOnly => {
+                    plan.summary.infusions.skipped += 1;
+                },
+                Some(existing) => {
+                    plan.update_infusions.push((existing.id, infusion.infusion_type));
+                    plan.summary.infusions.updated += 1;
+                },
+            }
+        }
+
+        // Existing IDs of incoming infusions, where they already exist
+        let existing_ids: HashMap<&str, u32> = incoming_names
+            .iter()
+            .filter_map(|name| { current.find_by_name(name).map(|inf| { (*name, inf.id) }) })
+            .collect();
+
+        let mut incoming_pairs = HashSet::new();
+        for compat in &data.compatibility {
+            for name in [&compat.infusion_a, &compat.infusion_b] {
+                if !incoming_names.contains(name.as_str()) {
+                    return Err(StoreError::InvalidData(format!("infusion not found: {}", name)));
+                }
+            }
+            let pair = if compat.infusion_a < compat.infusion_b {
+                (compat.infusion_a.as_str(), compat.infusion_b.as_str())
+            } else {
+                (compat.infusion_b.as_str(), compat.infusion_a.as_str())
+            };
+            if !incoming_pairs.insert(pair) {
+                continue;
+            }
+
+            let ids = existing_ids.get(pair.0).zip(existing_ids.get(pair.1));
+            let existing = ids.and_then(|(id1, id2)| {
+                current.compatibility(*id1, *id2).map(|counts| { (*id1, *id2, counts) })
+            });
+            let new_details = existing.and_then(|(id1, id2, _)| {
+                let details = current.details(id1, id2).cloned().unwrap_or_default();
+                compat.details.clone().filter(|new| { *new != details })
+            });
+            match existing {
+                None => {
+                    plan.add_compatibility.push(compat.clone());
+                    plan.summary.compatibility.added += 1;
+                },
+                Some((_, _, counts)) if *counts == compat.counts && new_details.is_none() => {
+                    plan.summary.compatibility.unchanged += 1;
+                },
+                Some(_) if mode == ImportMode::InsertOnly => {
+                    plan.summary.compatibility.skipped += 1;
+                },
+                Some((id1, id2, counts)) => {
+                    let (id1, id2) = if id1 < id2 { (id1, id2) } else { (id2, id1) };
+                    if *counts != compat.counts {
+                        plan.update_compatibility.push((id1, id2, compat.counts));
+                    }
+                    if let Some(details) = new_details {
+                        plan.set_details.push((id1, id2, details));
+                    }
+                    plan.summary.compatibility.updated += 1;
+                },
+            }
+        }
+
+        if mode == ImportMode::Replace {
+            // Infusions missing from the file may be running in saved regimens, be named in the audit log or
+            // have been added on the admin pages, so they're retired rather than removed. They keep their
+            // compatibility records and settings, and can be restored on the admin pages.
+            let mut retired = HashSet::new();
+            for infusion in current.infusions() {
+                if !incoming_names.contains(infusion.name.as_str()) {
+                    retired.insert(infusion.id);
+                    if !infusion.retired {
+                        plan.retire_infusions.push((infusion.id, true));
+                        plan.summary.infusions.retired += 1;
+                    }
+                }
+            }
+
+            for (id1, id2) in current.compatibility_pairs() {
+                if retired.contains(&id1) || retired.contains(&id2) {
+                    continue;
+                }
+                let name1 = current.infusion(id1).unwrap().name.as_str();
+                let name2 = current.infusion(id2).unwrap().name.as_str();
+                let pair = if name1 < name2 { (name1, name2) } else { (name2, name1) };
+                if !incoming_pairs.contains(&pair) {
+                    plan.remove_compatibility.push((id1, id2));
+                    plan.summary.compatibility.removed += 1;
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{CompatibilityStore, MemoryStore};
+    use crate::edit::Edit;
+
+    const MATRIX: &str = ",,Heparin,Norepinephrine,Propofol\n\
+                          Heparin,1,,1:0:0,0:1:0\n\
+                          Norepinephrine,1,1:0:0,,0:1:0\n\
+                          Propofol,1,0:1:0,0:1:0,\n";
+
+    fn import(store: &MemoryStore, contents: &str) -> Result<ImportSummary, StoreError> {
+        let parsed = parse::parse_matrix(contents, b',');
+        assert!(!parsed.has_errors(), "{:?}", parsed.problems);
+        store.import(&parsed.data, ImportMode::Replace, &ImportSource::new("test", contents.as_bytes()))
+    }
+
+    fn store_with_settings() -> (MemoryStore, u32, u32, u32) {
+        let store = MemoryStore::new();
+        import(&store, MATRIX).unwrap();
+        let dataset = store.load_dataset().unwrap();
+        let id = |name| { dataset.find_by_name(name).unwrap().id };
+        let (heparin, norepinephrine, propofol) = (id("Heparin"), id("Norepinephrine"), id("Propofol"));
+
+        for edit in [
+            Edit::SetCentralOnly { id: norepinephrine, central_only: true },
+            Edit::SetDedicatedLine { id: propofol, reason: Some("lipid emulsion".to_string()) },
+            Edit::SetLinePartner { id: propofol, partner: heparin, allowed: true },
+            Edit::SetFlushable { id1: heparin, id2: propofol, flushable: true },
+        ] {
+            store.edit(&edit, "alice", "test").unwrap();
+        }
+
+        (store, heparin, norepinephrine, propofol)
+    }
+
+    #[test]
+    fn replacing_with_the_same_data_keeps_settings() {
+        let (store, heparin, norepinephrine, propofol) = store_with_settings();
+        let summary = import(&store, MATRIX).unwrap();
+        assert_eq!((summary.infusions.removed, summary.compatibility.removed), (0, 0));
+
+        let dataset = store.load_dataset().unwrap();
+        assert!(dataset.infusion(norepinephrine).unwrap().central_only);
+        assert_eq!(dataset.infusion(propofol).unwrap().dedicated_line.as_deref(), Some("lipid emulsion"));
+        assert!(dataset.is_line_partner(propofol, heparin));
+        assert!(dataset.is_flushable(heparin, propofol));
+    }
+
+    #[test]
+    fn replacing_retires_infusions_and_keeps_their_settings() {
+        let (store, heparin, _, propofol) = store_with_settings();
+        let renamed = MATRIX.replace("Propofol", "Propofol 1%");
+        let summary = import(&store, &renamed).unwrap();
+        assert_eq!((summary.infusions.added, summary.infusions.retired, summary.infusions.removed), (1, 1, 0));
+        assert_eq!(summary.compatibility.removed, 0);
+
+        let dataset = store.load_dataset().unwrap();
+        let retired = dataset.infusion(propofol).unwrap();
+        assert!(retired.retired);
+        assert_eq!(retired.dedicated_line.as_deref(), Some("lipid emulsion"));
+        assert!(dataset.is_line_partner(propofol, heparin));
+        assert!(dataset.is_flushable(heparin, propofol));
+        assert!(dataset.compatibility(heparin, propofol).is_some());
+
+        // Already retired, so nothing changes the second time
+        let summary = import(&store, &renamed).unwrap();
+        assert_eq!(summary.infusions.retired, 0);
+    }
+
+    #[test]
+    fn replacing_keeps_infusions_in_regimens_and_the_audit_log() {
+        let (store, heparin, norepinephrine, _) = store_with_settings();
+        let edit = Edit::AddInfusion { name: "Vancomycin".to_string(), infusion_type: InfusionType::Drug };
+        store.edit(&edit, "alice", "test").unwrap();
+        let vancomycin = store.load_dataset().unwrap().find_by_name("Vancomycin").unwrap().id;
+        let regimen = store.add_regimen("Bed 4", &[vec![heparin], vec![vancomycin]], &Default::default(), &[], "alice").unwrap();
+
+        let summary = import(&store, MATRIX).unwrap();
+        assert_eq!(summary.infusions.retired, 1);
+
+        let dataset = store.load_dataset().unwrap();
+        assert!(dataset.infusion(vancomycin).unwrap().retired);
+        assert!(!dataset.infusion(norepinephrine).unwrap().retired);
+        let ivs = store.regimen(regimen.id).unwrap().unwrap().ivs;
+        assert!(ivs.iter().flatten().all(|id| { dataset.infusion(*id).is_some() }));
+    }
+
+    /// Heparin, Norepinephrine and Propofol, where only Heparin and Norepinephrine are compatible
+    fn data(heparin_norepinephrine: u32, with_propofol: bool) -> ImportData {
+        let names: &[&str] = if with_propofol { &["Heparin", "Norepinephrine", "Propofol"] } else { &["Heparin", "Norepinephrine"] };
+        let compat = |a: &str, b: &str, compatible: u32, incompatible: u32| {
+            NewCompatibility {
+                infusion_a: a.to_string(),
+                infusion_b: b.to_string(),
+                counts: CompatibilityCounts { compatible, incompatible, mixed: 0 },
+                details: None,
+            }
+        };
+        let mut compatibility = vec![compat("Heparin", "Norepinephrine", heparin_norepinephrine, 0)];
+        if with_propofol {
+            compatibility.extend([compat("Heparin", "Propofol", 0, 1), compat("Norepinephrine", "Propofol", 0, 1)]);
+        }
+
+        ImportData {
+            infusions: names.iter().map(|name| { NewInfusion { name: name.to_string(), infusion_type: InfusionType::Drug } }).collect(),
+            compatibility,
+        }
+    }
+
+    fn heparin_norepinephrine(store: &MemoryStore) -> u32 {
+        let dataset = store.load_dataset().unwrap();
+        let id = |name| { dataset.find_by_name(name).unwrap().id };
+        dataset.compatibility(id("Heparin"), id("Norepinephrine")).unwrap().compatible
+    }
+
+    #[test]
+    fn import_modes() {
+        let store = MemoryStore::new();
+        let source = ImportSource::new("test", b"");
+        let summary = store.import(&data(1, true), ImportMode::InsertOnly, &source).unwrap();
+        assert_eq!((summary.infusions.added, summary.compatibility.added), (3, 3));
+
+        let summary = store.import(&data(2, true), ImportMode::InsertOnly, &source).unwrap();
+        assert_eq!((summary.compatibility.skipped, summary.compatibility.unchanged), (1, 2));
+        assert_eq!(heparin_norepinephrine(&store), 1);
+
+        let summary = store.import(&data(2, true), ImportMode::Upsert, &source).unwrap();
+        assert_eq!((summary.compatibility.updated, summary.compatibility.unchanged), (1, 2));
+        assert_eq!(heparin_norepinephrine(&store), 2);
+
+        let summary = store.import(&data(2, false), ImportMode::Replace, &source).unwrap();
+        assert_eq!((summary.infusions.retired, summary.compatibility.removed), (1, 0));
+        assert!(store.load_dataset().unwrap().find_by_name("Propofol").unwrap().retired);
+
+        let mut without_pair = data(2, true);
+        without_pair.compatibility.pop();
+        let summary = store.import(&without_pair, ImportMode::Replace, &source).unwrap();
+        assert_eq!((summary.infusions.added, summary.compatibility.removed), (0, 1));
+    }
+
+    fn asymmetric() -> ImportData {
+        let compat = |a: &str, b: &str, counts: (u32, u32, u32)| {
+            NewCompatibility {
+                infusion_a: a.to_string(),
+                infusion_b: b.to_string(),
+                counts: CompatibilityCounts { compatible: counts.0, incompatible: counts.1, mixed: counts.2 },
+                details: None,
+            }
+        };
+        ImportData {
+            infusions: Vec::new(),
+            compatibility: vec![compat("A", "B", (3, 1, 10)), compat("B", "A", (1, 2, 250)), compat("A", "C", (1, 0, 0))],
+        }
+    }
+
+    fn resolved(rule: AsymmetryRule) -> (Vec<AsymmetricPair>, Vec<(u32, u32, u32)>) {
+        let mut data = asymmetric();
+        let pairs = data.resolve_asymmetry(rule);
+        let counts = data.compatibility.iter().map(|c| { (c.counts.compatible, c.counts.incompatible, c.counts.mixed) }).collect();
+
+        (pairs, counts)
+    }
+
+    #[test]
+    fn asymmetry_rules() {
+        let (pairs, counts) = resolved(AsymmetryRule::FirstWins);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(pairs[0].to_string(), "A → B is 3:1:10 but B → A is 1:2:250");
+        assert_eq!(counts, [(3, 1, 10), (1, 0, 0)]);
+
+        let (pairs, counts) = resolved(AsymmetryRule::Sum);
+        assert_eq!(counts, [(4, 3, MAX_COUNT), (1, 0, 0)]);
+        assert!(pairs[0].capped);
+        assert!(pairs[0].to_string().ends_with("(the sum is capped at 255)"));
+        assert!(!resolved(AsymmetryRule::FirstWins).0[0].capped);
+        assert_eq!(resolved(AsymmetryRule::MostConservative).1, [(1, 2, 250), (1, 0, 0)]);
+    }
+
+    #[test]
+    fn failing_on_asymmetry_leaves_the_data_alone() {
+        let (pairs, counts) = resolved(AsymmetryRule::Fail);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!(counts.len(), 3);
+
+        let mut data = asymmetric();
+        data.compatibility.remove(1);
+        assert!(data.resolve_asymmetry(AsymmetryRule::Fail).is_empty());
+    }
+}
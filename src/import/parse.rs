@@ -0,0 +1,454 @@
+use crate::dataset::{CompatibilityCounts, CompatibilityDetails};
+use crate::import::{ImportData, NewCompatibility, NewInfusion, MAX_COUNT};
+use crate::infusion::InfusionType;
+use csv::{Position, ReaderBuilder, StringRecord};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Something wrong with the input file. Lines and columns start at 1.
+#[derive(Debug)]
+pub struct Problem {
+    pub severity: Severity,
+    pub line: usize,
+    pub column: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.column {
+            Some(column) => write!(f, "{}: line {}, column {}: {}", severity, self.line, column, self.message),
+            None => write!(f, "{}: line {}: {}", severity, self.line, self.message),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ParseResult {
+    pub data: ImportData,
+    pub problems: Vec<Problem>,
+    /// Infusions whose type wasn't given in the file, with the line they first appear on.
+    /// Their types have to come from the database.
+    pub untyped: Vec<(String, usize)>,
+}
+
+impl ParseResult {
+    pub fn has_errors(&self) -> bool {
+        self.problems.iter().any(|p| { p.severity == Severity::Error })
+    }
+
+    /// Take the types of untyped infusions from `known`, reporting any that aren't there
+    pub fn resolve_types(&mut self, known: impl Fn(&str) -> Option<InfusionType>) {
+        for (name, line) in std::mem::take(&mut self.untyped) {
+            match known(&name) {
+                Some(infusion_type) => self.data.infusions.push(NewInfusion { name, infusion_type }),
+                None => self.error(line, None, format!("no type given for new infusion: {}", name)),
+            }
+        }
+    }
+
+    fn error(&mut self, line: usize, column: Option<usize>, message: String) {
+        self.problems.push(Problem { severity: Severity::Error, line, column, message });
+    }
+
+    fn warning(&mut self, line: usize, column: Option<usize>, message: String) {
+        self.problems.push(Problem { severity: Severity::Warning, line, column, message });
+    }
+}
+
+/// Split the file into records, one per line, with RFC 4180 quoting.
+///
+/// A UTF-8 byte order mark and CRLF line endings are accepted since
+/// that's what Excel produces.
+fn read_records(contents: &str, delimiter: u8, result: &mut ParseResult) -> Vec<(usize, StringRecord)> {
+    let contents = contents.strip_prefix('\u{feff}').unwrap_or(contents);
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(delimiter)
+        .from_reader(contents.as_bytes());
+
+    // The reader's positions point before any blank lines it skipped, so its line numbers
+    // can be too low. Count the newlines before the first character of each record instead.
+    let bytes = contents.as_bytes();
+    let line_at = |position: Option<&Position>| {
+        let mut start = position.map(|p| { p.byte() as usize }).unwrap_or(0);
+        while start < bytes.len() && (bytes[start] == b'\r' || bytes[start] == b'\n') {
+            start += 1;
+        }
+        bytes[..start].iter().filter(|b| { **b == b'\n' }).count() + 1
+    };
+
+    let mut records = Vec::new();
+    for record in reader.records() {
+        match record {
+            Ok(record) => {
+                let line = line_at(record.position());
+                records.push((line, record));
+            },
+            Err(error) => {
+                let line = line_at(error.position());
+                result.error(line, None, format!("unreadable line: {}", error));
+            },
+        }
+    }
+
+    records
+}
+
+/// Parse the matrix format described in the README.
+///
+/// Parsing carries on after a problem is found so every problem in the file is reported.
+pub fn parse_matrix(contents: &str, delimiter: u8) -> ParseResult {
+    let mut result = ParseResult::default();
+    let records = read_records(contents, delimiter, &mut result);
+    let mut lines = records.iter().map(|(line, record)| { (*line, record.iter().collect::<Vec<&str>>()) });
+
+    let headers = match lines.next() {
+        Some((_, headers)) => headers,
+        None => {
+            result.error(1, None, "no data in file".to_string());
+            return result;
+        }
+    };
+    let header_items: Vec<&str> = headers.into_iter().skip(2).collect();
+    let mut header_names = HashSet::new();
+    for (i, name) in header_items.iter().enumerate() {
+        if !header_names.insert(*name) {
+            result.error(1, Some(i + 3), format!("duplicate name in header: {}", name));
+        }
+    }
+
+    let mut row_names = HashSet::new();
+    for (line_num, items) in lines {
+        if items.iter().all(|item| { item.is_empty() }) {
+            continue;
+        }
+        if items.len() < 2 {
+            result.error(line_num, None, "expected a name and a type".to_string());
+            continue;
+        }
+
+        let name = items[0];
+        if name.is_empty() {
+            result.error(line_num, Some(1), "missing name".to_string());
+            continue;
+        }
+        if !row_names.insert(name) {
+            result.error(line_num, Some(1), format!("duplicate row: {}", name));
+            continue;
+        }
+
+        let infusion_type = match items[1].parse().ok().and_then(InfusionType::from_id) {
+            Some(infusion_type) => infusion_type,
+            None => {
+                result.error(line_num, Some(2), format!("unknown type code: {:?}", items[1]));
+                continue;
+            }
+        };
+        if infusion_type == InfusionType::Drug && !header_names.contains(name) {
+            result.warning(line_num, Some(1), format!("drug is missing from the header: {}", name));
+        }
+
+        let cells = &items[2..];
+        if cells.len() != header_items.len() {
+            result.error(line_num, None, format!(
+                "expected {} compatibility values but found {}", header_items.len(), cells.len()
+            ));
+            continue;
+        }
+
+        result.data.infusions.push(NewInfusion { name: name.to_string(), infusion_type });
+        for (i, cell) in cells.iter().enumerate() {
+            if cell.is_empty() {
+                continue;
+            }
+
+            match parse_counts(cell) {
+                Ok(counts) => {
+                    result.data.compatibility.push(NewCompatibility {
+                        infusion_a: name.to_string(),
+                        infusion_b: header_items[i].to_string(),
+                        counts,
+                        details: None,
+                    });
+                },
+                Err(message) => result.error(line_num, Some(i + 3), message),
+            }
+        }
+    }
+
+    for (i, name) in header_items.iter().enumerate() {
+        if !row_names.contains(name) {
+            result.error(1, Some(i + 3), format!("no row for header name: {}", name));
+        }
+    }
+
+    result
+}
+
+/// Parse a `compatible:incompatible:mixed` cell
+fn parse_counts(cell: &str) -> Result<CompatibilityCounts, String> {
+    let parts: Vec<&str> = cell.split(':').collect();
+    if parts.len() != 3 {
+        return Err(format!("expected 3 values separated by ':' but found {}: {:?}", parts.len(), cell));
+    }
+
+    let mut values = [0; 3];
+    for (value, part) in values.iter_mut().zip(parts) {
+        *value = parse_count(part)?;
+    }
+
+    Ok(CompatibilityCounts { compatible: values[0], incompatible: values[1], mixed: values[2] })
+}
+
+/// Parse a single trial count
+fn parse_count(value: &str) -> Result<u32, String> {
+    let count = value.trim().parse().map_err(|_| { format!("not a number: {:?}", value) })?;
+    if count > MAX_COUNT {
+        return Err(format!("count {} is larger than the maximum of {}", count, MAX_COUNT));
+    }
+
+    Ok(count)
+}
+
+/// Parse a type code, either the `infusion_type` ID or its name
+fn parse_type(value: &str) -> Option<InfusionType> {
+    match value.trim().to_lowercase().as_str() {
+        "drug" => Some(InfusionType::Drug),
+        "solution" => Some(InfusionType::Solution),
+        other => other.parse().ok().and_then(InfusionType::from_id),
+    }
+}
+
+/// Columns of the long format. Names are matched case-insensitively and may come in any order.
+const LONG_REQUIRED_COLUMNS: [&str; 5] = ["drug_a", "drug_b", "compatible", "incompatible", "mixed"];
+const LONG_OPTIONAL_COLUMNS: [&str; 5] = ["type_a", "type_b", "concentration", "diluent", "reference"];
+const DETAIL_COLUMNS: [&str; 3] = ["concentration", "diluent", "reference"];
+/// Longest concentration, diluent or reference that fits in its `VARCHAR(255)` column
+const MAX_DETAIL_LEN: usize = 255;
+
+/// Layout of an import file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Square matrix with one row and one column per infusion
+    Matrix,
+    /// One row per pair of infusions
+    Long,
+}
+
+/// Guess the format from the header. Long format files start with a `drug_a` column.
+pub fn detect_format(contents: &str, delimiter: u8) -> Format {
+    let mut result = ParseResult::default();
+    let is_long = read_records(contents, delimiter, &mut result)
+        .first()
+        .and_then(|(_, header)| { header.get(0) })
+        .is_some_and(|first| { first.trim().eq_ignore_ascii_case("drug_a") });
+
+    if is_long { Format::Long } else { Format::Matrix }
+}
+
+/// Pick the delimiter for a file: the one given by the user (a single
+/// character or `tab`), or tab for .tsv files and ',' otherwise
+pub fn delimiter_for(path: &str, delimiter: Option<&str>) -> Result<u8, String> {
+    match delimiter {
+        None if path.ends_with(".tsv") || path.ends_with(".tab") => Ok(b'\t'),
+        None => Ok(b','),
+        Some("tab") | Some("\\t") | Some("\t") => Ok(b'\t'),
+        Some(d) if d.len() == 1 => Ok(d.as_bytes()[0]),
+        Some(d) => Err(format!("Invalid delimiter {:?}, expected a single ASCII character or 'tab'", d)),
+    }
+}
+
+/// Parse a file in the given format, or detect the format if it isn't given
+pub fn parse(contents: &str, format: Option<Format>, delimiter: u8) -> ParseResult {
+    match format.unwrap_or_else(|| { detect_format(contents, delimiter) }) {
+        Format::Matrix => parse_matrix(contents, delimiter),
+        Format::Long => parse_long(contents, delimiter),
+    }
+}
+
+/// Parse the long format, which has one row per pair of infusions.
+///
+/// Parsing carries on after a problem is found so every problem in the file is reported.
+pub fn parse_long(contents: &str, delimiter: u8) -> ParseResult {
+    let mut result = ParseResult::default();
+    let records = read_records(contents, delimiter, &mut result);
+    let mut lines = records.iter().map(|(line, record)| { (*line, record.iter().collect::<Vec<&str>>()) });
+
+    let headers = match lines.next() {
+        Some((_, headers)) => headers,
+        None => {
+            result.error(1, None, "no data in file".to_string());
+            return result;
+        }
+    };
+    let mut columns: HashMap<String, usize> = HashMap::new();
+    for (i, header) in headers.iter().enumerate() {
+        let name = header.trim().to_lowercase();
+        if !LONG_REQUIRED_COLUMNS.contains(&name.as_str()) && !LONG_OPTIONAL_COLUMNS.contains(&name.as_str()) {
+            result.warning(1, Some(i + 1), format!("unknown column ignored: {}", header));
+        } else if columns.insert(name, i).is_some() {
+            result.error(1, Some(i + 1), format!("duplicate column: {}", header));
+        }
+    }
+    for column in LONG_REQUIRED_COLUMNS {
+        if !columns.contains_key(column) {
+            result.error(1, None, format!("missing column: {}", column));
+        }
+    }
+    if result.has_errors() {
+        return result;
+    }
+    // Without any of these columns the details already stored for each pair are kept
+    let has_details = DETAIL_COLUMNS.iter().any(|c| { columns.contains_key(*c) });
+
+    let mut types: HashMap<String, (InfusionType, usize)> = HashMap::new();   // name -> (type, line it was set on)
+    let mut first_seen: Vec<(String, usize)> = Vec::new();
+    for (line_num, items) in lines {
+        if items.iter().all(|item| { item.is_empty() }) {
+            continue;
+        }
+        let cell = |column: &str| {
+            columns.get(column).map(|i| { (*i + 1, items.get(*i).copied().unwrap_or("").trim()) })
+        };
+        let errors_before = result.problems.len();
+
+        // A row with only drug_a (and maybe type_a) adds an infusion without any compatibility data
+        let infusion_only = ["drug_b", "compatible", "incompatible", "mixed"]
+            .iter()
+            .all(|column| { cell(column).unwrap().1.is_empty() });
+        let sides = if infusion_only { &[("drug_a", "type_a")][..] } else { &[("drug_a", "type_a"), ("drug_b", "type_b")][..] };
+
+        let mut names = Vec::new();
+        for (name_column, type_column) in sides {
+            let (column, name) = cell(name_column).unwrap();
+            if name.is_empty() {
+                result.error(line_num, Some(column), format!("missing {}", name_column));
+                continue;
+            }
+            names.push(name);
+            if !first_seen.iter().any(|(n, _)| { n == name }) {
+                first_seen.push((name.to_string(), line_num));
+            }
+
+            let type_cell = cell(type_column).filter(|(_, value)| { !value.is_empty() });
+            if let Some((column, value)) = type_cell {
+                match (parse_type(value), types.get(name)) {
+                    (None, _) => result.error(line_num, Some(column), format!("unknown type code: {:?}", value)),
+                    (Some(infusion_type), Some((existing, line))) if *existing != infusion_type => {
+                        result.error(line_num, Some(column), format!(
+                            "type of {} doesn't match the type given on line {}", name, line
+                        ));
+                    },
+                    (Some(infusion_type), _) => {
+                        types.entry(name.to_string()).or_insert((infusion_type, line_num));
+                    },
+                }
+            }
+        }
+        if infusion_only {
+            continue;
+        }
+        if names.len() == 2 && names[0] == names[1] {
+            result.error(line_num, None, format!("{} is paired with itself", names[0]));
+        }
+
+        let mut counts = [0; 3];
+        for (count, column_name) in counts.iter_mut().zip(["compatible", "incompatible", "mixed"]) {
+            let (column, value) = cell(column_name).unwrap();
+            match parse_count(value) {
+                Ok(value) => *count = value,
+                Err(message) => result.error(line_num, Some(column), message),
+            }
+        }
+
+        let mut details = [None, None, None];
+        for (detail, column_name) in details.iter_mut().zip(DETAIL_COLUMNS) {
+            let Some((column, value)) = cell(column_name) else { continue };
+            if value.chars().count() > MAX_DETAIL_LEN {
+                result.error(line_num, Some(column), format!("{} can't be longer than {} characters", column_name, MAX_DETAIL_LEN));
+            } else if !value.is_empty() {
+                *detail = Some(value.to_string());
+            }
+        }
+
+        if result.problems[errors_before..].iter().any(|p| { p.severity == Severity::Error }) {
+            continue;
+        }
+        let [concentration, diluent, reference] = details;
+        result.data.compatibility.push(NewCompatibility {
+            infusion_a: names[0].to_string(),
+            infusion_b: names[1].to_string(),
+            counts: CompatibilityCounts { compatible: counts[0], incompatible: counts[1], mixed: counts[2] },
+            details: has_details.then_some(CompatibilityDetails { concentration, diluent, reference }),
+        });
+    }
+
+    for (name, line) in first_seen {
+        match types.get(&name) {
+            Some((infusion_type, _)) => {
+                result.data.infusions.push(NewInfusion { name, infusion_type: *infusion_type });
+            },
+            None => result.untyped.push((name, line)),
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_format_reads_concentration_diluent_and_reference() {
+        let contents = "drug_a,type_a,drug_b,type_b,compatible,incompatible,mixed,concentration,diluent,reference\n\
+                        Heparin,1,Propofol,1,0,2,0,100 units/mL,D5W,Trissel 2023\n\
+                        Heparin,1,NS,2,1,0,0,,,\n";
+        let result = parse_long(contents, b',');
+        assert!(result.problems.is_empty(), "{:?}", result.problems);
+        assert_eq!(result.data.compatibility.len(), 2);
+        assert_eq!(result.data.compatibility[0].counts, CompatibilityCounts { compatible: 0, incompatible: 2, mixed: 0 });
+        let details = CompatibilityDetails {
+            concentration: Some("100 units/mL".to_string()),
+            diluent: Some("D5W".to_string()),
+            reference: Some("Trissel 2023".to_string()),
+        };
+        assert_eq!(result.data.compatibility[0].details, Some(details));
+        assert_eq!(result.data.compatibility[1].details, Some(CompatibilityDetails::default()));
+
+        let without_details = parse_long("drug_a,drug_b,compatible,incompatible,mixed\nHeparin,Propofol,0,2,0\n", b',');
+        assert_eq!(without_details.data.compatibility[0].details, None);
+
+        let too_long = format!("drug_a,drug_b,compatible,incompatible,mixed,reference\nHeparin,Propofol,0,2,0,{}\n", "x".repeat(256));
+        let result = parse_long(&too_long, b',');
+        assert!(result.problems.iter().any(|p| { p.message == "reference can't be longer than 255 characters" }));
+    }
+
+    #[test]
+    fn long_format_infusion_only_rows() {
+        let result = parse_long("drug_a,type_a,drug_b,compatible,incompatible,mixed\nNS,2,,,,\n", b',');
+        assert!(result.problems.is_empty(), "{:?}", result.problems);
+        assert_eq!(result.data.infusions.len(), 1);
+        assert_eq!(result.data.infusions[0].infusion_type, InfusionType::Solution);
+        assert!(result.data.compatibility.is_empty());
+    }
+
+    #[test]
+    fn long_format_needs_the_count_columns() {
+        let result = parse_long("drug_a,drug_b,compatible\nHeparin,Propofol,1\n", b',');
+        assert!(result.has_errors());
+        assert!(result.problems.iter().any(|p| { p.message == "missing column: mixed" }));
+    }
+}
@@ -1,13 +1,35 @@
-use std::collections::HashMap;
-use std::rc::Rc;
+use crate::schedule::Schedule;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum InfusionType {
     Drug,
     Solution
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl InfusionType {
+    /// Look up a type by its ID in the `infusion_type` table
+    pub fn from_id(id: u32) -> Option<Self> {
+        match id {
+            1 => Some(InfusionType::Drug),
+            2 => Some(InfusionType::Solution),
+            _ => None,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        match self {
+            InfusionType::Drug => 1,
+            InfusionType::Solution => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Compatibility {
     Compatible,
     Incompatible,
@@ -36,14 +58,51 @@ impl CompatibilityData {
             compatibility,
         }
     }
+
+    pub fn compatible(&self) -> u32 {
+        self.compatible
+    }
+
+    pub fn incompatible(&self) -> u32 {
+        self.incompatible
+    }
+
+    pub fn mixed(&self) -> u32 {
+        self.mixed
+    }
+
+    pub fn compatibility(&self) -> &Compatibility {
+        &self.compatibility
+    }
 }
 
-#[derive(Debug)]
+/// A rule that an infusion needs an IV of its own, e.g. blood products, TPN or propofol
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedicatedLine {
+    /// Shown to the user on the results page
+    pub reason: String,
+    /// Infusion.id of infusions that may still share the line
+    pub allowed_with: HashSet<u32>,
+}
+
+/// Infusions are `Send + Sync` so they can be shared between tasks and
+/// solved on a blocking thread. Compatibility data is shared between
+/// both infusions in a pair, so cloning an infusion is cheap.
+#[derive(Debug, Clone)]
 pub struct Infusion {
     id: u32,
     name: String,
     infusion_type: InfusionType,
-    compatibility: HashMap<u32, Rc<CompatibilityData>>, // Infusion.id -> CompatibilityData
+    compatibility: HashMap<u32, Arc<CompatibilityData>>, // Infusion.id -> CompatibilityData
+    /// `None` for continuous infusions
+    schedule: Option<Schedule>,
+    /// Infusion.id of incompatible infusions that can take turns on one IV with a flush in between
+    flushable: HashSet<u32>,
+    central_only: bool,
+    /// `None` if it can share an IV with any compatible infusion
+    dedicated_line: Option<DedicatedLine>,
+    /// Still solved with while it's running, but can't be added
+    retired: bool,
 }
 
 impl Infusion {
@@ -53,6 +112,11 @@ impl Infusion {
             name,
             infusion_type,
             compatibility: HashMap::new(),
+            schedule: None,
+            flushable: HashSet::new(),
+            central_only: false,
+            dedicated_line: None,
+            retired: false,
         }
     }
 
@@ -64,8 +128,65 @@ impl Infusion {
         &self.name
     }
 
-    pub fn add_compatibility_data(&mut self, other_id: u32, compat_data: &Rc<CompatibilityData>) {
-        self.compatibility.insert(other_id, Rc::clone(compat_data));
+    pub fn infusion_type(&self) -> &InfusionType {
+        &self.infusion_type
+    }
+
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    pub fn set_schedule(&mut self, schedule: Option<Schedule>) {
+        self.schedule = schedule;
+    }
+
+    /// Whether the data says the two can share an IV at the same time
+    pub fn is_compatible(&self, other_id: u32) -> bool {
+        self.get_compatible().any(|id| { *id == other_id })
+    }
+
+    /// Whether it may only be given through central access
+    pub fn central_only(&self) -> bool {
+        self.central_only
+    }
+
+    pub fn set_central_only(&mut self, central_only: bool) {
+        self.central_only = central_only;
+    }
+
+    pub fn dedicated_line(&self) -> Option<&DedicatedLine> {
+        self.dedicated_line.as_ref()
+    }
+
+    pub fn set_dedicated_line(&mut self, dedicated_line: Option<DedicatedLine>) {
+        self.dedicated_line = dedicated_line;
+    }
+
+    pub fn retired(&self) -> bool {
+        self.retired
+    }
+
+    pub fn set_retired(&mut self, retired: bool) {
+        self.retired = retired;
+    }
+
+    /// Whether its dedicated line rule, if any, lets the two share an IV.
+    /// The other infusion's rule has to allow it too.
+    pub fn allows_on_line(&self, other_id: u32) -> bool {
+        self.dedicated_line.as_ref().is_none_or(|line| { line.allowed_with.contains(&other_id) })
+    }
+
+    pub fn allow_flush(&mut self, other_id: u32) {
+        self.flushable.insert(other_id);
+    }
+
+    /// Whether the two can take turns on one IV if it's flushed in between
+    pub fn can_flush(&self, other_id: u32) -> bool {
+        self.flushable.contains(&other_id)
+    }
+
+    pub fn add_compatibility_data(&mut self, other_id: u32, compat_data: &Arc<CompatibilityData>) {
+        self.compatibility.insert(other_id, Arc::clone(compat_data));
     }
 
     pub fn get_compatible(&self) -> impl Iterator<Item = &u32> {
@@ -78,13 +199,13 @@ impl Infusion {
     }
 
     pub fn get_incompatible(&self, all_ids: &Vec<&u32>) -> Vec<u32> {
-        let compat: Vec<_> = self.get_compatible().map(|i| { i.clone() }).collect();
+        let compat: Vec<_> = self.get_compatible().copied().collect();
         let mut incompat = Vec::new();
 
         for id in all_ids {
             let id = *id;
             if !compat.contains(id) {
-                incompat.push(id.clone());
+                incompat.push(*id);
             }
         }
         
@@ -0,0 +1,16 @@
+pub mod access;
+pub mod audit;
+pub mod auth;
+pub mod config;
+pub mod dataset;
+pub mod db;
+pub mod diff;
+pub mod edit;
+mod exact;
+pub mod import;
+pub mod infusion;
+pub mod oidc;
+pub mod regimen;
+pub mod request;
+pub mod schedule;
+pub mod solver;
@@ -0,0 +1,406 @@
+//! Signing in to the web server, with a local account or through an OpenID Connect provider
+
+use crate::AppState;
+use iv_compatibility::auth::{self, Role};
+use iv_compatibility::config::AuthConfig;
+use iv_compatibility::db::StoreError;
+use iv_compatibility::oidc::{LoginRequest, OidcError, Provider};
+
+use axum::async_trait;
+use axum::extract::{FromRequestParts, Query, State};
+use axum::http::header::{AUTHORIZATION, COOKIE, HOST, ORIGIN, REFERER, SET_COOKIE};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, Method, StatusCode};
+use axum::response::{AppendHeaders, Html, IntoResponse, Redirect, Response};
+use axum::Form;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const SESSION_COOKIE: &str = "iv_compat_session";
+/// Ties a sign-in sent to the OpenID provider to the browser that started it
+const STATE_COOKIE: &str = "iv_compat_oidc_state";
+
+/// How long someone stays signed in
+const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long someone has to finish signing in at the OpenID provider
+const OIDC_LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Someone who has signed in
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    /// Who to attribute their changes to. For OpenID sign-ins this is `OidcUser::id`, never a local username.
+    pub username: String,
+    /// What to call them on the pages
+    pub name: String,
+    pub role: Role,
+}
+
+struct Session {
+    user: User,
+    expires: Instant,
+}
+
+struct PendingLogin {
+    request: LoginRequest,
+    next: String,
+    expires: Instant,
+}
+
+/// Sessions are kept in memory, so restarting the server signs everyone out
+pub struct Logins {
+    config: AuthConfig,
+    sessions: Mutex<HashMap<String, Session>>,  // session token -> session
+    provider: Mutex<Option<Provider>>,          // discovered on the first OpenID sign-in
+    pending: Mutex<HashMap<String, PendingLogin>>,  // OpenID state -> sign-in waiting for the provider
+}
+
+impl Logins {
+    pub fn new(config: AuthConfig) -> Self {
+        Self {
+            config,
+            sessions: Mutex::new(HashMap::new()),
+            provider: Mutex::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn start_session(&self, user: User) -> String {
+        let token = auth::random_token();
+        let now = Instant::now();
+
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, session| { session.expires > now });
+        sessions.insert(token.clone(), Session { user, expires: now + SESSION_LIFETIME });
+
+        token
+    }
+
+    fn session(&self, token: &str) -> Option<User> {
+        let sessions = self.sessions.lock().unwrap();
+
+        sessions.get(token).filter(|session| { session.expires > Instant::now() }).map(|session| { session.user.clone() })
+    }
+
+    /// Sign someone out everywhere, e.g. after their account is removed or their role changes
+    pub fn end_sessions_for(&self, username: &str) {
+        self.sessions.lock().unwrap().retain(|_, session| { session.user.username != username });
+    }
+
+    fn cookie(&self, name: &str, value: &str, max_age: Duration) -> String {
+        let secure = if self.config.secure_cookies { "; Secure" } else { "" };
+
+        format!("{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}{}", name, value, max_age.as_secs(), secure)
+    }
+}
+
+/// Anyone allowed to use the calculator. `None` if they haven't signed in and anonymous use is allowed.
+pub struct Viewer(pub Option<User>);
+
+/// Anyone who has signed in, whatever their role
+pub struct SignedIn(pub User);
+
+/// Someone allowed to change the compatibility data: a pharmacist or an admin
+pub struct Pharmacist(pub User);
+
+/// Someone allowed to manage accounts
+pub struct Admin(pub User);
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Viewer {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        match current_user(parts, state).await? {
+            Some(user) => Ok(Viewer(Some(user))),
+            None if state.logins.config.allow_anonymous => Ok(Viewer(None)),
+            None => Err(sign_in_required(parts)),
+        }
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for SignedIn {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        require_role(parts, state, Role::Viewer).await.map(SignedIn)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Pharmacist {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        require_role(parts, state, Role::Pharmacist).await.map(Pharmacist)
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<Arc<AppState>> for Admin {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &Arc<AppState>) -> Result<Self, Self::Rejection> {
+        require_role(parts, state, Role::Admin).await.map(Admin)
+    }
+}
+
+async fn require_role(parts: &Parts, state: &Arc<AppState>, role: Role) -> Result<User, Response> {
+    match current_user(parts, state).await? {
+        Some(user) if user.role >= role => Ok(user),
+        Some(user) => {
+            let message = format!("This page needs a {} account. You're signed in as {} ({}).", role, user.username, user.role);
+            if parts.headers.contains_key(AUTHORIZATION) {
+                return Err((StatusCode::FORBIDDEN, message + "\n").into_response());
+            }
+            Err(login_page(state, StatusCode::FORBIDDEN, &message, parts.uri.path()))
+        },
+        None => Err(sign_in_required(parts)),
+    }
+}
+
+/// Whoever the session cookie or basic auth credentials belong to.
+///
+/// Basic auth is for scripts, e.g. `curl -u NAME -X POST .../reload`.
+async fn current_user(parts: &Parts, state: &Arc<AppState>) -> Result<Option<User>, Response> {
+    // Basic auth credentials and (for top-level navigation) the session cookie are sent
+    // with requests from other sites, so changes must come from our own pages
+    if parts.method != Method::GET && !same_origin(&parts.headers) {
+        return Err((StatusCode::FORBIDDEN, "Changes can only be made from this site's pages\n").into_response());
+    }
+
+    if let Some(user) = cookie(&parts.headers, SESSION_COOKIE).and_then(|token| { state.logins.session(token) }) {
+        return Ok(Some(user));
+    }
+
+    let credentials = parts.headers
+        .get(AUTHORIZATION)
+        .and_then(|value| { value.to_str().ok()?.strip_prefix("Basic ") })
+        .and_then(|encoded| { BASE64.decode(encoded).ok() })
+        .and_then(|decoded| { String::from_utf8(decoded).ok() });
+    let Some((username, password)) = credentials.as_deref().and_then(|c| { c.split_once(':') }) else {
+        return Ok(None);
+    };
+
+    match check_password(state, username, password).await {
+        Ok(Some(user)) => Ok(Some(user)),
+        Ok(None) => Err((StatusCode::UNAUTHORIZED, "Wrong username or password\n").into_response()),
+        Err(error) => Err((StatusCode::SERVICE_UNAVAILABLE, format!("Couldn't check the login: {}\n", error)).into_response()),
+    }
+}
+
+async fn check_password(state: &Arc<AppState>, username: &str, password: &str) -> Result<Option<User>, StoreError> {
+    let store = state.store.clone();
+    let (username, password) = (username.to_string(), password.to_string());
+
+    tokio::task::spawn_blocking(move || {
+        let account = store.account(&username)?;
+        Ok(account
+            .filter(|account| { auth::verify_password(&password, &account.password_hash) })
+            .map(|account| { User { name: account.username.clone(), username: account.username, role: account.role } }))
+    })
+    .await
+    .expect("Login task panicked")
+}
+
+/// Browsers are sent to the sign-in page, anything else just gets an error
+fn sign_in_required(parts: &Parts) -> Response {
+    if parts.method == Method::GET && !parts.headers.contains_key(AUTHORIZATION) {
+        let next = parts.uri.path_and_query().map(|pq| { pq.as_str() }).unwrap_or("/");
+        let next = url::form_urlencoded::byte_serialize(next.as_bytes()).collect::<String>();
+        return Redirect::to(&format!("/login?next={}", next)).into_response();
+    }
+
+    (StatusCode::UNAUTHORIZED, "Please sign in\n").into_response()
+}
+
+/// True unless a browser says the request came from a page on another site
+fn same_origin(headers: &HeaderMap) -> bool {
+    let host = headers.get(HOST).and_then(|host| { host.to_str().ok() });
+    let origin = headers.get(ORIGIN).or_else(|| { headers.get(REFERER) }).and_then(|origin| { origin.to_str().ok() });
+
+    match origin {
+        None => true,
+        Some(origin) => {
+            let origin_host = origin.split_once("://").and_then(|(_, rest)| { rest.split('/').next() });
+            origin_host.is_some() && origin_host == host
+        },
+    }
+}
+
+fn cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| { value.to_str().ok() })
+        .flat_map(|value| { value.split(';') })
+        .filter_map(|pair| { pair.trim().split_once('=') })
+        .find(|(key, _)| { *key == name })
+        .map(|(_, value)| { value })
+}
+
+/// Only redirect to paths on this site after signing in
+fn local_path(next: Option<&str>) -> &str {
+    match next {
+        Some(next) if next.starts_with('/') && !next.starts_with("//") && !next.contains('\\') => next,
+        _ => "/",
+    }
+}
+
+fn login_page(state: &AppState, status: StatusCode, message: &str, next: &str) -> Response {
+    let oidc_name = state.logins.config.oidc.as_ref().map(|oidc| { oidc.display_name.clone() });
+    let template = state.env.get_template("login").expect("Template not found!");
+    let rendered = template
+        .render(context!(message => message, next => next, oidc_name => oidc_name))
+        .expect("Unable to render login page");
+
+    (status, Html(rendered)).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NextParams {
+    next: Option<String>,
+}
+
+pub async fn handler_login_page(state: State<Arc<AppState>>, Query(params): Query<NextParams>) -> Response {
+    login_page(&state, StatusCode::OK, "", local_path(params.next.as_deref()))
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    next: Option<String>,
+}
+
+pub async fn handler_login(state: State<Arc<AppState>>, headers: HeaderMap, Form(form): Form<LoginForm>) -> Response {
+    let next = local_path(form.next.as_deref());
+    if !same_origin(&headers) {
+        return (StatusCode::FORBIDDEN, "Sign in from this site's sign-in page\n").into_response();
+    }
+
+    match check_password(&state, &form.username, &form.password).await {
+        Ok(Some(user)) => {
+            println!("{} signed in ({})", user.username, user.role);
+            let token = state.logins.start_session(user);
+            let cookie = state.logins.cookie(SESSION_COOKIE, &token, SESSION_LIFETIME);
+            (AppendHeaders([(SET_COOKIE, cookie)]), Redirect::to(next)).into_response()
+        },
+        Ok(None) => login_page(&state, StatusCode::UNAUTHORIZED, "Wrong username or password", next),
+        Err(error) => login_page(&state, StatusCode::SERVICE_UNAVAILABLE, &format!("Couldn't check the login: {}", error), next),
+    }
+}
+
+pub async fn handler_logout(state: State<Arc<AppState>>, headers: HeaderMap) -> Response {
+    if let Some(token) = cookie(&headers, SESSION_COOKIE) {
+        state.logins.sessions.lock().unwrap().remove(token);
+    }
+    let cookie = state.logins.cookie(SESSION_COOKIE, "", Duration::ZERO);
+
+    (AppendHeaders([(SET_COOKIE, cookie)]), Redirect::to("/login")).into_response()
+}
+
+/// Send the browser to the OpenID provider
+pub async fn handler_oidc_login(state: State<Arc<AppState>>, Query(params): Query<NextParams>) -> Response {
+    let next = local_path(params.next.as_deref()).to_string();
+    let Some(config) = state.logins.config.oidc.clone() else {
+        return login_page(&state, StatusCode::NOT_FOUND, "Single sign-on isn't set up", &next);
+    };
+
+    let cached = state.logins.provider.lock().unwrap().clone();
+    let provider = match cached {
+        Some(provider) => provider,
+        None => {
+            let discovered = tokio::task::spawn_blocking(move || { Provider::discover(&config) })
+                .await
+                .expect("OpenID discovery task panicked");
+            match discovered {
+                Ok(provider) => {
+                    *state.logins.provider.lock().unwrap() = Some(provider.clone());
+                    provider
+                },
+                Err(error) => return oidc_failed(&state, error, &next),
+            }
+        },
+    };
+
+    let config = state.logins.config.oidc.as_ref().unwrap();
+    let request = match provider.login_request(config) {
+        Ok(request) => request,
+        Err(error) => return oidc_failed(&state, error, &next),
+    };
+    let url = request.url.clone();
+    let cookie = state.logins.cookie(STATE_COOKIE, &request.state, OIDC_LOGIN_TIMEOUT);
+
+    let now = Instant::now();
+    let mut pending = state.logins.pending.lock().unwrap();
+    pending.retain(|_, login| { login.expires > now });
+    pending.insert(request.state.clone(), PendingLogin { request, next, expires: now + OIDC_LOGIN_TIMEOUT });
+
+    (AppendHeaders([(SET_COOKIE, cookie)]), Redirect::to(&url)).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CallbackParams {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+}
+
+/// Where the OpenID provider sends the browser back to
+pub async fn handler_oidc_callback(state: State<Arc<AppState>>, headers: HeaderMap,
+                                   Query(params): Query<CallbackParams>) -> Response {
+    let login = params.state
+        .as_deref()
+        .filter(|login_state| { cookie(&headers, STATE_COOKIE) == Some(*login_state) })
+        .and_then(|login_state| { state.logins.pending.lock().unwrap().remove(login_state) })
+        .filter(|login| { login.expires > Instant::now() });
+    let Some(login) = login else {
+        return login_page(&state, StatusCode::BAD_REQUEST, "The sign-in took too long or was started elsewhere. Please try again.", "/");
+    };
+    if let Some(error) = params.error {
+        let reason = params.error_description.unwrap_or(error);
+        return oidc_failed(&state, OidcError::Rejected(reason), &login.next);
+    }
+    let Some(code) = params.code else {
+        return oidc_failed(&state, OidcError::Rejected("no code was sent back".to_string()), &login.next);
+    };
+
+    let (Some(provider), Some(config)) = (state.logins.provider.lock().unwrap().clone(), state.logins.config.oidc.clone()) else {
+        return login_page(&state, StatusCode::NOT_FOUND, "Single sign-on isn't set up", "/");
+    };
+    let request = login.request.clone();
+    let result = tokio::task::spawn_blocking(move || { provider.finish_login(&config, &request, &code) })
+        .await
+        .expect("OpenID sign-in task panicked");
+
+    match result {
+        Ok(oidc_user) => {
+            let user = User { username: oidc_user.id, name: oidc_user.name, role: oidc_user.role };
+            println!("{} ({}) signed in through OpenID ({})", user.name, user.username, user.role);
+            let token = state.logins.start_session(user);
+            let cookies = [
+                (SET_COOKIE, state.logins.cookie(SESSION_COOKIE, &token, SESSION_LIFETIME)),
+                (SET_COOKIE, state.logins.cookie(STATE_COOKIE, "", Duration::ZERO)),
+            ];
+            (AppendHeaders(cookies), Redirect::to(&login.next)).into_response()
+        },
+        Err(error) => oidc_failed(&state, error, &login.next),
+    }
+}
+
+fn oidc_failed(state: &AppState, error: OidcError, next: &str) -> Response {
+    eprintln!("OpenID sign-in failed: {}", error);
+    let status = match error {
+        OidcError::Unreachable { .. } | OidcError::BadResponse { .. } => StatusCode::BAD_GATEWAY,
+        OidcError::Rejected(_) | OidcError::InvalidToken(_) => StatusCode::UNAUTHORIZED,
+    };
+
+    login_page(state, status, &error.to_string(), next)
+}
@@ -1,27 +1,40 @@
+mod admin;
+mod login;
+mod regimens;
+
 use itertools::Itertools;
-use solver::CompatibilityProblem;
+use clap::Parser;
+use iv_compatibility::access;
+use iv_compatibility::audit::{AnchorFile, AuditInfusion, SolveOutcome, SolveRecord};
+use iv_compatibility::config::Config;
+use iv_compatibility::dataset::Dataset;
+use iv_compatibility::db::{self, CompatibilityStore, StoreError};
+use iv_compatibility::infusion::Infusion;
+use iv_compatibility::request::{RequestError, SolveRequest};
+use iv_compatibility::solver::{self, CompatibilityProblem, ConflictError, Solution, SolveBudget};
+use login::{Logins, Pharmacist, Viewer};
 use serde::{Deserialize, Serialize};
 
-use mysql::{Pool, PooledConn};
-use mysql::prelude::*;
+use arc_swap::ArcSwap;
 
 use axum::http::StatusCode;
 use axum::response::{Html, Response, IntoResponse};
 use axum::body::Body;
-use axum::{Router, routing::get };
+use axum::{Router, routing::{get, post} };
 use axum::extract::State;
 use axum_extra::extract::Query;
 
 use tower_http::services::ServeDir;
-use minijinja::{Environment, context};
-use std::collections::HashSet;
-use std::sync::Arc;
+use minijinja::{AutoEscape, Environment, context};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-pub mod db;
-pub mod infusion;
-pub mod solver;
+/// How long a single request may spend looking for an optimal solution
+const SOLVE_TIME_LIMIT: Duration = Duration::from_secs(2);
 
-async fn handler_home(state: State<Arc<AppState>>) -> Result<Html<String>, StatusCode> {
+async fn handler_home(state: State<Arc<AppState>>, Viewer(user): Viewer) -> Result<Html<String>, StatusCode> {
     #[derive(Serialize, Deserialize, Debug)]
     pub struct SimpleInfusion {
         id: u32,
@@ -31,17 +44,18 @@ async fn handler_home(state: State<Arc<AppState>>) -> Result<Html<String>, Statu
 
     let template = state.env.get_template("home").expect("Template not found!");
 
-    let mut conn = state.pool.get_conn().expect("Failed to connect to DB!");
-    let infusions: Vec<SimpleInfusion> = conn
-        .query_map(
-            "SELECT id, name, type FROM infusion ORDER BY id",
-            |(id, name, inf_type)| {
-                SimpleInfusion { id, name, inf_type }
+    let dataset = state.dataset.load();
+    let infusions: Vec<SimpleInfusion> = dataset
+        .infusions()
+        .filter(|inf| { !inf.retired })
+        .map(|inf| {
+            SimpleInfusion { id: inf.id, name: inf.name.clone(), inf_type: inf.infusion_type.id() }
         })
-        .expect("[DB] Couldn't read from infusion table!");
+        .collect();
 
+    let version = dataset.version().map(|version| { (version.id, version.to_string()) });
     let rendered = template
-        .render(context!(inf => infusions))
+        .render(context!(inf => infusions, version => version, user => user))
         .expect("Unable to render home page");
 
     Ok(Html(rendered))
@@ -52,82 +66,585 @@ struct ResultParams {
     num_ivs: u32,
     ivs: String,
     #[serde(default)] // allow for add= not in query string
-    add: Vec<u32>
+    add: Vec<u32>,
+    /// Dataset version to solve with, defaulting to the active one
+    version: Option<u32>,
+    /// JSON object of infusion ID -> schedule, for intermittent infusions
+    schedules: Option<String>,
+    /// JSON list of the patient's lines. See `access::Device`.
+    devices: Option<String>,
 }
 
-async fn handler_results(state: State<Arc<AppState>>, params: Query<ResultParams>) -> Response {
-    let mut conn = state.pool.get_conn().expect("Failed to connect to DB!");
-    let ivs: Vec<HashSet<u32>>= serde_json::from_str(&params.ivs).expect("Invalid JSON data: ivs");
+async fn handler_results(state: State<Arc<AppState>>, Viewer(user): Viewer, params: Query<ResultParams>) -> Response {
+    let signed_in = user.is_some();
+    let mut record = solve_record(user.map(|user| { user.username }), None);
+    record.add = params.add.iter().map(|id| { AuditInfusion { id: *id, name: None } }).collect();
+    record.dataset_version = params.version;
+    let response = solve(&state, &params, signed_in, &mut record).await;
+
+    log_solve(&state, record, response).await
+}
 
-    let mut problem = load_problem(&mut conn, &ivs, &params.add);
+/// A record of a solve that hasn't run yet
+fn solve_record(username: Option<String>, regimen: Option<u32>) -> SolveRecord {
+    SolveRecord {
+        username,
+        dataset_version: None,
+        ivs: Vec::new(),
+        add: Vec::new(),
+        remove: Vec::new(),
+        schedules: BTreeMap::new(),
+        devices: Vec::new(),
+        strategy: solver::STRATEGY.to_string(),
+        time_limit_ms: SOLVE_TIME_LIMIT.as_millis() as u64,
+        outcome: SolveOutcome::Failed { reason: String::new() },
+        regimen,
+    }
+}
 
-    match problem.solve(ivs) {
-        Ok(results) => {
-            let template = state.env.get_template("results").expect("Template not found!");
+fn audit_infusion(dataset: &Dataset, id: u32) -> AuditInfusion {
+    AuditInfusion { id, name: dataset.infusion(id).map(|record| { record.name.clone() }) }
+}
 
-            let ivs_param = results
-                .into_iter()
-                .map(|(iv_id, iv_infusions)| {
-                    (iv_id, iv_infusions.into_iter().map(|inf| { inf.name() }).collect_vec())
+/// Write `record` to the audit log, and only pass `response` on if that worked
+async fn log_solve(state: &AppState, record: SolveRecord, response: Response) -> Response {
+    // Nobody should be shown a result that can't be reviewed later, even if that means solves stop
+    // while the database is down. The record goes to the server log instead, so it isn't lost.
+    let store = state.store.clone();
+    let (result, record) = tokio::task::spawn_blocking(move || { (store.log_solve(&record), record) })
+        .await
+        .expect("Audit log task panicked");
+    match result {
+        Ok(entry) => {
+            // The entry is safely in the database, so a result isn't held back for this
+            if let Err(error) = state.anchor.record(&entry) {
+                eprintln!("Couldn't update the audit anchor file: {}", error);
+            }
+            response
+        },
+        Err(error) => {
+            let json = serde_json::to_string(&record).expect("Failed to serialize solve record");
+            eprintln!("Couldn't write to the audit log, so the result wasn't shown: {}. Unlogged solve: {}", error, json);
+            (StatusCode::SERVICE_UNAVAILABLE, "The result couldn't be recorded in the audit log, so it isn't shown. Please try again.\n")
+                .into_response()
+        },
+    }
+}
+
+/// Handle a `/results` request, filling in `record` with what was asked and answered
+async fn solve(state: &Arc<AppState>, params: &ResultParams, signed_in: bool, record: &mut SolveRecord) -> Response {
+    let request = match SolveRequest::parse(&params.ivs, params.add.clone(), params.schedules.as_deref(), params.devices.as_deref()) {
+        Ok(request) => request,
+        Err(error) => {
+            record.outcome = SolveOutcome::Failed { reason: format!("{} (ivs={})", error, params.ivs) };
+            return invalid_request_response(state, error);
+        },
+    };
+    record.ivs = request.ivs
+        .iter()
+        .map(|iv| { iv.iter().sorted().map(|id| { AuditInfusion { id: *id, name: None } }).collect() })
+        .collect();
+
+    let dataset = match params.version {
+        None => state.dataset.load_full(),
+        Some(id) => match pinned_dataset(state, id).await {
+            Ok(Some(dataset)) => dataset,
+            Ok(None) => return invalid(state, record, RequestError::UnknownVersion(id)),
+            Err(error) => {
+                let reason = format!("Couldn't load dataset version {}: {}", id, error);
+                record.outcome = SolveOutcome::Failed { reason: reason.clone() };
+                return (StatusCode::SERVICE_UNAVAILABLE, reason + "\n").into_response();
+            },
+        },
+    };
+    let version = dataset.version().map(|version| { version.to_string() });
+    let lines = request.lines();
+    let devices = request.devices.clone();
+
+    let solution = match run_solve(&dataset, request, record).await {
+        Ok(Ok(solution)) => solution,
+        Ok(Err(conflict)) => return conflict_response(state, &conflict),
+        Err(error) => return invalid_request_response(state, error),
+    };
+
+    let layout = solution.layout();
+    let ivs_param = layout
+        .iter()
+        .enumerate()
+        .map(|(iv_id, iv_infusions)| {
+            let infusions = iv_infusions
+                .iter()
+                .map(|inf| {
+                    let schedule = inf.schedule().map(|schedule| { schedule.to_string() });
+                    let dedicated_line = inf.dedicated_line().map(|line| { line.reason.as_str() });
+                    (inf.name(), schedule, inf.central_only(), dedicated_line)
                 })
-                .sorted_by_key(|iv| { iv.0 })
                 .collect_vec();
-            
-            let rendered = template
-                .render(context!(ivs => ivs_param))
-                .expect("Unable to render results page");
+            // IVs past the patient's lines need a new one
+            let line = match lines.get(iv_id) {
+                Some(line) => Some(line.to_string()),
+                None if lines.is_empty() => None,
+                None if iv_infusions.iter().any(|inf| { inf.central_only() }) => Some("New central line".to_string()),
+                None => Some("New line".to_string()),
+            };
+            (iv_id, line, infusions)
+        })
+        .collect_vec();
+    let flushes = solver::flushes(&layout)
+        .into_iter()
+        .map(|(iv, a, b)| { (iv + 1, a.name(), b.name()) })
+        .collect_vec();
+    let time_shared = solver::time_shared(&layout)
+        .into_iter()
+        .map(|(iv, a, b)| { (iv + 1, a.name(), b.name()) })
+        .collect_vec();
+    // Infusion IDs in each IV, for saving the layout as a regimen
+    let layout_ids = layout
+        .iter()
+        .map(|iv_infusions| { iv_infusions.iter().map(|inf| { inf.id() }).sorted().collect_vec() })
+        .collect_vec();
+    let central_only = |id| { dataset.infusion(id).is_some_and(|infusion| { infusion.central_only }) };
+    let devices = access::with_new_lines(&devices, &layout_ids, central_only);
 
-            Html(rendered).into_response()
-        },
+    let template = state.env.get_template("results").expect("Template not found!");
+    let rendered = template
+        .render(context!(
+            ivs => ivs_param,
+            optimal => solution.optimal,
+            version => version,
+            signed_in => signed_in,
+            flushes => flushes,
+            time_shared => time_shared,
+            layout => serde_json::to_string(&layout_ids).expect("Failed to serialize layout"),
+            schedules => serde_json::to_string(&record.schedules).expect("Failed to serialize schedules"),
+            devices => serde_json::to_string(&devices).expect("Failed to serialize devices"),
+        ))
+        .expect("Unable to render results page");
+
+    Html(rendered).into_response()
+}
+
+/// Solve `request` with `dataset`, filling in `record` with what was asked and answered.
+///
+/// The outer error is for requests that can't be solved at all, the inner one for
+/// current IVs that already hold incompatible infusions.
+async fn run_solve(dataset: &Dataset, request: SolveRequest, record: &mut SolveRecord)
+                   -> Result<Result<Solution, ConflictError>, RequestError> {
+    record.dataset_version = dataset.version().map(|version| { version.id });
+    record.ivs = request.ivs.iter().map(|iv| { iv.iter().sorted().map(|id| { audit_infusion(dataset, *id) }).collect() }).collect();
+    record.add = request.add.iter().map(|id| { audit_infusion(dataset, *id) }).collect();
+    record.schedules = request.schedules.clone();
+    record.devices = request.devices.clone();
+
+    let mut problem = match load_problem(dataset, &request) {
+        Ok(problem) => problem,
         Err(error) => {
-            let template = state.env.get_template("results_error").expect("Template not found");
-            let rendered = template
-                .render(context!(iv => error.iv+1, conflicting_items => error.conflicting_items))
-                .expect("Unable to render error page");
+            record.outcome = SolveOutcome::Failed { reason: error.to_string() };
+            return Err(error);
+        },
+    };
+
+    // Solve on a blocking thread so long searches don't stall the runtime.
+    // If the client goes away, the guard is dropped and the search stops.
+    let budget = SolveBudget::new(SOLVE_TIME_LIMIT);
+    let _cancel_guard = CancelOnDrop(budget.clone());
+    let ivs = request.ivs;
+    let result = tokio::task::spawn_blocking(move || { problem.solve(ivs, &budget) })
+        .await
+        .expect("Solver task panicked");
 
-            let response = Response::builder()
-                .status(StatusCode::UNPROCESSABLE_ENTITY)
-                .body(Body::from(rendered))
-                .expect("Failed to build error response");
+    record.outcome = match &result {
+        Ok(solution) => SolveOutcome::Solved {
+            ivs: solution.layout().iter().map(|iv| { iv.iter().map(|inf| { inf.name().to_string() }).collect() }).collect(),
+            optimal: solution.optimal,
+            flushes: pair_names(solver::flushes(&solution.layout())),
+            time_shared: pair_names(solver::time_shared(&solution.layout())),
+        },
+        Err(error) => SolveOutcome::Conflict {
+            iv: error.iv,
+            conflicting_items: error.conflicting_items.clone(),
+            dedicated_line: error.dedicated_line.clone(),
+        },
+    };
 
-            response
+    Ok(result)
+}
+
+/// Names of the infusions in each pair from `solver::flushes` or `solver::time_shared`
+fn pair_names(pairs: Vec<(u32, &Infusion, &Infusion)>) -> Vec<(String, String)> {
+    pairs.into_iter().map(|(_, a, b)| { (a.name().to_string(), b.name().to_string()) }).collect()
+}
+
+fn conflict_response(state: &AppState, error: &ConflictError) -> Response {
+    let template = state.env.get_template("results_error").expect("Template not found");
+    let rendered = template
+        .render(context!(iv => error.iv+1, conflicting_items => error.conflicting_items, dedicated_line => error.dedicated_line))
+        .expect("Unable to render error page");
+
+    Response::builder()
+        .status(StatusCode::UNPROCESSABLE_ENTITY)
+        .body(Body::from(rendered))
+        .expect("Failed to build error response")
+}
+
+fn invalid(state: &AppState, record: &mut SolveRecord, error: RequestError) -> Response {
+    record.outcome = SolveOutcome::Failed { reason: error.to_string() };
+    invalid_request_response(state, error)
+}
+
+/// Cancels a solve when dropped
+struct CancelOnDrop(SolveBudget);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+fn invalid_request_response(state: &AppState, error: RequestError) -> Response {
+    let template = state.env.get_template("results_invalid").expect("Template not found");
+    let rendered = match error {
+        RequestError::Malformed(reason) => template.render(context!(malformed => reason)),
+        RequestError::InvalidInfusions { unknown_ids, duplicates } => {
+            template.render(context!(unknown_ids => unknown_ids, duplicates => duplicates))
         }
+        RequestError::UnknownVersion(id) => template.render(context!(unknown_version => id)),
+        RequestError::Retired(names) => template.render(context!(retired => names)),
+        RequestError::NeedsCentralAccess { infusion, line } => template.render(context!(needs_central => (infusion, line))),
+    }.expect("Unable to render error page");
+
+    Response::builder()
+        .status(StatusCode::BAD_REQUEST)
+        .body(Body::from(rendered))
+        .expect("Failed to build error response")
+}
+
+fn load_problem(dataset: &Dataset, request: &SolveRequest) -> Result<CompatibilityProblem, RequestError> {
+    let mut infusions = dataset.load_infusions(&request.infusion_ids());
+    request.validate(&infusions)?;
+    request.apply_schedules(&mut infusions);
+
+    let mut problem = CompatibilityProblem::new(infusions);
+    problem.set_lines(&request.lines());
+
+    Ok(problem)
+}
+
+/// Load an older dataset version, keeping it in memory for later requests.
+///
+/// Returns `None` if there's no such version.
+async fn pinned_dataset(state: &Arc<AppState>, id: u32) -> Result<Option<Arc<Dataset>>, StoreError> {
+    let current = state.dataset.load_full();
+    if current.version().map(|version| { version.id }) == Some(id) {
+        return Ok(Some(current));
+    }
+    if let Some(dataset) = state.pinned.lock().unwrap().get(&id) {
+        return Ok(Some(dataset.clone()));
+    }
+
+    // Versions never change once created, so they can be cached forever
+    let store = state.store.clone();
+    let dataset = tokio::task::spawn_blocking(move || { store.load_version(id) })
+        .await
+        .expect("Dataset loading task panicked")?
+        .map(Arc::new);
+    if let Some(dataset) = &dataset {
+        state.pinned.lock().unwrap().insert(id, dataset.clone());
+    }
+
+    Ok(dataset)
+}
+
+async fn handler_reload(state: State<Arc<AppState>>, Pharmacist(user): Pharmacist) -> (StatusCode, String) {
+    match reload_dataset(&state).await {
+        Ok(count) => {
+            println!("{} reloaded {} infusions", user.username, count);
+            (StatusCode::OK, format!("Reloaded {} infusions\n", count))
+        },
+        Err(error) => (StatusCode::SERVICE_UNAVAILABLE, format!("Reload failed, keeping previous data: {}\n", error)),
     }
 }
 
-fn load_problem(conn: &mut PooledConn, iv_data: &Vec<HashSet<u32>>, additional: &Vec<u32>) -> CompatibilityProblem {
-    let infusion_ids = iv_data.iter().flatten().chain(additional.iter()).collect();
-    let infusions = db::load_infusions(conn, infusion_ids);
+/// Load a fresh copy of the dataset from the DB and swap it in.
+///
+/// If loading fails the previous dataset stays in use.
+async fn reload_dataset(state: &Arc<AppState>) -> Result<usize, StoreError> {
+    let store = state.store.clone();
+    let dataset = tokio::task::spawn_blocking(move || { store.load_dataset() })
+    .await
+    .expect("Dataset loading task panicked")?;
+
+    let count = dataset.infusions().count();
+    state.dataset.store(Arc::new(dataset));
 
-    CompatibilityProblem::new(infusions)
+    Ok(count)
+}
+
+/// Reload the dataset whenever the process receives SIGHUP
+#[cfg(unix)]
+async fn reload_on_sighup(state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangup = signal(SignalKind::hangup()).expect("Failed to register SIGHUP handler");
+    while hangup.recv().await.is_some() {
+        match reload_dataset(&state).await {
+            Ok(count) => println!("Reloaded {} infusions", count),
+            Err(error) => eprintln!("Reload failed, keeping previous data: {}", error),
+        }
+    }
+}
+
+/// Web server for the IV compatibility calculator
+#[derive(Parser, Debug)]
+struct Args {
+    /// Config file to read the settings from
+    #[arg(long)]
+    config: Option<String>,
+    /// Address to listen on, overriding the config file
+    #[arg(long)]
+    bind: Option<SocketAddr>,
 }
 
 struct AppState {
     env: Environment<'static>,
-    pool: Pool,
+    store: Arc<dyn CompatibilityStore>,
+    dataset: ArcSwap<Dataset>,
+    pinned: Mutex<HashMap<u32, Arc<Dataset>>>,  // version ID -> older dataset versions requested so far
+    logins: Logins,
+    /// The newest audit log entry, kept outside the database
+    anchor: AnchorFile,
 }
 
-#[tokio::main]
-async fn main() {
-    let pool = db::connect_db("./conf.d/db.conf");
-
+fn templates() -> Environment<'static> {
     let mut env = Environment::new();
+    // Labels, names and usernames are all typed in by people, so nothing is trusted as HTML
+    env.set_auto_escape_callback(|_| { AutoEscape::Html });
     env.add_template("home", include_str!("../templates/home.jinja")).expect("Failed to load template");
     env.add_template("results", include_str!("../templates/results.jinja")).expect("Failed to load template");
     env.add_template("results_error", include_str!("../templates/results_error.jinja")).expect("Failed to load template");
+    env.add_template("results_invalid", include_str!("../templates/results_invalid.jinja")).expect("Failed to load template");
+    env.add_template("admin", include_str!("../templates/admin.jinja")).expect("Failed to load template");
+    env.add_template("admin_compatibility", include_str!("../templates/admin_compatibility.jinja")).expect("Failed to load template");
+    env.add_template("admin_error", include_str!("../templates/admin_error.jinja")).expect("Failed to load template");
+    env.add_template("admin_accounts", include_str!("../templates/admin_accounts.jinja")).expect("Failed to load template");
+    env.add_template("admin_audit", include_str!("../templates/admin_audit.jinja")).expect("Failed to load template");
+    env.add_template("login", include_str!("../templates/login.jinja")).expect("Failed to load template");
+    env.add_template("regimens", include_str!("../templates/regimens.jinja")).expect("Failed to load template");
+    env.add_template("regimen", include_str!("../templates/regimen.jinja")).expect("Failed to load template");
+    env.add_template("regimen_error", include_str!("../templates/regimen_error.jinja")).expect("Failed to load template");
+
+    env
+}
 
-    let app_state = Arc::new(AppState { env, pool });
-    let app = Router::new()
+fn router(state: Arc<AppState>) -> Router {
+    Router::new()
         .route("/", get(handler_home))
         .route("/results", get(handler_results))
+        .route("/reload", post(handler_reload))
+        .route("/admin", get(admin::handler_admin))
+        .route("/admin/infusions", post(admin::handler_add_infusion))
+        .route("/admin/infusions/:id/rename", post(admin::handler_rename_infusion))
+        .route("/admin/infusions/:id/retire", post(admin::handler_retire_infusion))
+        .route("/admin/infusions/:id/access", post(admin::handler_set_central_only))
+        .route("/admin/compatibility", get(admin::handler_compatibility).post(admin::handler_edit_compatibility))
+        .route("/admin/compatibility/flush", post(admin::handler_set_flushable))
+        .route("/admin/dedicated", post(admin::handler_set_dedicated_line))
+        .route("/admin/dedicated/partners", post(admin::handler_set_line_partner))
+        .route("/admin/audit", get(admin::handler_audit))
+        .route("/admin/accounts", get(admin::handler_accounts).post(admin::handler_add_account))
+        .route("/admin/accounts/:username/role", post(admin::handler_set_role))
+        .route("/admin/accounts/:username/remove", post(admin::handler_remove_account))
+        .route("/regimens", get(regimens::handler_regimens).post(regimens::handler_save_regimen))
+        .route("/regimens/:id", get(regimens::handler_regimen).post(regimens::handler_update_regimen))
+        .route("/regimens/:id/add", get(regimens::handler_add_to_regimen))
+        .route("/regimens/:id/stop", get(regimens::handler_stop_in_regimen))
+        .route("/regimens/:id/remove", post(regimens::handler_remove_regimen))
+        .route("/login", get(login::handler_login_page).post(login::handler_login))
+        .route("/logout", post(login::handler_logout))
+        .route("/login/oidc", get(login::handler_oidc_login))
+        .route("/login/oidc/callback", get(login::handler_oidc_callback))
         .nest_service("/static", ServeDir::new("static"))
-        .with_state(app_state);
+        .with_state(state)
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    let mut config = Config::load_or_exit(args.config.as_deref());
+    if let Some(bind) = args.bind {
+        config.bind = bind;
+    }
+    let store = db::connect(&config.db).expect("[DB] Couldn't connect to the database!");
+    if let Err(error) = store.check_schema() {
+        eprintln!("[DB] {}", error);
+        std::process::exit(1);
+    }
+
+    let dataset = store.load_dataset().expect("[DB] Couldn't load compatibility data!");
+    let anchor = AnchorFile::open(&config.audit_anchor).unwrap_or_else(|error| {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    });
+
+    let app_state = Arc::new(AppState {
+        env: templates(),
+        store,
+        dataset: ArcSwap::from_pointee(dataset),
+        pinned: Mutex::new(HashMap::new()),
+        logins: Logins::new(config.auth),
+        anchor,
+    });
 
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:8080")
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(app_state.clone()));
+
+    let app = router(app_state);
+
+    let listener = tokio::net::TcpListener::bind(config.bind)
         .await
         .expect("Failed to start TCP listener");
 
-    println!("Web server started");
+    println!("Web server started on {}", config.bind);
 
     axum::serve(listener, app).await.unwrap();
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::prelude::{Engine, BASE64_STANDARD};
+    use iv_compatibility::audit::AuditQuery;
+    use iv_compatibility::auth::{self, Account, Role};
+    use iv_compatibility::config::AuthConfig;
+    use iv_compatibility::db::MemoryStore;
+    use iv_compatibility::edit::Edit;
+    use iv_compatibility::import::{parse, ImportMode, ImportSource};
+    use std::future::IntoFuture;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    const MATRIX: &str = ",,Heparin,Norepinephrine,Propofol\n\
+                          Heparin,1,,1:0:0,0:1:0\n\
+                          Norepinephrine,1,1:0:0,,0:1:0\n\
+                          Propofol,1,0:1:0,0:1:0,\n";
+
+    /// Serve the app on a free local port, backed by an in-memory store
+    async fn serve(allow_anonymous: bool) -> (String, Arc<AppState>) {
+        let store = MemoryStore::new();
+        let parsed = parse::parse_matrix(MATRIX, b',');
+        store.import(&parsed.data, ImportMode::Replace, &ImportSource::new("test", MATRIX.as_bytes())).unwrap();
+        let account = Account { username: "alice".to_string(), password_hash: auth::hash_password("s3cret"), role: Role::Pharmacist };
+        store.add_account(&account).unwrap();
+        let dataset = store.load_dataset().unwrap();
+        static SERVED: AtomicU32 = AtomicU32::new(0);
+        let anchor = std::env::temp_dir()
+            .join(format!("iv-compat-test-{}-{}.anchor", std::process::id(), SERVED.fetch_add(1, Ordering::Relaxed)));
+        let _ = std::fs::remove_file(&anchor);
+
+        let state = Arc::new(AppState {
+            env: templates(),
+            store: Arc::new(store),
+            dataset: ArcSwap::from_pointee(dataset),
+            pinned: Mutex::new(HashMap::new()),
+            logins: Logins::new(AuthConfig { allow_anonymous, secure_cookies: false, oidc: None }),
+            anchor: AnchorFile::open(anchor).unwrap(),
+        });
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, router(state.clone())).into_future());
+
+        (format!("http://{}", address), state)
+    }
+
+    /// GET `url`, signed in as alice if `signed_in`, returning the status and body
+    async fn get(url: String, signed_in: bool) -> (u16, String) {
+        tokio::task::spawn_blocking(move || {
+            let agent = ureq::AgentBuilder::new().redirects(0).build();
+            let mut request = agent.get(&url);
+            if signed_in {
+                request = request.set("Authorization", &format!("Basic {}", BASE64_STANDARD.encode("alice:s3cret")));
+            }
+            match request.call() {
+                Ok(response) => (response.status(), response.into_string().unwrap()),
+                Err(ureq::Error::Status(status, response)) => (status, response.into_string().unwrap()),
+                Err(error) => panic!("{}", error),
+            }
+        }).await.unwrap()
+    }
+
+    fn id(state: &AppState, name: &str) -> u32 {
+        state.dataset.load().find_by_name(name).unwrap().id
+    }
+
+    fn audit_entries(state: &AppState) -> Vec<SolveRecord> {
+        let query = AuditQuery { from: None, to: None, infusion_id: None, limit: 10 };
+        state.store.query_audit(&query).unwrap().into_iter().map(|entry| { entry.record }).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn results_are_solved_and_audited() {
+        let (base, state) = serve(true).await;
+        let (heparin, propofol) = (id(&state, "Heparin"), id(&state, "Propofol"));
+
+        let url = format!("{}/results?num_ivs=2&ivs=[[],[]]&add={}&add={}", base, heparin, propofol);
+        let (status, body) = get(url, true).await;
+        assert_eq!(status, 200, "{}", body);
+        assert!(body.contains("Heparin") && body.contains("Propofol"));
+
+        let entries = audit_entries(&state);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].username.as_deref(), Some("alice"));
+        assert!(matches!(entries[0].outcome, SolveOutcome::Solved { .. }), "{:?}", entries[0].outcome);
+
+        let anchor = state.anchor.latest().unwrap();
+        assert_eq!(anchor.id, 1);
+        let verification = state.store.verify_audit_log(Some(&anchor)).unwrap();
+        assert_eq!((verification.first_broken, verification.missing_anchor), (None, None));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invalid_requests_are_refused_and_audited() {
+        let (base, state) = serve(true).await;
+
+        let (status, _) = get(format!("{}/results?num_ivs=1&ivs=[[]]&add=999", base), false).await;
+        assert_eq!(status, 400);
+        let (status, _) = get(format!("{}/results?num_ivs=1&ivs=not-json", base), false).await;
+        assert_eq!(status, 400);
+
+        let entries = audit_entries(&state);
+        assert_eq!(entries.len(), 2);
+        assert!(entries.iter().all(|entry| { entry.username.is_none() && matches!(entry.outcome, SolveOutcome::Failed { .. }) }));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retired_infusions_stay_in_regimens_but_cant_be_added() {
+        let (base, state) = serve(true).await;
+        let (heparin, norepinephrine) = (id(&state, "Heparin"), id(&state, "Norepinephrine"));
+        let regimen = state.store.add_regimen("Bed 4", &[vec![heparin, norepinephrine], vec![]], &BTreeMap::new(), &[], "alice").unwrap();
+        state.store.edit(&Edit::RetireInfusion { id: norepinephrine, retired: true }, "alice", "test").unwrap();
+        state.dataset.store(Arc::new(state.store.load_dataset().unwrap()));
+
+        let (status, body) = get(format!("{}/regimens/{}/stop?stop={}", base, regimen.id, norepinephrine), true).await;
+        assert_eq!(status, 200, "{}", body);
+        let (status, body) = get(format!("{}/regimens/{}/stop?stop={}", base, regimen.id, heparin), true).await;
+        assert_eq!(status, 200, "{}", body);
+        let (status, body) = get(format!("{}/results?num_ivs=2&ivs=[[{}],[]]&add={}", base, norepinephrine, heparin), true).await;
+        assert_eq!(status, 200, "{}", body);
+
+        let (status, body) = get(format!("{}/results?num_ivs=1&ivs=[[{}]]&add={}", base, heparin, norepinephrine), true).await;
+        assert_eq!(status, 400);
+        assert!(body.contains("Retired drugs/solutions"), "{}", body);
+        let (status, _) = get(format!("{}/regimens/{}/add?add={}", base, regimen.id, norepinephrine), true).await;
+        assert_eq!(status, 400);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn signing_in_can_be_required() {
+        let (base, state) = serve(false).await;
+        let url = format!("{}/results?num_ivs=1&ivs=[[]]&add={}", base, id(&state, "Heparin"));
+
+        let (status, _) = get(url.clone(), false).await;
+        assert_eq!(status, 303);
+        let (status, _) = get(url, true).await;
+        assert_eq!(status, 200);
+        assert_eq!(audit_entries(&state).len(), 1);
+    }
+}
@@ -1,12 +1,12 @@
-CREATE DATABASE IF NOT EXISTS iv_compatibility;
-
-USE iv_compatibility;
-
 CREATE TABLE IF NOT EXISTS infusion_type (
     id TINYINT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
     type VARCHAR(255) NOT NULL
 );
 
+INSERT IGNORE INTO infusion_type (id, type) VALUES
+    (1, 'Drug'),
+    (2, 'Solution');
+
 CREATE TABLE IF NOT EXISTS infusion (
     id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
     name VARCHAR(255) NOT NULL UNIQUE,
@@ -23,4 +23,4 @@ CREATE TABLE IF NOT EXISTS infusion_compatibility (
     PRIMARY KEY (infusion_a, infusion_b),
     FOREIGN KEY (infusion_a) REFERENCES infusion(id),
     FOREIGN KEY (infusion_b) REFERENCES infusion(id)
-);
\ No newline at end of file
+);
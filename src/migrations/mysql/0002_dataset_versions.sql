@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS dataset_version (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    name VARCHAR(255) NOT NULL,
+    created_at BIGINT NOT NULL,
+    source_hash CHAR(64) NOT NULL,
+    active BOOLEAN NOT NULL DEFAULT FALSE,
+    snapshot LONGTEXT NOT NULL
+);
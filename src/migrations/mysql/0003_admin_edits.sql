@@ -0,0 +1,17 @@
+ALTER TABLE infusion ADD COLUMN retired BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE TABLE IF NOT EXISTS account (
+    username VARCHAR(255) PRIMARY KEY,
+    password_hash VARCHAR(255) NOT NULL,
+    created_at BIGINT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS change_log (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    changed_at BIGINT NOT NULL,
+    username VARCHAR(255) NOT NULL,
+    description TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    dataset_version INT UNSIGNED NOT NULL,
+    FOREIGN KEY (dataset_version) REFERENCES dataset_version(id)
+);
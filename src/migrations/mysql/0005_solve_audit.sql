@@ -0,0 +1,33 @@
+CREATE TABLE IF NOT EXISTS solve_audit (
+    id BIGINT UNSIGNED PRIMARY KEY,
+    logged_at BIGINT NOT NULL,
+    record MEDIUMTEXT NOT NULL,
+    prev_hash CHAR(64) NOT NULL,
+    hash CHAR(64) NOT NULL,
+    INDEX solve_audit_logged_at (logged_at)
+);
+
+-- Every infusion in each request, for looking entries up by drug
+CREATE TABLE IF NOT EXISTS solve_audit_infusion (
+    infusion_id INT UNSIGNED NOT NULL,
+    audit_id BIGINT UNSIGNED NOT NULL,
+    PRIMARY KEY (infusion_id, audit_id),
+    FOREIGN KEY (audit_id) REFERENCES solve_audit(id)
+);
+
+-- The audit log is append-only. Triggers are dropped first so a half-applied migration can be run again.
+DROP TRIGGER IF EXISTS solve_audit_no_update;
+CREATE TRIGGER solve_audit_no_update BEFORE UPDATE ON solve_audit
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'the solve audit log is append-only';
+
+DROP TRIGGER IF EXISTS solve_audit_no_delete;
+CREATE TRIGGER solve_audit_no_delete BEFORE DELETE ON solve_audit
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'the solve audit log is append-only';
+
+DROP TRIGGER IF EXISTS solve_audit_infusion_no_update;
+CREATE TRIGGER solve_audit_infusion_no_update BEFORE UPDATE ON solve_audit_infusion
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'the solve audit log is append-only';
+
+DROP TRIGGER IF EXISTS solve_audit_infusion_no_delete;
+CREATE TRIGGER solve_audit_infusion_no_delete BEFORE DELETE ON solve_audit_infusion
+FOR EACH ROW SIGNAL SQLSTATE '45000' SET MESSAGE_TEXT = 'the solve audit log is append-only';
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS regimen (
+    id INT UNSIGNED PRIMARY KEY AUTO_INCREMENT,
+    label VARCHAR(64) NOT NULL UNIQUE,
+    ivs TEXT NOT NULL,  -- JSON list of infusion ID lists, one per IV
+    revision INT UNSIGNED NOT NULL,
+    updated_at BIGINT NOT NULL,
+    updated_by VARCHAR(255) NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Pairs of incompatible infusions that can take turns on one IV with a saline flush in between
+CREATE TABLE IF NOT EXISTS flushable_pair (
+    infusion_a INT UNSIGNED NOT NULL,   -- lower ID
+    infusion_b INT UNSIGNED NOT NULL,
+    PRIMARY KEY (infusion_a, infusion_b),
+    FOREIGN KEY (infusion_a) REFERENCES infusion(id),
+    FOREIGN KEY (infusion_b) REFERENCES infusion(id)
+);
@@ -0,0 +1,11 @@
+-- Why an infusion needs a line to itself, e.g. blood products or TPN. NULL if it can share.
+ALTER TABLE infusion ADD COLUMN dedicated_line VARCHAR(255) NULL;
+
+-- Infusions that may still share a dedicated line with the infusion it belongs to
+CREATE TABLE IF NOT EXISTS dedicated_line_partner (
+    infusion INT UNSIGNED NOT NULL,     -- has the dedicated line
+    partner INT UNSIGNED NOT NULL,
+    PRIMARY KEY (infusion, partner),
+    FOREIGN KEY (infusion) REFERENCES infusion(id),
+    FOREIGN KEY (partner) REFERENCES infusion(id)
+);
@@ -0,0 +1,3 @@
+-- The patient's lines in a regimen, as a JSON list of devices. IV number `i` goes through the `i`th lumen.
+-- NULL if the lines weren't given.
+ALTER TABLE regimen ADD COLUMN devices TEXT;
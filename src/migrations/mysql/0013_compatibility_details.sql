@@ -0,0 +1,4 @@
+-- Where a pair's trial counts come from, from the long import format. NULL if not given.
+ALTER TABLE infusion_compatibility ADD COLUMN concentration VARCHAR(255) NULL;
+ALTER TABLE infusion_compatibility ADD COLUMN diluent VARCHAR(255) NULL;
+ALTER TABLE infusion_compatibility ADD COLUMN reference VARCHAR(255) NULL;
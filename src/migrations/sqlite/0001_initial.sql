@@ -0,0 +1,26 @@
+CREATE TABLE IF NOT EXISTS infusion_type (
+    id INTEGER PRIMARY KEY,
+    type TEXT NOT NULL
+);
+
+INSERT OR IGNORE INTO infusion_type (id, type) VALUES
+    (1, 'Drug'),
+    (2, 'Solution');
+
+CREATE TABLE IF NOT EXISTS infusion (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL UNIQUE,
+    type INTEGER NOT NULL,
+    FOREIGN KEY (type) REFERENCES infusion_type(id)
+);
+
+CREATE TABLE IF NOT EXISTS infusion_compatibility (
+    infusion_a INTEGER NOT NULL,
+    infusion_b INTEGER NOT NULL,
+    compatible_results INTEGER NOT NULL CHECK (compatible_results BETWEEN 0 AND 255),
+    incompatible_results INTEGER NOT NULL CHECK (incompatible_results BETWEEN 0 AND 255),
+    mixed_results INTEGER NOT NULL CHECK (mixed_results BETWEEN 0 AND 255),
+    PRIMARY KEY (infusion_a, infusion_b),
+    FOREIGN KEY (infusion_a) REFERENCES infusion(id),
+    FOREIGN KEY (infusion_b) REFERENCES infusion(id)
+);
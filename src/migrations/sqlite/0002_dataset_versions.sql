@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS dataset_version (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    source_hash TEXT NOT NULL,
+    active INTEGER NOT NULL DEFAULT 0,
+    snapshot TEXT NOT NULL
+);
@@ -0,0 +1,17 @@
+ALTER TABLE infusion ADD COLUMN retired INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS account (
+    username TEXT PRIMARY KEY,
+    password_hash TEXT NOT NULL,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS change_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    changed_at INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    description TEXT NOT NULL,
+    reason TEXT NOT NULL,
+    dataset_version INTEGER NOT NULL,
+    FOREIGN KEY (dataset_version) REFERENCES dataset_version(id)
+);
@@ -0,0 +1,2 @@
+-- Accounts made before roles existed could edit everything, so they become admins
+ALTER TABLE account ADD COLUMN role TEXT NOT NULL DEFAULT 'admin';
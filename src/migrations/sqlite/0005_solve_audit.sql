@@ -0,0 +1,30 @@
+CREATE TABLE IF NOT EXISTS solve_audit (
+    id INTEGER PRIMARY KEY,
+    logged_at INTEGER NOT NULL,
+    record TEXT NOT NULL,
+    prev_hash TEXT NOT NULL,
+    hash TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS solve_audit_logged_at ON solve_audit (logged_at);
+
+-- Every infusion in each request, for looking entries up by drug
+CREATE TABLE IF NOT EXISTS solve_audit_infusion (
+    infusion_id INTEGER NOT NULL,
+    audit_id INTEGER NOT NULL,
+    PRIMARY KEY (infusion_id, audit_id),
+    FOREIGN KEY (audit_id) REFERENCES solve_audit(id)
+);
+
+-- The audit log is append-only
+CREATE TRIGGER IF NOT EXISTS solve_audit_no_update BEFORE UPDATE ON solve_audit
+BEGIN SELECT RAISE(ABORT, 'the solve audit log is append-only'); END;
+
+CREATE TRIGGER IF NOT EXISTS solve_audit_no_delete BEFORE DELETE ON solve_audit
+BEGIN SELECT RAISE(ABORT, 'the solve audit log is append-only'); END;
+
+CREATE TRIGGER IF NOT EXISTS solve_audit_infusion_no_update BEFORE UPDATE ON solve_audit_infusion
+BEGIN SELECT RAISE(ABORT, 'the solve audit log is append-only'); END;
+
+CREATE TRIGGER IF NOT EXISTS solve_audit_infusion_no_delete BEFORE DELETE ON solve_audit_infusion
+BEGIN SELECT RAISE(ABORT, 'the solve audit log is append-only'); END;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS regimen (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    label TEXT NOT NULL UNIQUE,
+    ivs TEXT NOT NULL,  -- JSON list of infusion ID lists, one per IV
+    revision INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    updated_by TEXT NOT NULL
+);
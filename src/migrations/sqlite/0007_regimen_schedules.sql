@@ -0,0 +1,2 @@
+-- Intermittent infusions in a regimen, as a JSON object of infusion ID -> schedule. NULL if there are none.
+ALTER TABLE regimen ADD COLUMN schedules TEXT;
@@ -0,0 +1,2 @@
+-- Infusions that may only be given through central access, e.g. vasopressors, vesicants and TPN
+ALTER TABLE infusion ADD COLUMN central_only INTEGER NOT NULL DEFAULT 0;
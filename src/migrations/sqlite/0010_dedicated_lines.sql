@@ -0,0 +1,11 @@
+-- Why an infusion needs a line to itself, e.g. blood products or TPN. NULL if it can share.
+ALTER TABLE infusion ADD COLUMN dedicated_line TEXT;
+
+-- Infusions that may still share a dedicated line with the infusion it belongs to
+CREATE TABLE IF NOT EXISTS dedicated_line_partner (
+    infusion INTEGER NOT NULL,  -- has the dedicated line
+    partner INTEGER NOT NULL,
+    PRIMARY KEY (infusion, partner),
+    FOREIGN KEY (infusion) REFERENCES infusion(id),
+    FOREIGN KEY (partner) REFERENCES infusion(id)
+);
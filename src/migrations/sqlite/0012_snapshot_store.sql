@@ -0,0 +1,8 @@
+-- Each distinct snapshot is stored once, by its SHA-256 hash, so versions with the same data share it.
+-- Versions from before this migration keep their snapshot in dataset_version.snapshot, which is empty for newer ones.
+CREATE TABLE IF NOT EXISTS dataset_snapshot (
+    hash TEXT PRIMARY KEY,
+    snapshot TEXT NOT NULL
+);
+
+ALTER TABLE dataset_version ADD COLUMN snapshot_hash TEXT REFERENCES dataset_snapshot(hash);
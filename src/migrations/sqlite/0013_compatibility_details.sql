@@ -0,0 +1,4 @@
+-- Where a pair's trial counts come from, from the long import format. NULL if not given.
+ALTER TABLE infusion_compatibility ADD COLUMN concentration TEXT;
+ALTER TABLE infusion_compatibility ADD COLUMN diluent TEXT;
+ALTER TABLE infusion_compatibility ADD COLUMN reference TEXT;
@@ -0,0 +1,274 @@
+//! Sign-in through an OpenID Connect provider, using the authorization code flow with PKCE
+
+mod http;
+mod jwks;
+
+use crate::auth::{self, Role};
+use jwks::Jwks;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{error, fmt};
+use url::Url;
+
+/// How to reach the provider, and how to map its users onto roles.
+/// There's no `Debug` impl, so the client secret can't end up in logs.
+#[derive(Clone)]
+pub struct OidcConfig {
+    /// e.g. `https://login.example.org/realms/hospital`. Only `https://` issuers can be used.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// This server's `/login/oidc/callback` URL, as registered with the provider
+    pub redirect_url: String,
+    /// Claim in the ID token listing the user's roles. Dots reach into nested objects, e.g. `realm_access.roles`.
+    pub role_claim: String,
+    /// Role for users whose token doesn't list any of viewer, pharmacist or admin
+    pub default_role: Role,
+    /// Shown on the sign-in button
+    pub display_name: String,
+}
+
+#[derive(Debug)]
+pub enum OidcError {
+    /// The provider couldn't be reached
+    Unreachable { url: String, reason: String },
+    /// The provider sent something that isn't what the spec says it should be
+    BadResponse { url: String, reason: String },
+    /// The provider refused to hand out a token, e.g. because the code expired
+    Rejected(String),
+    /// The ID token isn't for us, has expired or doesn't match the sign-in attempt
+    InvalidToken(String),
+}
+
+impl fmt::Display for OidcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OidcError::Unreachable { url, reason } => write!(f, "Couldn't reach {}: {}", url, reason),
+            OidcError::BadResponse { url, reason } => write!(f, "Unexpected response from {}: {}", url, reason),
+            OidcError::Rejected(reason) => write!(f, "The sign-in was rejected: {}", reason),
+            OidcError::InvalidToken(reason) => write!(f, "Invalid ID token: {}", reason),
+        }
+    }
+}
+
+impl error::Error for OidcError {}
+
+/// The endpoints a provider publishes in its discovery document
+#[derive(Debug, Clone, Deserialize)]
+pub struct Provider {
+    pub issuer: String,
+    pub authorization_endpoint: String,
+    pub token_endpoint: String,
+    /// Where the keys that sign ID tokens are published
+    pub jwks_uri: String,
+}
+
+/// A sign-in that has been sent to the provider. Keep it until the provider sends the user back.
+#[derive(Debug, Clone)]
+pub struct LoginRequest {
+    /// Where to send the user's browser
+    pub url: String,
+    /// Comes back with the user, to match them up with this request
+    pub state: String,
+    nonce: String,
+    verifier: String,
+}
+
+/// Someone the provider has vouched for
+#[derive(Debug, Clone)]
+pub struct OidcUser {
+    /// `sub@issuer`, which stays the same for them and can't be a local username, since those can't contain `:`
+    pub id: String,
+    /// What the provider calls them, for display only
+    pub name: String,
+    pub role: Role,
+}
+
+impl Provider {
+    /// Fetch the provider's discovery document. This blocks on network IO.
+    pub fn discover(config: &OidcConfig) -> Result<Self, OidcError> {
+        let issuer = config.issuer.trim_end_matches('/');
+        let url = parse_url(&format!("{}/.well-known/openid-configuration", issuer))?;
+        let provider: Provider = get_json(&url, http::request("GET", &url, None, &[])?)?;
+
+        if provider.issuer.trim_end_matches('/') != issuer {
+            return Err(OidcError::BadResponse {
+                url: url.to_string(),
+                reason: format!("issuer is {}, expected {}", provider.issuer, config.issuer),
+            });
+        }
+
+        Ok(provider)
+    }
+
+    pub fn login_request(&self, config: &OidcConfig) -> Result<LoginRequest, OidcError> {
+        let (state, nonce, verifier) = (auth::random_token(), auth::random_token(), auth::random_token());
+        let challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()));
+
+        let mut url = parse_url(&self.authorization_endpoint)?;
+        url.query_pairs_mut()
+            .append_pair("response_type", "code")
+            .append_pair("client_id", &config.client_id)
+            .append_pair("redirect_uri", &config.redirect_url)
+            .append_pair("scope", "openid profile email")
+            .append_pair("state", &state)
+            .append_pair("nonce", &nonce)
+            .append_pair("code_challenge", &challenge)
+            .append_pair("code_challenge_method", "S256");
+
+        Ok(LoginRequest { url: url.to_string(), state, nonce, verifier })
+    }
+
+    /// Trade the code the provider sent back for an ID token, and work out who signed in.
+    /// This blocks on network IO.
+    pub fn finish_login(&self, config: &OidcConfig, request: &LoginRequest, code: &str) -> Result<OidcUser, OidcError> {
+        let url = parse_url(&self.token_endpoint)?;
+        let body = url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("grant_type", "authorization_code")
+            .append_pair("code", code)
+            .append_pair("redirect_uri", &config.redirect_url)
+            .append_pair("client_id", &config.client_id)
+            .append_pair("client_secret", &config.client_secret)
+            .append_pair("code_verifier", &request.verifier)
+            .finish();
+        let response = http::request("POST", &url, Some("application/x-www-form-urlencoded"), body.as_bytes())?;
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            id_token: Option<String>,
+            error: Option<String>,
+            error_description: Option<String>,
+        }
+        let token: TokenResponse = serde_json::from_slice(&response.body)
+            .map_err(|e| { OidcError::BadResponse { url: url.to_string(), reason: e.to_string() } })?;
+        if let Some(error) = token.error {
+            return Err(OidcError::Rejected(token.error_description.unwrap_or(error)));
+        }
+        let id_token = token.id_token.ok_or_else(|| {
+            OidcError::BadResponse { url: url.to_string(), reason: format!("no id_token (HTTP {})", response.status) }
+        })?;
+
+        // Keys can be rotated at any time, so they're fetched for every sign-in
+        let jwks_url = parse_url(&self.jwks_uri)?;
+        let jwks: Jwks = get_json(&jwks_url, http::request("GET", &jwks_url, None, &[])?)?;
+
+        self.check_id_token(config, request, &jwks, &id_token)
+    }
+
+    /// Check the token's signature against the provider's keys, then its claims
+    fn check_id_token(&self, config: &OidcConfig, request: &LoginRequest, jwks: &Jwks, id_token: &str)
+                      -> Result<OidcUser, OidcError> {
+        let invalid = |reason: &str| { OidcError::InvalidToken(reason.to_string()) };
+
+        let claims = jwks.verify(id_token).map_err(|reason| { invalid(&reason) })?;
+
+        if claims["iss"].as_str() != Some(&self.issuer) {
+            return Err(invalid("issued by someone else"));
+        }
+        let audience_matches = match &claims["aud"] {
+            Value::String(aud) => *aud == config.client_id,
+            Value::Array(auds) => auds.iter().any(|aud| { aud.as_str() == Some(&config.client_id) }),
+            _ => false,
+        };
+        if !audience_matches {
+            return Err(invalid("issued for another client"));
+        }
+        if claims["exp"].as_i64().is_none_or(|exp| { exp <= chrono::Utc::now().timestamp() }) {
+            return Err(invalid("expired"));
+        }
+        if claims["nonce"].as_str() != Some(&request.nonce) {
+            return Err(invalid("nonce doesn't match the sign-in attempt"));
+        }
+
+        user_from_claims(&self.issuer, &claims, config).ok_or_else(|| { invalid("no subject") })
+    }
+}
+
+/// Who the checked claims are about. Names can change or be reused, so they're only shown, never used to tell people apart.
+fn user_from_claims(issuer: &str, claims: &Value, config: &OidcConfig) -> Option<OidcUser> {
+    let subject = claims["sub"].as_str().filter(|sub| { !sub.is_empty() })?;
+    let name = ["preferred_username", "email"]
+        .iter()
+        .find_map(|claim| { claims[claim].as_str() })
+        .unwrap_or(subject);
+
+    Some(OidcUser { id: format!("{}@{}", subject, issuer), name: name.to_string(), role: role_from_claims(claims, config) })
+}
+
+/// The highest role named in the role claim, or the default role
+fn role_from_claims(claims: &Value, config: &OidcConfig) -> Role {
+    let value = config.role_claim.split('.').fold(claims, |value, key| { &value[key] });
+    let names = match value {
+        Value::String(name) => vec![name.as_str()],
+        Value::Array(names) => names.iter().filter_map(|name| { name.as_str() }).collect(),
+        _ => Vec::new(),
+    };
+
+    names.into_iter().filter_map(|name| { name.parse().ok() }).max().unwrap_or(config.default_role)
+}
+
+fn parse_url(url: &str) -> Result<Url, OidcError> {
+    Url::parse(url).map_err(|e| { OidcError::BadResponse { url: url.to_string(), reason: e.to_string() } })
+}
+
+fn get_json<T: for<'de> Deserialize<'de>>(url: &Url, response: http::Response) -> Result<T, OidcError> {
+    if response.status != 200 {
+        return Err(OidcError::BadResponse { url: url.to_string(), reason: format!("HTTP {}", response.status) });
+    }
+
+    serde_json::from_slice(&response.body).map_err(|e| { OidcError::BadResponse { url: url.to_string(), reason: e.to_string() } })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn config(role_claim: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: "https://login.example.org".to_string(),
+            client_id: "iv".to_string(),
+            client_secret: String::new(),
+            redirect_url: String::new(),
+            role_claim: role_claim.to_string(),
+            default_role: Role::Viewer,
+            display_name: String::new(),
+        }
+    }
+
+    #[test]
+    fn highest_role_wins() {
+        let claims = json!({ "realm_access": { "roles": ["offline_access", "pharmacist", "viewer"] } });
+        assert_eq!(role_from_claims(&claims, &config("realm_access.roles")), Role::Pharmacist);
+        assert_eq!(role_from_claims(&json!({ "roles": "admin" }), &config("roles")), Role::Admin);
+    }
+
+    #[test]
+    fn unknown_roles_get_the_default() {
+        assert_eq!(role_from_claims(&json!({ "roles": ["doctor"] }), &config("roles")), Role::Viewer);
+        assert_eq!(role_from_claims(&json!({}), &config("roles")), Role::Viewer);
+    }
+
+    #[test]
+    fn people_are_told_apart_by_issuer_and_subject() {
+        let config = config("roles");
+        let claims = json!({ "sub": "248289761001", "preferred_username": "alice", "email": "alice@example.org" });
+        let user = user_from_claims(&config.issuer, &claims, &config).unwrap();
+        assert_eq!(user.id, "248289761001@https://login.example.org");
+        assert_eq!(user.name, "alice");
+        assert!(auth::check_username(&user.id).is_err());
+
+        let user = user_from_claims(&config.issuer, &json!({ "sub": "248289761001" }), &config).unwrap();
+        assert_eq!(user.name, "248289761001");
+        assert!(user_from_claims(&config.issuer, &json!({ "preferred_username": "alice" }), &config).is_none());
+    }
+
+    #[test]
+    fn plain_http_is_refused() {
+        let url = Url::parse("http://login.example.org/.well-known/openid-configuration").unwrap();
+        assert!(matches!(http::request("GET", &url, None, &[]), Err(OidcError::Unreachable { .. })));
+    }
+}
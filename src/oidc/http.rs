@@ -0,0 +1,56 @@
+//! Requests to an OpenID provider, over HTTPS only
+
+use std::io::Read;
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+use super::OidcError;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Discovery documents, key sets and token responses are a few KB at most
+const MAX_BODY: u64 = 1024 * 1024;
+
+pub(super) struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+/// Send a request and wait for the whole response. Certificates are checked against the system's CA certificates.
+pub(super) fn request(method: &str, url: &Url, content_type: Option<&str>, body: &[u8]) -> Result<Response, OidcError> {
+    let failed = |reason: String| { OidcError::Unreachable { url: url.to_string(), reason } };
+    if url.scheme() != "https" {
+        return Err(failed("only https URLs can be used".to_string()));
+    }
+
+    let connector = native_tls::TlsConnector::new().map_err(|e| { failed(e.to_string()) })?;
+    let agent = ureq::AgentBuilder::new()
+        .tls_connector(Arc::new(connector))
+        .https_only(true)
+        .redirects(0)
+        .timeout(TIMEOUT)
+        .build();
+
+    let mut request = agent.request_url(method, url).set("Accept", "application/json");
+    if let Some(content_type) = content_type {
+        request = request.set("Content-Type", content_type);
+    }
+    let response = match request.send_bytes(body) {
+        Ok(response) => response,
+        // Token endpoints explain refusals in the body of a 400 response
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(error) => return Err(failed(error.to_string())),
+    };
+
+    let status = response.status();
+    let mut body = Vec::new();
+    response
+        .into_reader()
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .map_err(|e| { failed(e.to_string()) })?;
+    if body.len() as u64 > MAX_BODY {
+        return Err(failed(format!("response is larger than {} bytes", MAX_BODY)));
+    }
+
+    Ok(Response { status, body })
+}
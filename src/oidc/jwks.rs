@@ -0,0 +1,202 @@
+//! Checking ID token signatures against the keys a provider publishes at its `jwks_uri`
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Public};
+use openssl::rsa::Rsa;
+use openssl::sign::Verifier;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// A provider's JSON Web Key Set
+#[derive(Debug, Clone, Deserialize)]
+pub(super) struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    #[serde(rename = "use")]
+    key_use: Option<String>,
+    alg: Option<String>,
+    // RSA
+    n: Option<String>,
+    e: Option<String>,
+    // EC
+    crv: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+impl Jwks {
+    /// Check the token's RS256 or ES256 signature and return its claims
+    pub(super) fn verify(&self, token: &str) -> Result<Value, String> {
+        let parts = token.split('.').collect::<Vec<_>>();
+        let [header, payload, signature] = parts[..] else { return Err("not a JWT".to_string()) };
+
+        let header: Header = serde_json::from_slice(&decode(header)?).map_err(|_| { "header isn't JSON".to_string() })?;
+        let signature = decode(signature)?;
+        let signed = format!("{}.{}", parts[0], parts[1]);
+
+        let kty = match header.alg.as_str() {
+            "RS256" => "RSA",
+            "ES256" => "EC",
+            alg => return Err(format!("signed with {}, expected RS256 or ES256", alg)),
+        };
+        let key = self.keys
+            .iter()
+            .filter(|key| { key.kty == kty && key.key_use.as_deref() != Some("enc") })
+            .filter(|key| { key.alg.as_deref().is_none_or(|alg| { alg == header.alg }) })
+            .find(|key| { header.kid.is_none() || key.kid == header.kid })
+            .ok_or_else(|| { "signed with a key the provider doesn't publish".to_string() })?;
+
+        let verified = match kty {
+            "RSA" => verify_rsa(key, signed.as_bytes(), &signature),
+            _ => verify_ec(key, signed.as_bytes(), &signature),
+        };
+        if !verified.map_err(|e| { format!("unusable key: {}", e) })? {
+            return Err("bad signature".to_string());
+        }
+
+        serde_json::from_slice(&decode(payload)?).map_err(|_| { "claims aren't JSON".to_string() })
+    }
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, String> {
+    URL_SAFE_NO_PAD.decode(part.trim_end_matches('=')).map_err(|_| { "not base64".to_string() })
+}
+
+fn big_num(value: &Option<String>) -> Result<BigNum, String> {
+    let bytes = decode(value.as_deref().ok_or("missing a parameter")?)?;
+    BigNum::from_slice(&bytes).map_err(|e| { e.to_string() })
+}
+
+fn check(key: &PKey<Public>, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+    let mut verifier = Verifier::new(MessageDigest::sha256(), key).map_err(|e| { e.to_string() })?;
+    verifier.update(signed).map_err(|e| { e.to_string() })?;
+
+    Ok(verifier.verify(signature).unwrap_or(false))
+}
+
+fn verify_rsa(key: &Jwk, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+    let rsa = Rsa::from_public_components(big_num(&key.n)?, big_num(&key.e)?).map_err(|e| { e.to_string() })?;
+    let key = PKey::from_rsa(rsa).map_err(|e| { e.to_string() })?;
+
+    check(&key, signed, signature)
+}
+
+fn verify_ec(key: &Jwk, signed: &[u8], signature: &[u8]) -> Result<bool, String> {
+    if key.crv.as_deref() != Some("P-256") {
+        return Err("ES256 needs a P-256 key".to_string());
+    }
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|e| { e.to_string() })?;
+    let ec = EcKey::from_public_key_affine_coordinates(&group, &*big_num(&key.x)?, &*big_num(&key.y)?)
+        .map_err(|e| { e.to_string() })?;
+    let key = PKey::from_ec_key(ec).map_err(|e| { e.to_string() })?;
+
+    // JWS signatures are r and s side by side, while OpenSSL wants them DER encoded
+    if signature.len() != 64 {
+        return Ok(false);
+    }
+    let (r, s) = signature.split_at(32);
+    let der = BigNum::from_slice(r)
+        .and_then(|r| { EcdsaSig::from_private_components(r, BigNum::from_slice(s)?) })
+        .and_then(|sig| { sig.to_der() })
+        .map_err(|e| { e.to_string() })?;
+
+    check(&key, signed, &der)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::pkey::Private;
+    use openssl::sign::Signer;
+    use serde_json::json;
+
+    fn encode(bytes: &[u8]) -> String {
+        URL_SAFE_NO_PAD.encode(bytes)
+    }
+
+    fn rsa_key() -> (PKey<Private>, Jwks) {
+        let rsa = Rsa::generate(2048).unwrap();
+        let jwks = serde_json::from_value(json!({ "keys": [{
+            "kty": "RSA", "kid": "k1", "use": "sig",
+            "n": encode(&rsa.n().to_vec()), "e": encode(&rsa.e().to_vec()),
+        }] })).unwrap();
+
+        (PKey::from_rsa(rsa).unwrap(), jwks)
+    }
+
+    fn ec_key() -> (PKey<Private>, Jwks) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let ec = EcKey::generate(&group).unwrap();
+        let (mut x, mut y) = (BigNum::new().unwrap(), BigNum::new().unwrap());
+        let mut ctx = openssl::bn::BigNumContext::new().unwrap();
+        ec.public_key().affine_coordinates(&group, &mut x, &mut y, &mut ctx).unwrap();
+        let jwks = serde_json::from_value(json!({ "keys": [{
+            "kty": "EC", "crv": "P-256", "kid": "k2",
+            "x": encode(&x.to_vec_padded(32).unwrap()), "y": encode(&y.to_vec_padded(32).unwrap()),
+        }] })).unwrap();
+
+        (PKey::from_ec_key(ec).unwrap(), jwks)
+    }
+
+    fn token(key: &PKey<Private>, alg: &str, kid: &str, claims: &Value) -> String {
+        let signed = format!("{}.{}", encode(json!({ "alg": alg, "kid": kid }).to_string().as_bytes()),
+                             encode(claims.to_string().as_bytes()));
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(signed.as_bytes()).unwrap();
+        let mut signature = signer.sign_to_vec().unwrap();
+        if alg == "ES256" {
+            let sig = EcdsaSig::from_der(&signature).unwrap();
+            signature = [sig.r().to_vec_padded(32).unwrap(), sig.s().to_vec_padded(32).unwrap()].concat();
+        }
+
+        format!("{}.{}", signed, encode(&signature))
+    }
+
+    #[test]
+    fn accepts_rs256_and_es256() {
+        let claims = json!({ "sub": "nurse" });
+        let (key, jwks) = rsa_key();
+        assert_eq!(jwks.verify(&token(&key, "RS256", "k1", &claims)), Ok(claims.clone()));
+        let (key, jwks) = ec_key();
+        assert_eq!(jwks.verify(&token(&key, "ES256", "k2", &claims)), Ok(claims));
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let (key, jwks) = rsa_key();
+        let token = token(&key, "RS256", "k1", &json!({ "sub": "nurse" }));
+        let parts = token.split('.').collect::<Vec<_>>();
+        let forged = format!("{}.{}.{}", parts[0], encode(json!({ "sub": "admin" }).to_string().as_bytes()), parts[2]);
+
+        assert_eq!(jwks.verify(&forged), Err("bad signature".to_string()));
+    }
+
+    #[test]
+    fn rejects_other_keys_and_algorithms() {
+        let claims = json!({ "sub": "nurse" });
+        let (_, jwks) = rsa_key();
+        let (other_key, _) = rsa_key();
+        assert!(jwks.verify(&token(&other_key, "RS256", "k1", &claims)).is_err());
+        assert!(jwks.verify(&token(&other_key, "RS256", "unknown", &claims)).is_err());
+
+        let unsigned = format!("{}.{}.", encode(br#"{"alg":"none"}"#), encode(claims.to_string().as_bytes()));
+        assert!(jwks.verify(&unsigned).is_err());
+    }
+}
@@ -0,0 +1,96 @@
+use crate::access::Device;
+use crate::schedule::Schedule;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Longest label a regimen can have
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// The IVs a patient currently has, saved so new orders can be added to them
+#[derive(Debug, Clone, Serialize)]
+pub struct Regimen {
+    pub id: u32,
+    /// A bed or encounter label. Never a patient's name.
+    pub label: String,
+    /// Infusion IDs in each IV, in IV order
+    pub ivs: Vec<Vec<u32>>,
+    /// Infusion ID -> when it runs, for intermittent infusions
+    pub schedules: BTreeMap<u32, Schedule>,
+    /// The patient's lines, if given. IV number `i` goes through the `i`th lumen.
+    pub devices: Vec<Device>,
+    /// Goes up by one with every change, so changes based on an old layout can be refused
+    pub revision: u32,
+    pub updated_at: i64,    // Unix timestamp
+    pub updated_by: String,
+}
+
+/// How one IV differs between two layouts
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct IvChange {
+    /// Index of the IV in the new layout
+    pub iv: usize,
+    /// False if the IV is a new line
+    pub existing: bool,
+    /// Infusions that stay in this IV
+    pub kept: Vec<u32>,
+    pub added: Vec<u32>,
+    pub removed: Vec<u32>,
+}
+
+/// Trim a label and check it can be used
+pub fn check_label(label: &str) -> Result<&str, String> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err("The label can't be empty".to_string());
+    }
+    if label.chars().count() > MAX_LABEL_LEN {
+        return Err(format!("The label can't be longer than {} characters", MAX_LABEL_LEN));
+    }
+
+    Ok(label)
+}
+
+/// The IVs that differ between `before` and `after`. IVs are matched up by position.
+pub fn changes(before: &[Vec<u32>], after: &[Vec<u32>]) -> Vec<IvChange> {
+    let empty = Vec::new();
+
+    (0..before.len().max(after.len()))
+        .map(|iv| {
+            let old = before.get(iv).unwrap_or(&empty);
+            let new = after.get(iv).unwrap_or(&empty);
+            IvChange {
+                iv,
+                existing: iv < before.len(),
+                kept: new.iter().filter(|id| { old.contains(id) }).copied().collect(),
+                added: new.iter().filter(|id| { !old.contains(id) }).copied().collect(),
+                removed: old.iter().filter(|id| { !new.contains(id) }).copied().collect(),
+            }
+        })
+        .filter(|change| { !change.added.is_empty() || !change.removed.is_empty() })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        assert_eq!(check_label("  Bed 4 "), Ok("Bed 4"));
+        assert!(check_label(" ").is_err());
+        assert!(check_label(&"é".repeat(MAX_LABEL_LEN)).is_ok());
+        assert!(check_label(&"é".repeat(MAX_LABEL_LEN + 1)).is_err());
+    }
+
+    #[test]
+    fn changes_by_position() {
+        let layout = changes(&[vec![1, 2], vec![3], vec![4]], &[vec![1], vec![3], vec![4, 5], vec![2]]);
+        assert_eq!(layout, [
+            IvChange { iv: 0, existing: true, kept: vec![1], added: vec![], removed: vec![2] },
+            IvChange { iv: 2, existing: true, kept: vec![4], added: vec![5], removed: vec![] },
+            IvChange { iv: 3, existing: false, kept: vec![], added: vec![2], removed: vec![] },
+        ]);
+
+        assert_eq!(changes(&[vec![1]], &[]), [IvChange { iv: 0, existing: true, kept: vec![], added: vec![], removed: vec![1] }]);
+    }
+}
@@ -0,0 +1,538 @@
+//! Saved regimens: a patient's current IVs, kept so new orders can be added without re-entering them
+
+use crate::admin::format_time;
+use crate::login::{SignedIn, User};
+use crate::{audit_infusion, conflict_response, invalid_request_response, load_problem, log_solve, pair_names, run_solve, solve_record};
+use crate::{AppState, CancelOnDrop, SOLVE_TIME_LIMIT};
+use iv_compatibility::access::{self, Device};
+use iv_compatibility::audit::{SolveOutcome, SolveRecord};
+use iv_compatibility::dataset::Dataset;
+use iv_compatibility::infusion::Infusion;
+use iv_compatibility::db::StoreError;
+use iv_compatibility::regimen::{self, Regimen};
+use iv_compatibility::request::{self, RequestError, SolveRequest};
+use iv_compatibility::schedule::Schedule;
+use iv_compatibility::solver::{self, Consolidation, ConflictError, SolveBudget};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{Html, IntoResponse, Redirect, Response};
+use axum::Form;
+use axum_extra::extract::Query;
+use itertools::Itertools;
+use minijinja::context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to spend checking that a layout sent back by the browser is compatible
+const CHECK_TIME_LIMIT: Duration = Duration::from_millis(500);
+
+#[derive(Serialize)]
+struct IvView {
+    number: usize,
+    /// The line it goes through, if the regimen's lines were given
+    line: Option<String>,
+    infusions: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct ChangeView {
+    number: usize,
+    existing: bool,
+    kept: Vec<String>,
+    added: Vec<String>,
+    removed: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct MoveView {
+    name: String,
+    from: u32,
+    to: u32,
+}
+
+fn infusion_name(dataset: &Dataset, id: u32) -> String {
+    dataset.infusion(id).map(|infusion| { infusion.name.clone() }).unwrap_or_else(|| { format!("#{}", id) })
+}
+
+/// Infusion names, with when they run for intermittent infusions
+fn names(dataset: &Dataset, ids: &[u32], schedules: &BTreeMap<u32, Schedule>) -> Vec<String> {
+    ids.iter()
+        .map(|id| {
+            match schedules.get(id) {
+                Some(schedule) => format!("{} ({})", infusion_name(dataset, *id), schedule),
+                None => infusion_name(dataset, *id),
+            }
+        })
+        .collect()
+}
+
+fn iv_views(dataset: &Dataset, regimen: &Regimen) -> Vec<IvView> {
+    let lines = access::lines(&regimen.devices);
+    regimen.ivs
+        .iter()
+        .enumerate()
+        .map(|(iv, ids)| {
+            IvView {
+                number: iv + 1,
+                line: lines.get(iv).map(|line| { line.to_string() }),
+                infusions: names(dataset, ids, &regimen.schedules),
+            }
+        })
+        .collect()
+}
+
+/// `devices`, with new lines for any IVs in `layout` past them, as JSON
+fn devices_json(dataset: &Dataset, devices: &[Device], layout: &[Vec<u32>]) -> String {
+    let central_only = |id| { dataset.infusion(id).is_some_and(|infusion| { infusion.central_only }) };
+    serde_json::to_string(&access::with_new_lines(devices, layout, central_only)).expect("Failed to serialize devices")
+}
+
+pub async fn handler_regimens(state: State<Arc<AppState>>, SignedIn(user): SignedIn) -> Response {
+    #[derive(Serialize)]
+    struct RegimenRow {
+        id: u32,
+        label: String,
+        infusions: usize,
+        ivs: usize,
+        updated_at: String,
+        updated_by: String,
+    }
+
+    let store = state.store.clone();
+    let regimens = match tokio::task::spawn_blocking(move || { store.regimens() }).await.expect("Regimen task panicked") {
+        Ok(regimens) => regimens,
+        Err(error) => return error_response(&state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    };
+    let regimens = regimens
+        .into_iter()
+        .map(|regimen| {
+            RegimenRow {
+                id: regimen.id,
+                infusions: regimen.ivs.iter().flatten().count(),
+                ivs: regimen.ivs.len(),
+                updated_at: format_time(regimen.updated_at),
+                label: regimen.label,
+                updated_by: regimen.updated_by,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let template = state.env.get_template("regimens").expect("Template not found!");
+    let rendered = template
+        .render(context!(user => user, regimens => regimens))
+        .expect("Unable to render regimens page");
+
+    Html(rendered).into_response()
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SaveForm {
+    label: String,
+    /// Infusion IDs in each IV, as JSON
+    ivs: String,
+    /// Infusion ID -> schedule, as JSON
+    #[serde(default)]
+    schedules: String,
+    /// The patient's lines, as JSON. See `access::Device`.
+    #[serde(default)]
+    devices: String,
+}
+
+/// Save a layout from the results page as a new regimen
+pub async fn handler_save_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Form(form): Form<SaveForm>) -> Response {
+    let label = match regimen::check_label(&form.label) {
+        Ok(label) => label.to_string(),
+        Err(message) => return error_response(&state, StatusCode::BAD_REQUEST, &message),
+    };
+    let (ivs, schedules, devices) = match checked_layout(&state, &form.ivs, &form.schedules, &form.devices).await {
+        Ok(layout) => layout,
+        Err(message) => return error_response(&state, StatusCode::BAD_REQUEST, &message),
+    };
+
+    let store = state.store.clone();
+    let username = user.username.clone();
+    let result = tokio::task::spawn_blocking(move || { store.add_regimen(&label, &ivs, &schedules, &devices, &username) })
+        .await
+        .expect("Regimen task panicked");
+
+    match result {
+        Ok(regimen) => {
+            println!("{} saved regimen #{} ({})", user.username, regimen.id, regimen.label);
+            Redirect::to(&format!("/regimens/{}", regimen.id)).into_response()
+        },
+        Err(error) => store_error_response(&state, error),
+    }
+}
+
+pub async fn handler_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Path(id): Path<u32>) -> Response {
+    let regimen = match load_regimen(&state, id).await {
+        Ok(regimen) => regimen,
+        Err(response) => return response,
+    };
+
+    render_regimen(&state, &user, &regimen, None)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AddParams {
+    #[serde(default)]
+    add: Vec<u32>,
+    /// JSON object of infusion ID -> schedule, for the new infusions that are intermittent
+    schedules: Option<String>,
+}
+
+/// Work out where new infusions would go, keeping the regimen's IVs as they are.
+/// Nothing is saved until the proposal is applied.
+pub async fn handler_add_to_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Path(id): Path<u32>,
+                                    Query(params): Query<AddParams>) -> Response {
+    let regimen = match load_regimen(&state, id).await {
+        Ok(regimen) => regimen,
+        Err(response) => return response,
+    };
+    if params.add.is_empty() {
+        return error_response(&state, StatusCode::BAD_REQUEST, "Pick at least one infusion to add");
+    }
+
+    let mut schedules = regimen.schedules.clone();
+    match params.schedules.as_deref().map(request::parse_schedules).transpose() {
+        Ok(added) => schedules.extend(added.unwrap_or_default()),
+        Err(error) => return invalid_request_response(&state, error),
+    }
+
+    let mut record = solve_record(Some(user.username.clone()), Some(regimen.id));
+    let request = SolveRequest {
+        ivs: regimen.ivs.iter().map(|iv| { iv.iter().copied().collect() }).collect(),
+        add: params.add,
+        schedules: schedules.clone(),
+        devices: regimen.devices.clone(),
+    };
+    let dataset = state.dataset.load_full();
+
+    let response = match run_solve(&dataset, request, &mut record).await {
+        Ok(Ok(solution)) => {
+            let layout = solution
+                .layout()
+                .iter()
+                .map(|iv| { iv.iter().map(|infusion| { infusion.id() }).sorted().collect_vec() })
+                .collect_vec();
+            let numbered = |pairs: Vec<(u32, &Infusion, &Infusion)>| {
+                pairs.into_iter().map(|(iv, a, b)| { (iv + 1, a.name().to_string(), b.name().to_string()) }).collect()
+            };
+            let flushes = numbered(solver::flushes(&solution.layout()));
+            let time_shared = numbered(solver::time_shared(&solution.layout()));
+            let changes = regimen::changes(&regimen.ivs, &layout);
+            let proposal = Proposal {
+                unchanged: layout.len() - changes.len(),
+                changes: changes
+                    .into_iter()
+                    .map(|change| {
+                        ChangeView {
+                            number: change.iv + 1,
+                            existing: change.existing,
+                            kept: names(&dataset, &change.kept, &schedules),
+                            added: names(&dataset, &change.added, &schedules),
+                            removed: names(&dataset, &change.removed, &schedules),
+                        }
+                    })
+                    .collect(),
+                stopped: Vec::new(),
+                moves: Vec::new(),
+                freed: Vec::new(),
+                flushes,
+                time_shared,
+                optimal: solution.optimal,
+                layout: serde_json::to_string(&layout).expect("Failed to serialize layout"),
+                schedules: serde_json::to_string(&schedules).expect("Failed to serialize schedules"),
+                devices: devices_json(&dataset, &regimen.devices, &layout),
+            };
+            render_regimen(&state, &user, &regimen, Some(proposal))
+        },
+        Ok(Err(conflict)) => conflict_response(&state, &conflict),
+        Err(error) => invalid_request_response(&state, error),
+    };
+
+    log_solve(&state, record, response).await
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StopParams {
+    #[serde(default)]
+    stop: Vec<u32>,
+}
+
+/// Work out how the regimen's IVs can be consolidated once some infusions are stopped.
+/// Nothing is saved until the proposal is applied.
+pub async fn handler_stop_in_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Path(id): Path<u32>,
+                                     Query(params): Query<StopParams>) -> Response {
+    let regimen = match load_regimen(&state, id).await {
+        Ok(regimen) => regimen,
+        Err(response) => return response,
+    };
+    let dataset = state.dataset.load_full();
+    if params.stop.is_empty() {
+        return error_response(&state, StatusCode::BAD_REQUEST, "Pick at least one infusion to stop");
+    }
+    if let Some(id) = params.stop.iter().find(|id| { !regimen.ivs.iter().any(|iv| { iv.contains(id) }) }) {
+        let message = format!("{} isn't part of this regimen", infusion_name(&dataset, *id));
+        return error_response(&state, StatusCode::BAD_REQUEST, &message);
+    }
+
+    let mut record = solve_record(Some(user.username.clone()), Some(regimen.id));
+    record.strategy = solver::CONSOLIDATE_STRATEGY.to_string();
+    record.dataset_version = dataset.version().map(|version| { version.id });
+    record.ivs = regimen.ivs.iter().map(|iv| { iv.iter().map(|id| { audit_infusion(&dataset, *id) }).collect() }).collect();
+    record.remove = params.stop.iter().map(|id| { audit_infusion(&dataset, *id) }).collect();
+    record.schedules = regimen.schedules.clone();
+    let mut schedules = regimen.schedules.clone();
+    schedules.retain(|id, _| { !params.stop.contains(id) });
+    let request = SolveRequest {
+        ivs: regimen.ivs.iter().map(|iv| { iv.iter().filter(|id| { !params.stop.contains(id) }).copied().collect() }).collect(),
+        add: Vec::new(),
+        schedules: schedules.clone(),
+        devices: regimen.devices.clone(),
+    };
+
+    let response = match run_consolidate(&dataset, request, &mut record).await {
+        Ok(Ok(consolidation)) => {
+            let ids = |iv: &[Infusion]| { iv.iter().map(|infusion| { infusion.id() }).sorted().collect_vec() };
+            // Each lumen stays an IV even with nothing left in it, otherwise freed IVs are dropped
+            let layout = if regimen.devices.is_empty() {
+                consolidation.layout().into_iter().map(ids).collect_vec()
+            } else {
+                (0..regimen.ivs.len() as u32)
+                    .map(|iv| { consolidation.ivs.get(&iv).map(|infusions| { ids(infusions) }).unwrap_or_default() })
+                    .collect_vec()
+            };
+            // The layout leaves out freed IVs, so number them as they're numbered now
+            let numbers = consolidation.ivs.keys().sorted().collect_vec();
+            let numbered = |pairs: Vec<(u32, &Infusion, &Infusion)>| {
+                pairs.into_iter().map(|(iv, a, b)| { (numbers[iv as usize] + 1, a.name().to_string(), b.name().to_string()) }).collect()
+            };
+            let flushes = numbered(solver::flushes(&consolidation.layout()));
+            let time_shared = numbered(solver::time_shared(&consolidation.layout()));
+            let touched = regimen.ivs
+                .iter()
+                .enumerate()
+                .filter(|(_, ids)| { ids.iter().any(|id| { params.stop.contains(id) }) })
+                .map(|(iv, _)| { iv as u32 })
+                .chain(consolidation.moves.iter().flat_map(|m| { [m.from, m.to] }))
+                .chain(consolidation.freed.iter().copied())
+                .collect::<HashSet<_>>();
+            let proposal = Proposal {
+                changes: Vec::new(),
+                unchanged: regimen.ivs.len() - touched.len(),
+                stopped: names(&dataset, &params.stop, &regimen.schedules),
+                moves: consolidation.moves
+                    .iter()
+                    .map(|m| { MoveView { name: m.infusion.name().to_string(), from: m.from + 1, to: m.to + 1 } })
+                    .collect(),
+                freed: consolidation.freed.iter().map(|iv| { iv + 1 }).collect(),
+                flushes,
+                time_shared,
+                optimal: consolidation.optimal,
+                layout: serde_json::to_string(&layout).expect("Failed to serialize layout"),
+                schedules: serde_json::to_string(&schedules).expect("Failed to serialize schedules"),
+                devices: devices_json(&dataset, &regimen.devices, &layout),
+            };
+            render_regimen(&state, &user, &regimen, Some(proposal))
+        },
+        Ok(Err(conflict)) => conflict_response(&state, &conflict),
+        Err(error) => invalid_request_response(&state, error),
+    };
+
+    log_solve(&state, record, response).await
+}
+
+/// Consolidate the IVs in `request`, filling in `record` with the answer. See `run_solve`.
+async fn run_consolidate(dataset: &Dataset, request: SolveRequest, record: &mut SolveRecord)
+                         -> Result<Result<Consolidation, ConflictError>, RequestError> {
+    let mut problem = match load_problem(dataset, &request) {
+        Ok(problem) => problem,
+        Err(error) => {
+            record.outcome = SolveOutcome::Failed { reason: error.to_string() };
+            return Err(error);
+        },
+    };
+
+    let budget = SolveBudget::new(SOLVE_TIME_LIMIT);
+    let _cancel_guard = CancelOnDrop(budget.clone());
+    let ivs = request.ivs;
+    let result = tokio::task::spawn_blocking(move || { problem.consolidate(ivs, &budget) })
+        .await
+        .expect("Solver task panicked");
+
+    record.outcome = match &result {
+        Ok(consolidation) => SolveOutcome::Solved {
+            ivs: consolidation.layout().iter().map(|iv| { iv.iter().map(|inf| { inf.name().to_string() }).collect() }).collect(),
+            optimal: consolidation.optimal,
+            flushes: pair_names(solver::flushes(&consolidation.layout())),
+            time_shared: pair_names(solver::time_shared(&consolidation.layout())),
+        },
+        Err(error) => SolveOutcome::Conflict {
+            iv: error.iv,
+            conflicting_items: error.conflicting_items.clone(),
+            dedicated_line: error.dedicated_line.clone(),
+        },
+    };
+
+    Ok(result)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ApplyForm {
+    /// The revision the proposal was based on
+    revision: u32,
+    /// Infusion IDs in each IV, as JSON
+    ivs: String,
+    /// Infusion ID -> schedule, as JSON
+    #[serde(default)]
+    schedules: String,
+    /// The patient's lines, as JSON. See `access::Device`.
+    #[serde(default)]
+    devices: String,
+}
+
+/// Save a proposed layout, unless someone else changed the regimen first
+pub async fn handler_update_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Path(id): Path<u32>,
+                                    Form(form): Form<ApplyForm>) -> Response {
+    let (ivs, schedules, devices) = match checked_layout(&state, &form.ivs, &form.schedules, &form.devices).await {
+        Ok(layout) => layout,
+        Err(message) => return error_response(&state, StatusCode::BAD_REQUEST, &message),
+    };
+
+    let store = state.store.clone();
+    let username = user.username.clone();
+    let result = tokio::task::spawn_blocking(move || { store.update_regimen(id, form.revision, &ivs, &schedules, &devices, &username) })
+        .await
+        .expect("Regimen task panicked");
+
+    match result {
+        Ok(Some(regimen)) => {
+            println!("{} updated regimen #{} ({}) to revision {}", user.username, regimen.id, regimen.label, regimen.revision);
+            Redirect::to(&format!("/regimens/{}", regimen.id)).into_response()
+        },
+        Ok(None) => error_response(&state, StatusCode::NOT_FOUND, "There's no such regimen"),
+        Err(error) => store_error_response(&state, error),
+    }
+}
+
+pub async fn handler_remove_regimen(state: State<Arc<AppState>>, SignedIn(user): SignedIn, Path(id): Path<u32>) -> Response {
+    let store = state.store.clone();
+    match tokio::task::spawn_blocking(move || { store.remove_regimen(id) }).await.expect("Regimen task panicked") {
+        Ok(true) => {
+            println!("{} removed regimen #{}", user.username, id);
+            Redirect::to("/regimens").into_response()
+        },
+        Ok(false) => error_response(&state, StatusCode::NOT_FOUND, "There's no such regimen"),
+        Err(error) => store_error_response(&state, error),
+    }
+}
+
+/// Where new infusions would go, or how the IVs can be consolidated
+#[derive(Serialize)]
+struct Proposal {
+    /// Only the IVs that change, when adding infusions
+    changes: Vec<ChangeView>,
+    /// IVs nothing happens to
+    unchanged: usize,
+    stopped: Vec<String>,
+    moves: Vec<MoveView>,
+    /// IV numbers with nothing left in them
+    freed: Vec<u32>,
+    /// IV number and the infusions on either side of each flush
+    flushes: Vec<(u32, String, String)>,
+    /// IV number and incompatible infusions that share it only because they never run at the same time
+    time_shared: Vec<(u32, String, String)>,
+    optimal: bool,
+    /// The whole new layout, as JSON, for applying it
+    layout: String,
+    /// Schedules for the new layout, as JSON
+    schedules: String,
+    /// Lines for the new layout, as JSON
+    devices: String,
+}
+
+fn render_regimen(state: &AppState, user: &User, regimen: &Regimen, proposal: Option<Proposal>) -> Response {
+    #[derive(Serialize)]
+    struct SimpleInfusion {
+        id: u32,
+        name: String,
+        inf_type: u32,
+    }
+
+    let dataset = state.dataset.load();
+    let in_use = regimen.ivs.iter().flatten().collect::<Vec<_>>();
+    let options = dataset
+        .infusions()
+        .filter(|infusion| { !infusion.retired && !in_use.contains(&&infusion.id) })
+        .map(|infusion| { SimpleInfusion { id: infusion.id, name: infusion.name.clone(), inf_type: infusion.infusion_type.id() } })
+        .collect::<Vec<_>>();
+    let version = dataset.version().map(|version| { version.to_string() });
+
+    let template = state.env.get_template("regimen").expect("Template not found!");
+    let rendered = template
+        .render(context!(
+            user => user,
+            regimen => regimen,
+            ivs => iv_views(&dataset, regimen),
+            running => regimen.ivs.iter().flatten().map(|id| { (id, infusion_name(&dataset, *id)) }).collect_vec(),
+            updated_at => format_time(regimen.updated_at),
+            inf => options,
+            version => version,
+            proposal => proposal,
+        ))
+        .expect("Unable to render regimen page");
+
+    Html(rendered).into_response()
+}
+
+async fn load_regimen(state: &AppState, id: u32) -> Result<Regimen, Response> {
+    let store = state.store.clone();
+    match tokio::task::spawn_blocking(move || { store.regimen(id) }).await.expect("Regimen task panicked") {
+        Ok(Some(regimen)) => Ok(regimen),
+        Ok(None) => Err(error_response(state, StatusCode::NOT_FOUND, "There's no such regimen")),
+        Err(error) => Err(error_response(state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string())),
+    }
+}
+
+/// Parse a layout, its schedules and lines sent back by the browser, and check that it only uses
+/// known infusions, that every IV's infusions are compatible and that central-only infusions are on central lines
+async fn checked_layout(state: &AppState, ivs_json: &str, schedules_json: &str, devices_json: &str)
+                        -> Result<(Vec<Vec<u32>>, BTreeMap<u32, Schedule>, Vec<Device>), String> {
+    let mut ivs: Vec<Vec<u32>> = serde_json::from_str(ivs_json).map_err(|e| { format!("Invalid IV layout: {}", e) })?;
+    let schedules_json = Some(schedules_json).filter(|json| { !json.is_empty() });
+    let devices_json = Some(devices_json).filter(|json| { !json.is_empty() });
+    let request = SolveRequest::parse(ivs_json, Vec::new(), schedules_json, devices_json).map_err(|e| { e.to_string() })?;
+    // Every lumen is an IV, even with nothing in it
+    ivs.resize_with(request.ivs.len(), Vec::new);
+    let (schedules, devices) = (request.schedules.clone(), request.devices.clone());
+
+    let dataset = state.dataset.load_full();
+    let mut problem = load_problem(&dataset, &request).map_err(|e| { e.to_string() })?;
+    let budget = SolveBudget::new(CHECK_TIME_LIMIT);
+    tokio::task::spawn_blocking(move || { problem.solve(request.ivs, &budget) })
+        .await
+        .expect("Solver task panicked")
+        .map_err(|e| { e.to_string() })?;
+
+    Ok((ivs, schedules, devices))
+}
+
+fn store_error_response(state: &AppState, error: StoreError) -> Response {
+    match error {
+        StoreError::InvalidData(reason) => error_response(state, StatusCode::CONFLICT, &reason),
+        error => error_response(state, StatusCode::SERVICE_UNAVAILABLE, &error.to_string()),
+    }
+}
+
+fn error_response(state: &AppState, status: StatusCode, message: &str) -> Response {
+    let template = state.env.get_template("regimen_error").expect("Template not found!");
+    let rendered = template
+        .render(context!(message => message))
+        .expect("Unable to render error page");
+
+    (status, Html(rendered)).into_response()
+}
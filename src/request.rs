@@ -0,0 +1,254 @@
+use crate::access::{self, Access, Device, Line};
+use crate::infusion::Infusion;
+use crate::schedule::Schedule;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use itertools::Itertools;
+use std::{error, fmt};
+
+#[derive(Debug)]
+pub enum RequestError {
+    /// The `ivs` parameter couldn't be parsed
+    Malformed(String),
+    /// Some infusion IDs don't exist or are listed more than once
+    InvalidInfusions {
+        unknown_ids: Vec<u32>,
+        duplicates: Vec<String>,
+    },
+    /// The requested dataset version doesn't exist
+    UnknownVersion(u32),
+    /// Retired infusions can stay on the current IVs but can't be added
+    Retired(Vec<String>),
+    /// A current IV goes through a peripheral line but holds an infusion that needs central access
+    NeedsCentralAccess { infusion: String, line: String },
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RequestError::Malformed(reason) => write!(f, "Invalid IV data: {}", reason),
+            RequestError::InvalidInfusions { unknown_ids, duplicates } => {
+                write!(f, "Unknown infusions: {:?}, duplicate infusions: {:?}", unknown_ids, duplicates)
+            }
+            RequestError::UnknownVersion(id) => write!(f, "Unknown dataset version: {}", id),
+            RequestError::Retired(names) => write!(f, "Retired infusions can't be added: {}", names.join(", ")),
+            RequestError::NeedsCentralAccess { infusion, line } => {
+                write!(f, "{} needs central access but is on {}", infusion, line)
+            }
+        }
+    }
+}
+
+impl error::Error for RequestError {}
+
+/// The current IVs and the infusions to be added to them, as submitted by the user
+#[derive(Debug)]
+pub struct SolveRequest {
+    pub ivs: Vec<HashSet<u32>>,
+    pub add: Vec<u32>,
+    /// Infusion ID -> when it runs, for intermittent infusions
+    pub schedules: BTreeMap<u32, Schedule>,
+    /// The patient's lines, if given. IV number `i` goes through the `i`th lumen.
+    pub devices: Vec<Device>,
+}
+
+impl SolveRequest {
+    pub fn parse(ivs_json: &str, add: Vec<u32>, schedules_json: Option<&str>, devices_json: Option<&str>)
+                 -> Result<Self, RequestError> {
+        let lists: Vec<Vec<u32>> = serde_json::from_str(ivs_json)
+            .map_err(|e| { RequestError::Malformed(e.to_string()) })?;
+        // Sets would quietly drop an infusion listed twice in the same IV
+        let mut ivs: Vec<HashSet<u32>> = Vec::with_capacity(lists.len());
+        for (iv, list) in lists.into_iter().enumerate() {
+            let mut set = HashSet::new();
+            if let Some(id) = list.into_iter().find(|id| { !set.insert(*id) }) {
+                return Err(RequestError::Malformed(format!("infusion {} is listed twice in IV {}", id, iv + 1)));
+            }
+            ivs.push(set);
+        }
+        let schedules = match schedules_json {
+            Some(json) => parse_schedules(json)?,
+            None => BTreeMap::new(),
+        };
+        let devices: Vec<Device> = match devices_json {
+            Some(json) => serde_json::from_str(json).map_err(|e| { RequestError::Malformed(format!("devices: {}", e)) })?,
+            None => Vec::new(),
+        };
+
+        if !devices.is_empty() {
+            for device in &devices {
+                device.check().map_err(|reason| { RequestError::Malformed(format!("devices: {}", reason)) })?;
+            }
+            // Every lumen is an IV, even if nothing is running through it yet
+            let num_lines = access::lines(&devices).len();
+            if ivs.len() > num_lines {
+                return Err(RequestError::Malformed(format!("{} IVs but only {} lumens", ivs.len(), num_lines)));
+            }
+            ivs.resize_with(num_lines, HashSet::new);
+        }
+
+        Ok(Self { ivs, add, schedules, devices })
+    }
+
+    /// The line each current IV goes through, if the devices were given
+    pub fn lines(&self) -> Vec<Line> {
+        access::lines(&self.devices)
+    }
+
+    /// All requested infusion IDs, with duplicates removed
+    pub fn infusion_ids(&self) -> Vec<u32> {
+        self.ivs.iter().flatten().chain(self.add.iter()).copied().unique().collect()
+    }
+
+    /// Check that every requested infusion exists, that each infusion only appears
+    /// once across all IVs and the list of infusions to add, and that none being added is retired.
+    pub fn validate(&self, infusions: &HashMap<u32, Infusion>) -> Result<(), RequestError> {
+        let unknown_ids = self.infusion_ids()
+            .into_iter()
+            .filter(|id| { !infusions.contains_key(id) })
+            .sorted()
+            .collect_vec();
+
+        let mut counts: HashMap<u32, u32> = HashMap::new();
+        for id in self.ivs.iter().flatten().chain(self.add.iter()) {
+            *counts.entry(*id).or_insert(0) += 1;
+        }
+        let duplicates = counts
+            .iter()
+            .filter(|(_, count)| { **count > 1 })
+            .map(|(id, _)| {
+                match infusions.get(id) {
+                    Some(infusion) => infusion.name().to_string(),
+                    None => format!("#{}", id),
+                }
+            })
+            .sorted()
+            .collect_vec();
+
+        let requested = self.infusion_ids();
+        if let Some(id) = self.schedules.keys().find(|id| { !requested.contains(id) }) {
+            return Err(RequestError::Malformed(format!("infusion {} has a schedule but isn't in the request", id)));
+        }
+
+        if !unknown_ids.is_empty() || !duplicates.is_empty() {
+            return Err(RequestError::InvalidInfusions { unknown_ids, duplicates });
+        }
+
+        let retired = self.add
+            .iter()
+            .filter(|id| { infusions[id].retired() })
+            .map(|id| { infusions[id].name().to_string() })
+            .sorted()
+            .collect_vec();
+        if !retired.is_empty() {
+            return Err(RequestError::Retired(retired));
+        }
+
+        for (iv, line) in self.ivs.iter().zip(self.lines()) {
+            let misplaced = iv.iter().sorted().find(|id| { line.access == Access::Peripheral && infusions[id].central_only() });
+            if let Some(id) = misplaced {
+                return Err(RequestError::NeedsCentralAccess { infusion: infusions[id].name().to_string(), line: line.to_string() });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Give each infusion its schedule from the request
+    pub fn apply_schedules(&self, infusions: &mut HashMap<u32, Infusion>) {
+        for (id, infusion) in infusions.iter_mut() {
+            infusion.set_schedule(self.schedules.get(id).copied());
+        }
+    }
+}
+
+/// Parse and check schedules given as a JSON object of infusion ID -> schedule
+pub fn parse_schedules(json: &str) -> Result<BTreeMap<u32, Schedule>, RequestError> {
+    let schedules: BTreeMap<u32, Schedule> = serde_json::from_str(json)
+        .map_err(|e| { RequestError::Malformed(format!("schedules: {}", e)) })?;
+    for (id, schedule) in &schedules {
+        schedule.check().map_err(|reason| { RequestError::Malformed(format!("schedule for infusion {}: {}", id, reason)) })?;
+    }
+
+    Ok(schedules)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::infusion::InfusionType;
+
+    fn infusions() -> HashMap<u32, Infusion> {
+        let mut infusions: HashMap<u32, Infusion> = [1, 2, 3, 9]
+            .into_iter()
+            .map(|id| { (id, Infusion::new(id, format!("#{}", id), InfusionType::Drug)) })
+            .collect();
+        infusions.get_mut(&3).unwrap().set_central_only(true);
+        infusions.get_mut(&9).unwrap().set_retired(true);
+
+        infusions
+    }
+
+    const DEVICES: &str = r#"[{"kind":"peripheral","lumens":1},{"kind":"central","lumens":2}]"#;
+
+    #[test]
+    fn every_lumen_is_an_iv() {
+        let request = SolveRequest::parse("[[1]]", vec![2], None, Some(DEVICES)).unwrap();
+        assert_eq!(request.ivs.len(), 3);
+        assert_eq!(request.lines().len(), 3);
+
+        assert!(matches!(SolveRequest::parse("[[1],[2],[],[]]", Vec::new(), None, Some(DEVICES)), Err(RequestError::Malformed(_))));
+        assert!(matches!(SolveRequest::parse("[[1]", Vec::new(), None, None), Err(RequestError::Malformed(_))));
+    }
+
+    #[test]
+    fn infusion_listed_twice_in_an_iv() {
+        let error = SolveRequest::parse("[[1],[2,3,2]]", Vec::new(), None, None).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid IV data: infusion 2 is listed twice in IV 2");
+    }
+
+    #[test]
+    fn unknown_and_duplicate_infusions() {
+        let request = SolveRequest::parse("[[1],[4]]", vec![1, 5], None, None).unwrap();
+        match request.validate(&infusions()) {
+            Err(RequestError::InvalidInfusions { unknown_ids, duplicates }) => {
+                assert_eq!(unknown_ids, [4, 5]);
+                assert_eq!(duplicates, ["#1"]);
+            }
+            other => panic!("expected invalid infusions, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn retired_infusions_can_stay_but_not_be_added() {
+        let request = SolveRequest::parse("[[1],[9]]", vec![2], None, None).unwrap();
+        assert!(request.validate(&infusions()).is_ok());
+
+        let request = SolveRequest::parse("[[1]]", vec![9, 2], None, None).unwrap();
+        let error = request.validate(&infusions()).unwrap_err();
+        assert_eq!(error.to_string(), "Retired infusions can't be added: #9");
+    }
+
+    #[test]
+    fn central_only_infusion_on_a_peripheral_line() {
+        let request = SolveRequest::parse("[[3]]", Vec::new(), None, Some(DEVICES)).unwrap();
+        let error = request.validate(&infusions()).unwrap_err();
+        assert_eq!(error.to_string(), "#3 needs central access but is on Peripheral line 1");
+
+        let request = SolveRequest::parse("[[],[3]]", Vec::new(), None, Some(DEVICES)).unwrap();
+        assert!(request.validate(&infusions()).is_ok());
+        let request = SolveRequest::parse("[[3]]", Vec::new(), None, None).unwrap();
+        assert!(request.validate(&infusions()).is_ok());
+    }
+
+    #[test]
+    fn schedules_must_be_for_requested_infusions() {
+        let schedule = r#"{"2":{"start":0,"duration":60,"interval":720}}"#;
+        let request = SolveRequest::parse("[[1]]", vec![2], Some(schedule), None).unwrap();
+        assert!(request.validate(&infusions()).is_ok());
+        assert_eq!(request.schedules[&2].interval, 720);
+
+        let request = SolveRequest::parse("[[1]]", Vec::new(), Some(schedule), None).unwrap();
+        assert!(matches!(request.validate(&infusions()), Err(RequestError::Malformed(_))));
+        assert!(parse_schedules(r#"{"2":{"start":0,"duration":0,"interval":720}}"#).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+
+/// When an intermittent infusion runs, repeating every day. Infusions without one run continuously.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Schedule {
+    /// Start of a dose, in minutes after midnight
+    pub start: u32,
+    /// How long each dose runs, in minutes
+    pub duration: u32,
+    /// Minutes from the start of one dose to the start of the next. Divides a day evenly.
+    pub interval: u32,
+}
+
+impl Schedule {
+    /// Check the schedule makes sense, e.g. that doses don't run into each other
+    pub fn check(&self) -> Result<(), String> {
+        if self.interval == 0 || !MINUTES_PER_DAY.is_multiple_of(self.interval) {
+            return Err(format!("a dose every {} minutes doesn't fit evenly into a day", self.interval));
+        }
+        if self.duration == 0 {
+            return Err("doses need to run for at least a minute".to_string());
+        }
+        if self.duration >= self.interval {
+            return Err(format!("doses of {} minutes every {} minutes would never stop", self.duration, self.interval));
+        }
+        if self.start >= MINUTES_PER_DAY {
+            return Err(format!("{} minutes after midnight isn't a time of day", self.start));
+        }
+
+        Ok(())
+    }
+
+    /// Start of each dose in a day, in minutes after midnight
+    fn starts(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..MINUTES_PER_DAY / self.interval).map(|dose| { (self.start + dose * self.interval) % MINUTES_PER_DAY })
+    }
+
+    fn overlaps(&self, other: &Schedule) -> bool {
+        self.starts().any(|a| {
+            other.starts().any(|b| {
+                // Minutes from the start of one dose to the start of the other, around the clock
+                let a_to_b = (b + MINUTES_PER_DAY - a) % MINUTES_PER_DAY;
+                let b_to_a = (a + MINUTES_PER_DAY - b) % MINUTES_PER_DAY;
+                a_to_b < self.duration || b_to_a < other.duration
+            })
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} min from {:02}:{:02}", self.duration, self.start / 60, self.start % 60)?;
+        if self.interval.is_multiple_of(60) {
+            write!(f, " every {} h", self.interval / 60)
+        } else {
+            write!(f, " every {} min", self.interval)
+        }
+    }
+}
+
+/// Whether two infusions ever run at the same time
+pub fn overlap(a: Option<&Schedule>, b: Option<&Schedule>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => a.overlaps(b),
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn every(hours: u32, start: u32, duration: u32) -> Schedule {
+        Schedule { start, duration, interval: hours * 60 }
+    }
+
+    #[test]
+    fn checks_schedules() {
+        assert!(every(8, 8 * 60, 30).check().is_ok());
+        assert!(Schedule { start: 0, duration: 30, interval: 0 }.check().is_err());
+        assert!(Schedule { start: 0, duration: 30, interval: 7 * 60 }.check().is_err());
+        assert!(every(8, 0, 0).check().is_err());
+        assert!(every(8, 0, 8 * 60).check().is_err());
+        assert!(every(8, MINUTES_PER_DAY, 30).check().is_err());
+    }
+
+    #[test]
+    fn doses_overlap_when_they_run_at_the_same_time() {
+        let a = every(12, 8 * 60, 60);
+        assert!(!overlap(Some(&a), Some(&every(12, 10 * 60, 60))));
+        assert!(overlap(Some(&a), Some(&every(12, 8 * 60 + 30, 60))));
+        assert!(overlap(Some(&every(12, 10 * 60, 60)), Some(&every(6, 8 * 60, 150))));
+        // Doses back to back don't overlap
+        assert!(!overlap(Some(&a), Some(&every(12, 9 * 60, 60))));
+    }
+
+    #[test]
+    fn doses_overlap_around_midnight() {
+        let late = every(24, 23 * 60 + 30, 60);
+        assert!(overlap(Some(&late), Some(&every(24, 10, 30))));
+        assert!(!overlap(Some(&late), Some(&every(24, 40, 30))));
+    }
+
+    #[test]
+    fn continuous_infusions_always_overlap() {
+        assert!(overlap(None, Some(&every(12, 0, 30))));
+        assert!(overlap(None, None));
+    }
+
+    #[test]
+    fn displays_hours_or_minutes() {
+        assert_eq!(every(8, 8 * 60 + 5, 30).to_string(), "30 min from 08:05 every 8 h");
+        assert_eq!(Schedule { start: 0, duration: 10, interval: 90 }.to_string(), "10 min from 00:00 every 90 min");
+    }
+}
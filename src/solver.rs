@@ -1,5 +1,11 @@
+use crate::access::{Access, Line};
+use crate::exact::{greedy_clique_size, relabel, ClosestSearch, ExactSearch};
 use crate::infusion::Infusion;
+use crate::schedule;
 use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use itertools::Itertools;
 use petgraph::prelude::*;
 use std::{error, fmt};
@@ -7,7 +13,9 @@ use std::{error, fmt};
 #[derive(Debug)]
 pub struct ConflictError {
     pub iv: u32,
-    pub conflicting_items: (String, String)
+    pub conflicting_items: (String, String),
+    /// Why one of them needs a line of its own, if that's what keeps them apart
+    pub dedicated_line: Option<String>,
 }
 
 impl fmt::Display for ConflictError {
@@ -18,11 +26,116 @@ impl fmt::Display for ConflictError {
 
 impl error::Error for ConflictError {}
 
+/// Names the method `CompatibilityProblem::solve` uses, for the audit log.
+/// Change it whenever a change to the solver could give different layouts.
+pub const STRATEGY: &str = "greedy-then-exact/2";
+
+/// Names the method `CompatibilityProblem::consolidate` uses, like `STRATEGY`
+pub const CONSOLIDATE_STRATEGY: &str = "fewest-ivs-then-fewest-moves/2";
+
+/// Limits how long a solve may search for an optimal solution
+#[derive(Debug, Clone)]
+pub struct SolveBudget {
+    deadline: Instant,
+    cancelled: Arc<AtomicBool>,
+}
+
+impl SolveBudget {
+    pub fn new(time_limit: Duration) -> Self {
+        Self {
+            deadline: Instant::now() + time_limit,
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Stop the search as soon as possible, keeping the best solution found so far
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || Instant::now() >= self.deadline
+    }
+}
+
+#[derive(Debug)]
+pub struct Solution {
+    pub ivs: HashMap<u32, Vec<Infusion>>,   // IV number -> infusions in that IV
+    /// False if the search was stopped before proving that no solution uses fewer IVs
+    pub optimal: bool,
+}
+
+impl Solution {
+    /// The infusions in each IV, in IV order
+    pub fn layout(&self) -> Vec<&[Infusion]> {
+        (0..self.ivs.len() as u32).map(|iv| { self.ivs[&iv].as_slice() }).collect()
+    }
+}
+
+/// Whether two incompatible infusions can still take turns on one IV: if their doses never run
+/// at the same time, or if the pair can be flushed between, one of them is intermittent and
+/// the other is paused during each dose
+pub fn can_take_turns(a: &Infusion, b: &Infusion) -> bool {
+    !schedule::overlap(a.schedule(), b.schedule())
+        || (a.can_flush(b.id()) && a.schedule().is_some() != b.schedule().is_some())
+}
+
+/// Pairs of incompatible infusions in the same IV, with whether the pair is flushable
+fn taking_turns<'a, 'b>(layout: &'b [&'a [Infusion]]) -> impl Iterator<Item = (u32, &'a Infusion, &'a Infusion, bool)> + 'b {
+    layout
+        .iter()
+        .enumerate()
+        .flat_map(|(iv, infusions)| {
+            infusions
+                .iter()
+                .tuple_combinations()
+                .filter(|(a, b)| { !a.is_compatible(b.id()) })
+                .map(move |(a, b)| { (iv as u32, a, b, a.can_flush(b.id())) })
+        })
+}
+
+/// Pairs of incompatible infusions that take turns on an IV, which has to be flushed between them
+pub fn flushes<'a>(layout: &[&'a [Infusion]]) -> Vec<(u32, &'a Infusion, &'a Infusion)> {
+    taking_turns(layout).filter(|turns| { turns.3 }).map(|(iv, a, b, _)| { (iv, a, b) }).collect()
+}
+
+/// Pairs of incompatible infusions that share an IV only because they never run at the same time
+pub fn time_shared<'a>(layout: &[&'a [Infusion]]) -> Vec<(u32, &'a Infusion, &'a Infusion)> {
+    taking_turns(layout).filter(|turns| { !turns.3 }).map(|(iv, a, b, _)| { (iv, a, b) }).collect()
+}
+
+/// A layout that needs as few IVs as possible, found by moving as few infusions as possible
+#[derive(Debug)]
+pub struct Consolidation {
+    /// Current IV number -> infusions in that IV afterwards. IVs that are no longer needed are left out.
+    pub ivs: HashMap<u32, Vec<Infusion>>,
+    pub moves: Vec<Move>,
+    /// Current IVs with nothing left in them
+    pub freed: Vec<u32>,
+    /// False if the search was stopped before proving that no layout uses fewer IVs or fewer moves
+    pub optimal: bool,
+}
+
+/// An infusion that has to change IV
+#[derive(Debug, Clone)]
+pub struct Move {
+    pub infusion: Infusion,
+    pub from: u32,
+    pub to: u32,
+}
+
+impl Consolidation {
+    /// The infusions in each remaining IV, in the order of the current IVs
+    pub fn layout(&self) -> Vec<&[Infusion]> {
+        self.ivs.keys().sorted().map(|iv| { self.ivs[iv].as_slice() }).collect()
+    }
+}
+
 /// An IV drug compatibility problem is reduced to a graph coloring problem
 /// where each IV infusion is represented by a node and _incompatible_
 /// infusions are connected by edges. Nodes of the same color are infusions
 /// that go in the same IV.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CompatibilityProblem {
     infusions: HashMap<u32, Infusion>,
     graph: UnGraphMap<u32, ()>,
@@ -32,23 +145,40 @@ pub struct CompatibilityProblem {
     color_usage: HashMap<u32, Vec<u32>>,            // color -> list of nodes with that color
     color_max_count: HashMap<u32, u32>,             // color -> max number of nodes which _could_ use that color
     colors: Vec<u32>,
+    peripheral: Vec<u32>,                           // colors of IVs that go through peripheral lines
 }
 
 impl CompatibilityProblem {
     pub fn new(infusions: HashMap<u32, Infusion>) -> Self {
-        // Build the graph representation
+        // Build the graph representation. Incompatible infusions can still share an IV
+        // if they take turns on it.
         let all_ids = infusions.keys().collect();
         let edges = infusions
             .values()
-            .map(|inf| {
-                inf.get_incompatible(&all_ids).into_iter().map(
+            .flat_map(|inf| {
+                inf.get_incompatible(&all_ids).into_iter().filter(
+                    |other_id| {
+                        !can_take_turns(inf, &infusions[other_id])
+                    }
+                ).map(
                     |other_id| {
                         (inf.id(), other_id)
                     }
                 ).collect::<Vec<(u32, u32)>>()
-            })
-            .flatten();
-        let graph = UnGraphMap::from_edges(edges);
+            });
+        // Infusions with a dedicated line can only share it with the infusions it allows,
+        // whatever the compatibility data says
+        let dedicated_edges = infusions
+            .values()
+            .filter(|inf| { inf.dedicated_line().is_some() })
+            .flat_map(|inf| {
+                infusions
+                    .keys()
+                    .filter(|other_id| { **other_id != inf.id() && !inf.allows_on_line(**other_id) })
+                    .map(|other_id| { (inf.id(), *other_id) })
+                    .collect::<Vec<(u32, u32)>>()
+            });
+        let graph = UnGraphMap::from_edges(edges.chain(dedicated_edges));
 
         // Initialize color tracking data
         let mut possible_colors = HashMap::new();
@@ -70,9 +200,29 @@ impl CompatibilityProblem {
             color_usage: HashMap::new(),
             color_max_count: HashMap::new(),
             colors: Vec::new(),
+            peripheral: Vec::new(),
         }
     }
 
+    /// The line each current IV goes through, so infusions that need central access are
+    /// kept off peripheral lines, both when solving and when consolidating.
+    pub fn set_lines(&mut self, lines: &[Line]) {
+        self.peripheral = (0..lines.len() as u32).filter(|iv| { lines[*iv as usize].access == Access::Peripheral }).collect();
+    }
+
+    /// Node -> colors it can't have
+    fn forbidden_colors(&self) -> HashMap<u32, Vec<u32>> {
+        if self.peripheral.is_empty() {
+            return HashMap::new();
+        }
+
+        self.infusions
+            .values()
+            .filter(|infusion| { infusion.central_only() })
+            .map(|infusion| { (infusion.id(), self.peripheral.clone()) })
+            .collect()
+    }
+
     /// Sort nodes by number of possible colors descending,
     /// then by number of adjacent uncolored nodes ascending.
     /// 
@@ -90,7 +240,7 @@ impl CompatibilityProblem {
     fn select_color(&self, node_colors: &HashSet<u32>) -> u32 {
         // Map colors to the max possible number of nodes that could be that color
         let color_potential = node_colors
-            .into_iter()
+            .iter()
             .map(|color| {
                 let num_possible = *self.color_max_count.get(color).unwrap();
                 (color, num_possible)
@@ -119,22 +269,28 @@ impl CompatibilityProblem {
     }
 
     fn color_node(&mut self, node: u32, color: u32) -> Result<(), ConflictError> {
-        let adjacent_nodes = self.graph.neighbors(node).collect_vec();
+        // Every node has a self loop since it isn't compatible with itself
+        let adjacent_nodes = self.graph.neighbors(node).filter(|adj| { *adj != node }).collect_vec();
         // Check that node is allowed to be this color
         for adj_node in &adjacent_nodes {
             if self.color_usage.get(&color).unwrap().contains(adj_node) {
-                let name1 = self.infusions.get(&node).unwrap().name().to_string();
-                let name2 = self.infusions.get(adj_node).unwrap().name().to_string();
-                return Err(ConflictError { iv: color, conflicting_items: (name1, name2) });
+                let (inf1, inf2) = (&self.infusions[&node], &self.infusions[adj_node]);
+                let dedicated_line = [(inf1, inf2), (inf2, inf1)]
+                    .into_iter()
+                    .find(|(inf, other)| { !inf.allows_on_line(other.id()) })
+                    .map(|(inf, _)| { format!("{} needs a line of its own: {}", inf.name(), inf.dedicated_line().unwrap().reason) });
+                let conflicting_items = (inf1.name().to_string(), inf2.name().to_string());
+                return Err(ConflictError { iv: color, conflicting_items, dedicated_line });
             }
         }
 
         self.color_usage.get_mut(&color).unwrap().push(node);
         
-        // Update color counts
-        for other_color in self.possible_colors.get(&node).unwrap() {
-            if color != *other_color {
-                *self.color_max_count.get_mut(other_color).unwrap() -= 1;
+        // Update color counts. The node is no longer a candidate for any other color.
+        let node_colors = std::mem::take(self.possible_colors.get_mut(&node).unwrap());
+        for other_color in node_colors {
+            if color != other_color {
+                *self.color_max_count.get_mut(&other_color).unwrap() -= 1;
             }
         }
 
@@ -169,12 +325,25 @@ impl CompatibilityProblem {
         Ok(())
     }
 
-    pub fn solve(&mut self, ivs: Vec<HashSet<u32>>) -> Result<HashMap<u32, Vec<&Infusion>>, ConflictError> {
+    /// Find an IV layout, keeping the given IVs as they are.
+    ///
+    /// A layout is found heuristically first, then improved by an exact search
+    /// until it is proven optimal or the budget runs out.
+    pub fn solve(&mut self, ivs: Vec<HashSet<u32>>, budget: &SolveBudget) -> Result<Solution, ConflictError> {
+        let num_preset = ivs.len() as u32;
         self.init_coloring(ivs)?;
+        let preset = self.coloring();
+        for (node, colors) in self.forbidden_colors() {
+            let Some(possible) = self.possible_colors.get_mut(&node) else { continue };
+            for color in colors {
+                if possible.remove(&color) {
+                    *self.color_max_count.get_mut(&color).unwrap() -= 1;
+                }
+            }
+        }
 
-        while self.uncolored_nodes.len() > 0 {
+        while !self.uncolored_nodes.is_empty() {
             self.sort_nodes();
-            println!("{:?}", self.uncolored_nodes);
 
             let node = self.uncolored_nodes.pop().unwrap();
             let node_colors = self.possible_colors.get(&node).unwrap();
@@ -183,22 +352,299 @@ impl CompatibilityProblem {
             let color = if node_colors.is_empty() { self.add_new_color() } else { self.select_color(node_colors) };
 
             self.color_node(node, color)?;
+        }
+
+        let mut coloring = self.coloring();
+        let mut num_colors = self.colors.len() as u32;
+        let optimal = self.improve(&mut coloring, &mut num_colors, num_preset, preset, budget);
+
+        let mut output: HashMap<u32, Vec<Infusion>> = (0..num_colors).map(|iv| { (iv, Vec::new()) }).collect();
+        for (inf_id, iv) in coloring {
+            output.get_mut(&iv).unwrap().push(self.infusions.get(&inf_id).unwrap().clone());
+        }
+
+        Ok(Solution { ivs: output, optimal })
+    }
+
+    /// Node -> color for every colored node
+    fn coloring(&self) -> HashMap<u32, u32> {
+        self.color_usage
+            .iter()
+            .flat_map(|(color, nodes)| { nodes.iter().map(|node| { (*node, *color) }) })
+            .collect()
+    }
+
+    /// Take the current IVs, after some infusions have been stopped, and find a layout that
+    /// needs as few IVs as possible. The current IVs are only a preference here: infusions
+    /// may move, but as few of them as possible.
+    ///
+    /// Fails if the current IVs already hold incompatible infusions.
+    pub fn consolidate(&mut self, ivs: Vec<HashSet<u32>>, budget: &SolveBudget) -> Result<Consolidation, ConflictError> {
+        let num_ivs = ivs.len() as u32;
+        let in_use = ivs.iter().filter(|iv| { !iv.is_empty() }).count() as u32;
+        self.init_coloring(ivs)?;
+        let current = self.coloring();
+        let adjacent = self.adjacent();
+
+        // First the fewest IVs the infusions fit into, wherever they are now
+        let lower_bound = greedy_clique_size(&adjacent);
+        let mut fewest = (in_use, None);
+        let mut optimal = true;
+        let mut search = ExactSearch::new(adjacent.clone(), HashMap::new(), budget);
+        while fewest.0 > lower_bound {
+            match search.find_coloring(fewest.0 - 1) {
+                Ok(Some(better)) => fewest = (better.values().unique().count() as u32, Some(better)),
+                Ok(None) => break,
+                Err(_) => {
+                    optimal = false;
+                    break;
+                },
+            }
+        }
+
+        // Then the layout with that many IVs that moves the fewest infusions. Infusions that need
+        // central access may not fit into that few IVs without peripheral lines, in which case
+        // one more IV is tried at a time. The current layout always fits.
+        let forbidden = self.forbidden_colors();
+        let allowed = |coloring: &HashMap<u32, u32>| {
+            coloring.iter().all(|(node, color)| { !forbidden.get(node).is_some_and(|colors| { colors.contains(color) }) })
+        };
+        let (mut num_colors, found) = fewest;
+        let mut upper_bound = found.map(|coloring| { relabel(&coloring, &current, num_ivs) }).filter(allowed);
+        let (coloring, proven) = loop {
+            if num_colors == in_use {
+                upper_bound = upper_bound.or_else(|| { Some(current.clone()) });
+            }
+            let mut closest = ClosestSearch::new(adjacent.clone(), current.clone(), num_ivs, budget);
+            closest.restrict(forbidden.clone());
+            if let Some(coloring) = upper_bound.take() {
+                closest.set_upper_bound(coloring);
+            }
+            match closest.find(num_colors) {
+                (Some(coloring), proven) => break (coloring, proven),
+                (None, proven) => {
+                    optimal &= proven;
+                    num_colors += 1;
+                },
+            }
+        };
 
-            println!("{:?}", self.color_usage);    
+        let mut output: HashMap<u32, Vec<Infusion>> = HashMap::new();
+        let mut moves = Vec::new();
+        for (inf_id, iv) in coloring.iter().sorted() {
+            let infusion = self.infusions.get(inf_id).unwrap().clone();
+            if current[inf_id] != *iv {
+                moves.push(Move { infusion: infusion.clone(), from: current[inf_id], to: *iv });
+            }
+            output.entry(*iv).or_default().push(infusion);
         }
+        moves.sort_by(|a, b| { (a.from, a.infusion.name()).cmp(&(b.from, b.infusion.name())) });
+        let freed = (0..num_ivs).filter(|iv| { !output.contains_key(iv) }).collect();
+
+        Ok(Consolidation { ivs: output, moves, freed, optimal: optimal && proven })
+    }
+
+    /// Node -> neighbors, without self loops
+    fn adjacent(&self) -> HashMap<u32, Vec<u32>> {
+        self.graph
+            .nodes()
+            .map(|node| {
+                (node, self.graph.neighbors(node).filter(|adj| { *adj != node }).collect_vec())
+            })
+            .collect()
+    }
 
-        // Convert infusion IDs to names
-        let mut output = HashMap::new();
-        for (iv, inf_id_list) in self.color_usage.iter() {
-            let iv_infusions = inf_id_list
-                .into_iter()
-                .map(|inf_id| {
-                    self.infusions.get(&inf_id).unwrap()
-                })
-                .collect_vec();
-            output.insert(*iv, iv_infusions);
+    /// Search for colorings with fewer colors than `num_colors`, replacing
+    /// `coloring` with each better one found. Returns true if the final
+    /// coloring is proven to use as few colors as possible.
+    fn improve(&self, coloring: &mut HashMap<u32, u32>, num_colors: &mut u32, num_preset: u32,
+               preset: HashMap<u32, u32>, budget: &SolveBudget) -> bool {
+        let adjacent = self.adjacent();
+        let lower_bound = std::cmp::max(num_preset, greedy_clique_size(&adjacent));
+
+        let mut search = ExactSearch::new(adjacent, preset, budget);
+        let forbidden = self.forbidden_colors();
+        if !forbidden.is_empty() {
+            search.restrict(forbidden, num_preset);
+        }
+        while *num_colors > lower_bound {
+            match search.find_coloring(*num_colors - 1) {
+                Ok(Some(better)) => {
+                    *num_colors = better.values().map(|c| { c + 1 }).max().unwrap_or(0).max(num_preset);
+                    *coloring = better;
+                },
+                Ok(None) => return true,
+                Err(_) => return false,
+            }
         }
 
-        Ok(output)
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::{self, Device};
+    use crate::infusion::{CompatibilityData, DedicatedLine, InfusionType};
+    use crate::schedule::Schedule;
+
+    /// Infusions with the given IDs, where only the listed pairs are compatible
+    fn infusions(ids: &[u32], compatible: &[(u32, u32)]) -> HashMap<u32, Infusion> {
+        let mut infusions: HashMap<u32, Infusion> = ids
+            .iter()
+            .map(|id| { (*id, Infusion::new(*id, format!("#{}", id), InfusionType::Drug)) })
+            .collect();
+        for (a, b) in compatible {
+            let data = Arc::new(CompatibilityData::new(1, 0, 0));
+            infusions.get_mut(a).unwrap().add_compatibility_data(*b, &data);
+            infusions.get_mut(b).unwrap().add_compatibility_data(*a, &data);
+        }
+
+        infusions
+    }
+
+    fn every(hours: u32, start_hour: u32) -> Option<Schedule> {
+        Some(Schedule { start: start_hour * 60, duration: 60, interval: hours * 60 })
+    }
+
+    /// Infusion IDs in each IV of a layout, sorted so layouts can be compared
+    fn ids(layout: Vec<&[Infusion]>) -> Vec<Vec<u32>> {
+        layout.iter().map(|iv| { iv.iter().map(|inf| { inf.id() }).sorted().collect_vec() }).sorted().collect()
+    }
+
+    fn solve(infusions: HashMap<u32, Infusion>, ivs: Vec<HashSet<u32>>) -> Result<Solution, ConflictError> {
+        CompatibilityProblem::new(infusions).solve(ivs, &SolveBudget::new(Duration::from_secs(5)))
+    }
+
+    #[test]
+    fn compatible_infusions_share_an_iv() {
+        let solution = solve(infusions(&[1, 2, 3], &[(1, 2)]), Vec::new()).unwrap();
+        assert_eq!(ids(solution.layout()), vec![vec![1, 2], vec![3]]);
+        assert!(solution.optimal);
+    }
+
+    #[test]
+    fn current_ivs_are_kept() {
+        let ivs = vec![HashSet::from([1]), HashSet::from([2])];
+        let solution = solve(infusions(&[1, 2, 3], &[(1, 2), (2, 3)]), ivs).unwrap();
+        assert_eq!(solution.layout()[1].iter().map(|inf| { inf.id() }).sorted().collect_vec(), vec![2, 3]);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn incompatible_current_iv_is_a_conflict() {
+        let error = solve(infusions(&[1, 2], &[]), vec![HashSet::from([1, 2])]).unwrap_err();
+        assert_eq!(error.iv, 0);
+        assert!(error.dedicated_line.is_none());
+    }
+
+    #[test]
+    fn intermittent_infusions_that_never_overlap_share_without_a_flush() {
+        let mut infusions = infusions(&[1, 2], &[]);
+        infusions.get_mut(&1).unwrap().set_schedule(every(12, 8));
+        infusions.get_mut(&2).unwrap().set_schedule(every(12, 10));
+
+        let solution = solve(infusions, Vec::new()).unwrap();
+        assert_eq!(ids(solution.layout()), vec![vec![1, 2]]);
+        assert_eq!(time_shared(&solution.layout()).len(), 1);
+        assert!(flushes(&solution.layout()).is_empty());
+    }
+
+    #[test]
+    fn overlapping_doses_need_separate_ivs_even_if_flushable() {
+        let mut infusions = infusions(&[1, 2], &[]);
+        infusions.get_mut(&1).unwrap().set_schedule(every(12, 8));
+        infusions.get_mut(&2).unwrap().set_schedule(every(6, 8));
+        infusions.get_mut(&1).unwrap().allow_flush(2);
+        infusions.get_mut(&2).unwrap().allow_flush(1);
+
+        assert_eq!(ids(solve(infusions, Vec::new()).unwrap().layout()), vec![vec![1], vec![2]]);
+    }
+
+    #[test]
+    fn flushable_pair_pauses_a_continuous_infusion() {
+        let mut infusions = infusions(&[1, 2], &[]);
+        infusions.get_mut(&1).unwrap().set_schedule(every(12, 8));
+        assert_eq!(ids(solve(infusions.clone(), Vec::new()).unwrap().layout()), vec![vec![1], vec![2]]);
+
+        infusions.get_mut(&1).unwrap().allow_flush(2);
+        infusions.get_mut(&2).unwrap().allow_flush(1);
+        let solution = solve(infusions, Vec::new()).unwrap();
+        assert_eq!(ids(solution.layout()), vec![vec![1, 2]]);
+        assert_eq!(flushes(&solution.layout()).len(), 1);
+        assert!(time_shared(&solution.layout()).is_empty());
+    }
+
+    #[test]
+    fn continuous_flushable_pair_stays_apart() {
+        let mut infusions = infusions(&[1, 2], &[]);
+        infusions.get_mut(&1).unwrap().allow_flush(2);
+        infusions.get_mut(&2).unwrap().allow_flush(1);
+
+        assert_eq!(ids(solve(infusions, Vec::new()).unwrap().layout()), vec![vec![1], vec![2]]);
+    }
+
+    /// A peripheral line with 2 and 3, and a central line with 1, which needs central access
+    fn central_only_on_two_lines() -> (CompatibilityProblem, Vec<HashSet<u32>>) {
+        let mut infusions = infusions(&[1, 2, 3], &[(1, 2), (1, 3), (2, 3)]);
+        infusions.get_mut(&1).unwrap().set_central_only(true);
+        let mut problem = CompatibilityProblem::new(infusions);
+        problem.set_lines(&access::lines(&[
+            Device { kind: Access::Peripheral, lumens: 1 },
+            Device { kind: Access::Central, lumens: 1 },
+        ]));
+
+        (problem, vec![HashSet::from([2, 3]), HashSet::from([1])])
+    }
+
+    #[test]
+    fn central_only_infusions_stay_off_peripheral_lines() {
+        let (mut problem, _) = central_only_on_two_lines();
+        let solution = problem.solve(vec![HashSet::new(), HashSet::new()], &SolveBudget::new(Duration::from_secs(5))).unwrap();
+        assert!(solution.ivs[&1].iter().any(|inf| { inf.id() == 1 }));
+        assert!(solution.ivs[&0].is_empty());
+    }
+
+    #[test]
+    fn consolidating_moves_the_others_to_the_central_line() {
+        let (mut problem, ivs) = central_only_on_two_lines();
+        let consolidation = problem.consolidate(ivs, &SolveBudget::new(Duration::from_secs(5))).unwrap();
+        assert_eq!(ids(consolidation.layout()), vec![vec![1, 2, 3]]);
+        assert!(consolidation.ivs.contains_key(&1));
+        assert_eq!(consolidation.freed, vec![0]);
+        assert_eq!(consolidation.moves.len(), 2);
+    }
+
+    #[test]
+    fn consolidating_without_lines_moves_the_fewest() {
+        let (_, ivs) = central_only_on_two_lines();
+        let mut infusions = infusions(&[1, 2, 3], &[(1, 2), (1, 3), (2, 3)]);
+        infusions.get_mut(&1).unwrap().set_central_only(true);
+        let consolidation = CompatibilityProblem::new(infusions).consolidate(ivs, &SolveBudget::new(Duration::from_secs(5))).unwrap();
+        assert_eq!(consolidation.freed, vec![1]);
+        assert_eq!(consolidation.moves.len(), 1);
+    }
+
+    fn dedicated(infusions: &mut HashMap<u32, Infusion>, id: u32, allowed_with: &[u32]) {
+        let line = DedicatedLine { reason: "TPN".to_string(), allowed_with: allowed_with.iter().copied().collect() };
+        infusions.get_mut(&id).unwrap().set_dedicated_line(Some(line));
+    }
+
+    #[test]
+    fn dedicated_line_is_kept_to_itself() {
+        let mut infusions = infusions(&[1, 2, 3], &[(1, 2), (1, 3)]);
+        dedicated(&mut infusions, 1, &[]);
+        assert_eq!(ids(solve(infusions.clone(), Vec::new()).unwrap().layout()), vec![vec![1], vec![2], vec![3]]);
+
+        dedicated(&mut infusions, 1, &[2]);
+        assert_eq!(ids(solve(infusions, Vec::new()).unwrap().layout()), vec![vec![1, 2], vec![3]]);
+    }
+
+    #[test]
+    fn sharing_a_dedicated_line_is_a_conflict() {
+        let mut infusions = infusions(&[1, 2], &[(1, 2)]);
+        dedicated(&mut infusions, 1, &[]);
+        let error = solve(infusions, vec![HashSet::from([1, 2])]).unwrap_err();
+        assert_eq!(error.dedicated_line.as_deref(), Some("#1 needs a line of its own: TPN"));
+    }
+}
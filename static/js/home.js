@@ -15,8 +15,10 @@ function updateIvCount() {
       title.text("IV #" + (i+1));
       let ivInputs = newIv.find(".infusion-input");
       let select = ivInputs.first();
-      select.attr("name", "iv-" + i);
+      select.find(".infusion-input-dropdown").attr("name", "iv-" + i);
       select.find("option")[0].selected = true;
+      newIv.find(".iv-line").val("");
+      clearSchedule(ivInputs.first());
       ivInputs.slice(1).remove();
 
       lastIv.after(newIv);
@@ -34,6 +36,7 @@ function deleteInfusion(event) {
   if (allInputs.length == 1) {
     let selector = thisInput.parent().find(".infusion-input-dropdown");
     selector.find("option")[0].selected = true;
+    clearSchedule(thisInput);
   } else {
     thisInput.remove();
   }
@@ -42,10 +45,45 @@ function deleteInfusion(event) {
 function addInfusion(event) {
   let newInput = $(event.currentTarget.parentElement).find(".infusion-input").first().clone();
   newInput.find(".infusion-input-dropdown").find("option")[0].selected = true;
+  clearSchedule(newInput);
 
   $(event.currentTarget).before(newInput);
 }
 
+// Group the IVs by the line they go through. Returns the devices and the IVs in lumen order,
+// or null if only some IVs have a line.
+function groupByLine(ivs) {
+  let lines = $(".iv-line").map(function() { return $(this).val(); }).get();
+  if (lines.every((line) => line == "")) {
+    return { devices: [], ivs: ivs };
+  }
+  if (lines.some((line) => line == "")) {
+    return null;
+  }
+
+  let devices = [];
+  let grouped = [];
+  let centralIndex = {};
+  lines.forEach((line, i) => {
+    if (line == "peripheral") {
+      devices.push({ kind: "peripheral", ivs: [ivs[i]] });
+    } else if (line in centralIndex) {
+      devices[centralIndex[line]].ivs.push(ivs[i]);
+    } else {
+      centralIndex[line] = devices.length;
+      devices.push({ kind: "central", ivs: [ivs[i]] });
+    }
+  });
+  for (const device of devices) {
+    grouped.push(...device.ivs);
+  }
+
+  return {
+    devices: devices.map((device) => ({ kind: device.kind, lumens: device.ivs.length })),
+    ivs: grouped,
+  };
+}
+
 function submitData() {
   let data = new FormData($("#input-form")[0]);
   let num_ivs = Number(data.get("num-ivs"));
@@ -54,7 +92,12 @@ function submitData() {
     ivs.push(Array.from(data.getAll("iv-" + i)).map(Number));
   }
 
-  ivs = JSON.stringify(ivs);
+  let byLine = groupByLine(ivs);
+  if (byLine == null) {
+    alert("Pick a line for every IV, or for none of them.");
+    return;
+  }
+  ivs = JSON.stringify(byLine.ivs);
 
   let parsedData = new FormData();
   parsedData.append("num_ivs", num_ivs);
@@ -62,6 +105,17 @@ function submitData() {
   for (const item of data.getAll("add")) {
     parsedData.append("add", item);
   }
+  if (byLine.devices.length > 0) {
+    parsedData.append("devices", JSON.stringify(byLine.devices));
+  }
+  let schedules = readSchedules($(".infusion-input"));
+  if (Object.keys(schedules).length > 0) {
+    parsedData.append("schedules", JSON.stringify(schedules));
+  }
+  // Solve with the data the page was built from, even if it's reloaded in the meantime
+  if (data.has("version")) {
+    parsedData.append("version", data.get("version"));
+  }
 
   queryString = new URLSearchParams(parsedData).toString();
   window.open("results?" + queryString, "_self");
@@ -0,0 +1,18 @@
+function submitAdd() {
+  let form = $("#add-form");
+  let rows = form.find(".infusion-input");
+
+  let params = new URLSearchParams();
+  rows.each(function() {
+    let id = $(this).find(".infusion-input-dropdown").val();
+    if (id) {
+      params.append("add", id);
+    }
+  });
+  let schedules = readSchedules(rows);
+  if (Object.keys(schedules).length > 0) {
+    params.append("schedules", JSON.stringify(schedules));
+  }
+
+  window.open("/regimens/" + form.data("regimen") + "/add?" + params.toString(), "_self");
+}
@@ -0,0 +1,29 @@
+// Intermittent infusions: each .infusion-input row can have a schedule next to its dropdown
+
+// Infusion ID -> {start, duration, interval} in minutes, for every row with a schedule
+function readSchedules(rows) {
+  let schedules = {};
+  rows.each(function() {
+    let row = $(this);
+    let id = row.find(".infusion-input-dropdown").val();
+    let interval = row.find(".schedule-every").val();
+    if (!id || !interval) {
+      return;
+    }
+
+    let [hours, minutes] = (row.find(".schedule-start").val() || "00:00").split(":").map(Number);
+    schedules[id] = {
+      start: hours * 60 + minutes,
+      duration: Number(row.find(".schedule-duration").val()),
+      interval: Number(interval),
+    };
+  });
+
+  return schedules;
+}
+
+function clearSchedule(row) {
+  row.find(".schedule-every").val("");
+  row.find(".schedule-start").val("08:00");
+  row.find(".schedule-duration").val("");
+}